@@ -0,0 +1,193 @@
+//! Python bindings, built into the `divvun_pipeline` module with maturin:
+//!
+//! ```python
+//! from divvun_pipeline import Pipeline
+//!
+//! p = Pipeline.open("se.zpipe")
+//! p.run("text")
+//! ```
+
+use std::{collections::BTreeMap, path::PathBuf, sync::Arc};
+
+use divvun_pipeline::{
+    convert::{decode, module_metadata_json, string_type_id, ConvertError, DataFormat},
+    file::load_pipeline_with_keys,
+    module::{module_search_paths, trusted_keys as configured_trusted_keys},
+    run::output_type,
+    session::{CommandOutput, CommandRecorder, PipelineSession, SessionRequest},
+};
+use pyo3::{create_exception, exceptions::PyException, prelude::*, types::PyDict};
+
+create_exception!(divvun_pipeline, PipelineError, PyException);
+
+fn json_to_py(py: Python<'_>, value: &serde_json::Value) -> PyResult<PyObject> {
+    let value = py
+        .import("json")?
+        .call_method1("loads", (value.to_string(),))?;
+    Ok(value.into())
+}
+
+/// A pipeline file with its modules loaded, which stay loaded for every run
+#[pyclass(module = "divvun_pipeline")]
+struct Pipeline {
+    session: PipelineSession,
+}
+
+impl Pipeline {
+    fn request(
+        input: &str,
+        pipeline: Option<String>,
+        variables: Option<BTreeMap<String, String>>,
+    ) -> SessionRequest {
+        let mut request = SessionRequest::new(input.as_bytes().to_vec(), DataFormat::Text);
+        request.pipeline = pipeline;
+        request.variables = variables.unwrap_or_default();
+        request
+    }
+
+    /// A command's output as text for strings and as the JSON form of its schema otherwise
+    fn command_output(&self, py: Python<'_>, command: &CommandOutput) -> PyResult<PyObject> {
+        let type_id = output_type(self.session.registry(), &command.command);
+        if type_id == string_type_id() {
+            if let Ok(text) = decode(&command.output, DataFormat::Text, type_id) {
+                return Ok(String::from_utf8_lossy(&text).into_owned().into_py(py));
+            }
+        }
+
+        match decode(&command.output, DataFormat::Json, type_id) {
+            Ok(json) => match serde_json::from_slice::<serde_json::Value>(&json) {
+                Ok(value) => json_to_py(py, &value),
+                Err(_) => Ok(command.output.clone().into_py(py)),
+            },
+            Err(_) => Ok(command.output.clone().into_py(py)),
+        }
+    }
+}
+
+#[pymethods]
+impl Pipeline {
+    /// Load a .zpipe file or unpacked directory, looking for modules in `module_path` before
+    /// `DIVVUN_PIPELINE_MODULE_PATH` and the paths of the config files. A signed file has to be
+    /// signed by a key in the `trusted_keys` file or of the config files.
+    #[staticmethod]
+    #[pyo3(signature = (path, module_path = None, trusted_keys = None))]
+    fn open(
+        py: Python<'_>,
+        path: PathBuf,
+        module_path: Option<PathBuf>,
+        trusted_keys: Option<PathBuf>,
+    ) -> PyResult<Pipeline> {
+        let explicit: Vec<PathBuf> = module_path.into_iter().collect();
+        let key_files: Vec<PathBuf> = trusted_keys.into_iter().collect();
+        let session = py
+            .allow_threads(|| {
+                let search_paths = module_search_paths(&explicit).map_err(|e| e.to_string())?;
+                let keys = configured_trusted_keys(&key_files).map_err(|e| e.to_string())?;
+                let file = load_pipeline_with_keys(&path, &keys).map_err(|e| e.to_string())?;
+                PipelineSession::new(file, &search_paths).map_err(|e| e.to_string())
+            })
+            .map_err(PipelineError::new_err)?;
+        Ok(Pipeline { session })
+    }
+
+    /// Names of the pipelines in the file
+    #[getter]
+    fn pipelines(&self) -> Vec<String> {
+        self.session.pipeline_names()
+    }
+
+    /// Run text through a pipeline, the default one if `pipeline` isn't given
+    #[pyo3(signature = (input, pipeline = None, variables = None))]
+    fn run(
+        &self,
+        py: Python<'_>,
+        input: &str,
+        pipeline: Option<String>,
+        variables: Option<BTreeMap<String, String>>,
+    ) -> PyResult<String> {
+        let request = Pipeline::request(input, pipeline, variables);
+        let output = py
+            .allow_threads(|| {
+                self.session
+                    .run_blocking(request)
+                    .map_err(|e| e.to_string())
+            })
+            .map_err(PipelineError::new_err)?;
+        Ok(String::from_utf8_lossy(&output).into_owned())
+    }
+
+    /// Like `run`, but also returns the output of every command as a list of dicts with its
+    /// `path` in the pipeline, `module`, `command`, `parameters`, `output` and `duration` in
+    /// seconds
+    #[pyo3(signature = (input, pipeline = None, variables = None))]
+    fn run_traced(
+        &self,
+        py: Python<'_>,
+        input: &str,
+        pipeline: Option<String>,
+        variables: Option<BTreeMap<String, String>>,
+    ) -> PyResult<(String, Vec<PyObject>)> {
+        let request = Pipeline::request(input, pipeline, variables);
+        let recorder = Arc::new(CommandRecorder::default());
+        let output = py
+            .allow_threads(|| {
+                self.session
+                    .run_observed_blocking(request, recorder.clone())
+                    .map_err(|e| e.to_string())
+            })
+            .map_err(PipelineError::new_err)?;
+
+        let mut commands = Vec::new();
+        for command in recorder.take_outputs() {
+            let dict = PyDict::new(py);
+            dict.set_item("path", command.path.to_string())?;
+            dict.set_item("module", &command.command.module)?;
+            dict.set_item("command", &command.command.command)?;
+            dict.set_item("parameters", command.command.parameters.clone())?;
+            dict.set_item("output", self.command_output(py, &command)?)?;
+            dict.set_item("duration", command.duration.as_secs_f64())?;
+            commands.push(dict.into());
+        }
+
+        Ok((String::from_utf8_lossy(&output).into_owned(), commands))
+    }
+
+    /// The metadata of every module the pipelines use, as dicts with the `moduleName`,
+    /// `moduleVersion` and `commands` of the module
+    fn modules(&self, py: Python<'_>) -> PyResult<Vec<PyObject>> {
+        let mut modules = Vec::new();
+        for name in self.session.module_names() {
+            // Depends on the variables of a run
+            if name.contains("${") {
+                continue;
+            }
+            let module = self
+                .session
+                .registry()
+                .get_module(&name)
+                .map_err(|e| PipelineError::new_err(format!("{}: {}", name, e)))?;
+            if let Some(metadata) = module.metadata() {
+                let metadata = metadata.lock();
+                let json = metadata
+                    .get()
+                    .map_err(ConvertError::from)
+                    .and_then(module_metadata_json)
+                    .map_err(|e| PipelineError::new_err(format!("{}: {}", name, e)))?;
+                modules.push(json_to_py(py, &json)?);
+            }
+        }
+        Ok(modules)
+    }
+
+    fn __repr__(&self) -> String {
+        format!("<Pipeline {}>", self.session.pipeline_names().join(", "))
+    }
+}
+
+#[pymodule]
+#[pyo3(name = "divvun_pipeline")]
+fn python_module(py: Python<'_>, module: &PyModule) -> PyResult<()> {
+    module.add_class::<Pipeline>()?;
+    module.add("PipelineError", py.get_type::<PipelineError>())?;
+    Ok(())
+}
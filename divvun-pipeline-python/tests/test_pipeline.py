@@ -0,0 +1,49 @@
+"""Tests of the Python bindings.
+
+Build the module into the current environment with `maturin develop` and build the test modules
+with `cargo build` first. The modules are looked up in DIVVUN_PIPELINE_MODULES, target/modules of
+the workspace by default.
+"""
+
+import os
+import unittest
+
+from divvun_pipeline import Pipeline, PipelineError
+
+ROOT = os.path.dirname(os.path.dirname(os.path.dirname(os.path.abspath(__file__))))
+MODULES = os.environ.get("DIVVUN_PIPELINE_MODULES", os.path.join(ROOT, "target", "modules"))
+PIPELINE = os.path.join(ROOT, "divvun-pipeline", "tests", "c", "reverse")
+
+
+class PipelineTest(unittest.TestCase):
+    def setUp(self):
+        self.pipeline = Pipeline.open(PIPELINE, MODULES)
+
+    def test_run(self):
+        self.assertEqual(self.pipeline.run("Hello"), "olleH")
+        self.assertEqual(self.pipeline.run("Hello", pipeline="default"), "olleH")
+
+    def test_run_traced(self):
+        output, commands = self.pipeline.run_traced("Hello")
+        self.assertEqual(output, "olleH")
+        self.assertEqual(commands[0]["module"], "reverse_string")
+        self.assertEqual(commands[0]["command"], "reverse")
+        self.assertEqual(commands[0]["output"], "olleH")
+        self.assertGreaterEqual(commands[0]["duration"], 0)
+
+    def test_modules(self):
+        modules = self.pipeline.modules()
+        self.assertEqual([module["moduleName"] for module in modules], ["reverse_string"])
+        self.assertIn("reverse", [command["name"] for command in modules[0]["commands"]])
+
+    def test_errors(self):
+        with self.assertRaises(PipelineError):
+            self.pipeline.run("Hello", pipeline="missing")
+        with self.assertRaises(PipelineError):
+            Pipeline.open(os.path.join(ROOT, "missing.zpipe"), MODULES)
+        with self.assertRaises(PipelineError):
+            Pipeline.open(PIPELINE, MODULES, trusted_keys=os.path.join(ROOT, "missing.keys"))
+
+
+if __name__ == "__main__":
+    unittest.main()
@@ -0,0 +1,111 @@
+#ifndef DIVVUN_PIPELINE_H
+#define DIVVUN_PIPELINE_H
+
+/* Generated by cbindgen from src/capi.rs, do not edit */
+
+#include <stdbool.h>
+#include <stddef.h>
+#include <stdint.h>
+
+typedef enum DpDataFormat {
+  DP_DATA_FORMAT_TEXT,
+  DP_DATA_FORMAT_CAPNP,
+  DP_DATA_FORMAT_JSON,
+} DpDataFormat;
+
+/**
+ * The output of a pipeline run
+ */
+typedef struct DpOutput DpOutput;
+
+/**
+ * A pipeline file with its modules loaded
+ */
+typedef struct DpPipeline DpPipeline;
+
+/**
+ * Load a pipeline file or unpacked directory and the modules its manifest requires. They are
+ * looked up in `module_search_path` if it isn't `NULL`, then in `DIVVUN_PIPELINE_MODULE_PATH`
+ * and the paths of the config files. A signed file has to be signed by one of the keys in
+ * `trusted_key_file`, if it isn't `NULL`, or of the config files. Returns `NULL` on failure.
+ *
+ * # Safety
+ *
+ * The paths have to be nul-terminated UTF-8 strings, `module_search_path` and
+ * `trusted_key_file` can be `NULL`.
+ */
+struct DpPipeline *dp_pipeline_open(const char *path,
+                                    const char *module_search_path,
+                                    const char *trusted_key_file);
+
+/**
+ * Set the value of `${key}` placeholders in module names and parameters for the following runs
+ *
+ * # Safety
+ *
+ * `pipeline` has to come from `dp_pipeline_open`, `key` and `value` have to be nul-terminated
+ * UTF-8 strings.
+ */
+bool dp_pipeline_set_variable(const struct DpPipeline *pipeline,
+                              const char *key,
+                              const char *value);
+
+/**
+ * Run the pipeline named `name`, or the default one if it is `NULL`, on `input_size` bytes of
+ * `input`. Returns the output in `output_format`, to be released with `dp_output_free`, or
+ * `NULL` on failure.
+ *
+ * # Safety
+ *
+ * `pipeline` has to come from `dp_pipeline_open`, `name` has to be `NULL` or a
+ * nul-terminated string and `input` has to point to `input_size` readable bytes.
+ */
+struct DpOutput *dp_pipeline_run(const struct DpPipeline *pipeline,
+                                 const char *name,
+                                 const uint8_t *input,
+                                 size_t input_size,
+                                 enum DpDataFormat input_format,
+                                 enum DpDataFormat output_format);
+
+/**
+ * Unload a pipeline. Outputs of its runs stay valid.
+ *
+ * # Safety
+ *
+ * `pipeline` has to come from `dp_pipeline_open` or be `NULL`, and must not be used afterwards.
+ */
+void dp_pipeline_close(struct DpPipeline *pipeline);
+
+/**
+ * The bytes of an output, which are not nul-terminated
+ *
+ * # Safety
+ *
+ * `output` has to come from `dp_pipeline_run`.
+ */
+const uint8_t *dp_output_data(const struct DpOutput *output);
+
+/**
+ * The number of bytes of an output
+ *
+ * # Safety
+ *
+ * `output` has to come from `dp_pipeline_run`.
+ */
+size_t dp_output_size(const struct DpOutput *output);
+
+/**
+ * Release an output
+ *
+ * # Safety
+ *
+ * `output` has to come from `dp_pipeline_run` or be `NULL`, and must not be used afterwards.
+ */
+void dp_output_free(struct DpOutput *output);
+
+/**
+ * Why the last call on this thread failed, `NULL` if it succeeded. Valid until the next call.
+ */
+const char *dp_error_message(void);
+
+#endif /* DIVVUN_PIPELINE_H */
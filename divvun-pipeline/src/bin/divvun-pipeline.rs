@@ -1,44 +1,710 @@
 #![feature(async_await)]
 
 use std::{
-    env,
+    collections::BTreeMap,
+    env, fs,
     io::{self, BufReader, Read, Write},
     path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread,
+    time::Duration,
 };
 
-use clap::{crate_version, App, Arg};
+use clap::{crate_version, App, Arg, ArgMatches, SubCommand};
 use log::{error, info};
 
 use divvun_pipeline::{
-    file::{load_pipeline_file, PIPELINE_EXTENSION},
-    module::AllocationType,
+    convert::{detect_format, DataFormat},
+    daemon::Daemon,
+    file::{load_pipeline_with_keys, PIPELINE_EXTENSION},
+    format::{parse_pipeline, pipeline_schema, PipelineFormat},
+    manifest::{Manifest, MANIFEST_FILE_NAME},
+    module::{
+        module_search_paths, trusted_keys as configured_trusted_keys, AllocationType,
+        ModuleAllocator, ModuleRegistry,
+    },
+    pack::{inspect_pipeline_file, sign_pipeline_file, unpack_pipeline_file, PipelinePacker},
+    repl::{Repl, REPL_HELP},
+    resources::{ResidencyPolicy, ResourceError, ResourceRegistry},
     run::PipelineRunConfigurationBuilder,
+    serve::{Server, ShutdownHandle},
+    session::{PipelinePackages, PipelineSession},
+    signature::{public_key_hex, read_secret_key, TrustedKeys},
+    trace::TraceFormat,
 };
 
+/// Split a `name=path` argument, using the file name if no name is given
+fn named_path(value: &str) -> (Option<String>, PathBuf) {
+    match value.find('=') {
+        Some(index) => (
+            Some(value[..index].to_string()),
+            PathBuf::from(&value[index + 1..]),
+        ),
+        None => (None, PathBuf::from(value)),
+    }
+}
+
+/// Module search paths from `-m`, followed by those of the environment and the config files
+fn search_paths(matches: &ArgMatches) -> Result<Vec<PathBuf>, Box<dyn std::error::Error>> {
+    let explicit: Vec<PathBuf> = matches
+        .values_of("modules")
+        .into_iter()
+        .flatten()
+        .map(PathBuf::from)
+        .collect();
+    let paths = module_search_paths(&explicit)?;
+    info!("Module search paths: {:?}", paths);
+    Ok(paths)
+}
+
+/// Collect the `key=value` pairs given with `--set`
+fn variables(matches: &ArgMatches) -> Result<BTreeMap<String, String>, String> {
+    let mut variables = BTreeMap::new();
+    for value in matches.values_of("set").into_iter().flatten() {
+        match value.find('=') {
+            Some(index) => {
+                variables.insert(value[..index].to_string(), value[index + 1..].to_string());
+            }
+            None => return Err(format!("{} is not of the form key=value", value)),
+        }
+    }
+    Ok(variables)
+}
+
+fn pack(matches: &ArgMatches) -> Result<(), Box<dyn std::error::Error>> {
+    let mut manifest = match matches.value_of("manifest") {
+        Some(path) => serde_json::from_slice(&fs::read(path)?)?,
+        None => Manifest::default(),
+    };
+
+    if let Some(name) = matches.value_of("name") {
+        manifest.name = Some(name.to_string());
+    }
+    if let Some(language) = matches.value_of("language") {
+        manifest.language = Some(language.to_string());
+    }
+    if let Some(description) = matches.value_of("description") {
+        manifest.description = Some(description.to_string());
+    }
+
+    for value in matches.values_of("pipeline").into_iter().flatten() {
+        let (name, path) = named_path(value);
+        let format = PipelineFormat::from_path(&path)
+            .ok_or_else(|| format!("{} is not a .json, .yaml or .toml file", path.display()))?;
+        let root = parse_pipeline(&fs::read_to_string(&path)?, format)?;
+        match name {
+            Some(name) => {
+                manifest.pipelines.insert(name, root);
+            }
+            None => manifest.pipeline = Some(root),
+        }
+    }
+
+    let mut packer = PipelinePacker::new(manifest);
+    if let Some(key) = matches.value_of("key") {
+        packer.sign_with(read_secret_key(Path::new(key))?);
+    }
+    for value in matches.values_of("resources").into_iter().flatten() {
+        let (name, path) = named_path(value);
+        let name = match name {
+            Some(name) => name,
+            None => path
+                .file_name()
+                .and_then(|name| name.to_str())
+                .ok_or_else(|| format!("{} is not a valid resource path", value))?
+                .to_string(),
+        };
+        if path.is_dir() {
+            packer.add_resource_dir(&name, &path)?;
+        } else {
+            packer.add_resource(&name, &path)?;
+        }
+    }
+
+    if !matches.is_present("no-validate") {
+        let mut registry = ModuleRegistry::new(
+            Arc::new(ModuleAllocator::new(AllocationType::Memory)),
+            Arc::new(ResourceRegistry::new()),
+        )?;
+        for path in search_paths(matches)? {
+            registry.add_search_path(&path);
+        }
+        packer.validate(&registry)?;
+    }
+
+    let manifest = packer.write(Path::new(matches.value_of("output").unwrap()))?;
+    info!("Packed {} resources", manifest.resources.len());
+    Ok(())
+}
+
+fn inspect(matches: &ArgMatches) -> Result<(), Box<dyn std::error::Error>> {
+    let info = inspect_pipeline_file(Path::new(matches.value_of("file").unwrap()))?;
+    let stdout = io::stdout();
+    let mut out = stdout.lock();
+
+    match info.manifest {
+        Some(ref manifest) => {
+            writeln!(out, "Manifest version: {}", manifest.version)?;
+            if let Some(ref name) = manifest.name {
+                writeln!(out, "Name: {}", name)?;
+            }
+            if let Some(ref language) = manifest.language {
+                writeln!(out, "Language: {}", language)?;
+            }
+            if let Some(ref description) = manifest.description {
+                writeln!(out, "Description: {}", description)?;
+            }
+            for module in &manifest.modules {
+                match module.min_version {
+                    Some(ref version) => writeln!(out, "Module: {} >= {}", module.name, version)?,
+                    None => writeln!(out, "Module: {}", module.name)?,
+                }
+            }
+            if manifest.pipeline.is_some() {
+                writeln!(out, "Pipeline: default")?;
+            }
+            for name in manifest.pipelines.keys() {
+                writeln!(out, "Pipeline: {}", name)?;
+            }
+        }
+        None => writeln!(out, "No {}", MANIFEST_FILE_NAME)?,
+    }
+
+    for entry in &info.entries {
+        let hash = info
+            .manifest
+            .as_ref()
+            .and_then(|manifest| manifest.resource(&entry.name))
+            .map(|resource| resource.sha256.as_str())
+            .unwrap_or("-");
+        writeln!(
+            out,
+            "{:>10} {:<10} {} {}",
+            entry.size,
+            if entry.is_aligned() {
+                "aligned"
+            } else if entry.stored {
+                "stored"
+            } else {
+                "compressed"
+            },
+            hash,
+            entry.name
+        )?;
+    }
+
+    Ok(())
+}
+
+fn sign(matches: &ArgMatches) -> Result<(), Box<dyn std::error::Error>> {
+    let keypair = read_secret_key(Path::new(matches.value_of("key").unwrap()))?;
+    sign_pipeline_file(Path::new(matches.value_of("file").unwrap()), &keypair)?;
+    println!("{}", public_key_hex(&keypair));
+    Ok(())
+}
+
+fn trusted_keys(matches: &ArgMatches) -> Result<TrustedKeys, Box<dyn std::error::Error>> {
+    let key_files: Vec<PathBuf> = matches
+        .values_of("trusted-keys")
+        .into_iter()
+        .flatten()
+        .map(PathBuf::from)
+        .collect();
+    let keys = configured_trusted_keys(&key_files)?;
+    Ok(if matches.is_present("require-signature") {
+        keys.require_signature(true)
+    } else {
+        keys
+    })
+}
+
+/// Load the pipeline file and its modules for an interactive session
+fn open_repl(
+    matches: &ArgMatches,
+    repl_matches: &ArgMatches,
+) -> Result<Repl, Box<dyn std::error::Error>> {
+    let keys = trusted_keys(matches)?;
+    let file = load_pipeline_with_keys(Path::new(repl_matches.value_of("file").unwrap()), &keys)?;
+    let pipeline = repl_matches.value_of("name").map(str::to_string);
+    file.pipeline(pipeline.as_ref().map(|name| &**name))?;
+
+    let mut session = PipelineSession::new(file, &search_paths(repl_matches)?)?;
+    if let Some(policy) = residency_policy(matches)? {
+        session = session.with_policy(policy)?;
+    }
+    Ok(Repl::new(session, pipeline, variables(repl_matches)?))
+}
+
+async fn repl(mut repl: Repl) -> Result<(), Box<dyn std::error::Error>> {
+    println!("{}", REPL_HELP);
+
+    loop {
+        print!("> ");
+        io::stdout().flush()?;
+
+        let mut line = String::new();
+        if io::stdin().read_line(&mut line)? == 0 {
+            println!();
+            return Ok(());
+        }
+
+        match repl.handle(line).await {
+            Ok((output, running)) => {
+                if !output.is_empty() {
+                    println!("{}", output.trim_end_matches('\n'));
+                }
+                if !running {
+                    return Ok(());
+                }
+            }
+            Err(e) => println!("error: {}", e),
+        }
+    }
+}
+
+fn schema() -> Result<(), Box<dyn std::error::Error>> {
+    serde_json::to_writer_pretty(io::stdout(), &pipeline_schema())?;
+    println!();
+    Ok(())
+}
+
+/// Load the pipeline files given as `path` or `name=path` with their modules
+fn load_packages(
+    matches: &ArgMatches,
+    sub_matches: &ArgMatches,
+) -> Result<PipelinePackages, Box<dyn std::error::Error>> {
+    let keys = trusted_keys(matches)?;
+    let policy = residency_policy(matches)?;
+    let search_paths = search_paths(sub_matches)?;
+
+    let mut sessions = BTreeMap::new();
+    for value in sub_matches.values_of("files").into_iter().flatten() {
+        let (name, path) = named_path(value);
+        let name = match name {
+            Some(name) => name,
+            None => path
+                .file_stem()
+                .and_then(|name| name.to_str())
+                .ok_or_else(|| format!("{} is not a valid pipeline file path", value))?
+                .to_string(),
+        };
+        if sessions.contains_key(&name) {
+            return Err(format!("{} is loaded more than once, use name=path", name).into());
+        }
+
+        let file = load_pipeline_with_keys(&path, &keys)?;
+        info!("Loaded {} as {}", path.display(), name);
+        let mut session = PipelineSession::new(file, &search_paths)?;
+        if let Some(policy) = policy {
+            session = session.with_policy(policy)?;
+        }
+        sessions.insert(name, session);
+    }
+
+    Ok(PipelinePackages::new(sessions))
+}
+
+fn serve(
+    matches: &ArgMatches,
+    serve_matches: &ArgMatches,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let max_concurrent = serve_matches
+        .value_of("max-concurrent")
+        .unwrap()
+        .parse::<usize>()
+        .map_err(|_| "--max-concurrent must be a number")?;
+    let packages = load_packages(matches, serve_matches)?;
+
+    let server = Server::bind(
+        serve_matches.value_of("address").unwrap(),
+        packages,
+        max_concurrent,
+    )?;
+    shutdown_on_signal(server.shutdown_handle());
+    server.run()?;
+    Ok(())
+}
+
+fn daemon(
+    matches: &ArgMatches,
+    daemon_matches: &ArgMatches,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let max_concurrent = daemon_matches
+        .value_of("max-concurrent")
+        .unwrap()
+        .parse::<usize>()
+        .map_err(|_| "--max-concurrent must be a number")?;
+    let daemon = Daemon::new(load_packages(matches, daemon_matches)?, max_concurrent);
+
+    match daemon_matches.value_of("socket") {
+        Some(socket) => serve_socket(&daemon, Path::new(socket)),
+        None => Ok(daemon.serve_stdio()?),
+    }
+}
+
+#[cfg(unix)]
+fn serve_socket(daemon: &Daemon, path: &Path) -> Result<(), Box<dyn std::error::Error>> {
+    let handle = ShutdownHandle::new();
+    shutdown_on_signal(handle.clone());
+    info!("Listening on {}", path.display());
+    daemon.serve_unix(path, handle)?;
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn serve_socket(_daemon: &Daemon, _path: &Path) -> Result<(), Box<dyn std::error::Error>> {
+    Err("Unix sockets are not supported on this platform".into())
+}
+
+#[cfg(unix)]
+static SHUTDOWN_REQUESTED: AtomicBool = AtomicBool::new(false);
+
+#[cfg(unix)]
+extern "C" fn request_shutdown(_signal: libc::c_int) {
+    SHUTDOWN_REQUESTED.store(true, Ordering::SeqCst);
+}
+
+/// Let the requests in flight finish on SIGINT and SIGTERM before exiting
+#[cfg(unix)]
+fn shutdown_on_signal(handle: ShutdownHandle) {
+    unsafe {
+        libc::signal(libc::SIGINT, request_shutdown as libc::sighandler_t);
+        libc::signal(libc::SIGTERM, request_shutdown as libc::sighandler_t);
+    }
+
+    thread::spawn(move || {
+        while !SHUTDOWN_REQUESTED.load(Ordering::SeqCst) {
+            thread::sleep(Duration::from_millis(100));
+        }
+        handle.shutdown();
+    });
+}
+
+#[cfg(not(unix))]
+fn shutdown_on_signal(_handle: ShutdownHandle) {}
+
+fn unpack(matches: &ArgMatches) -> Result<(), Box<dyn std::error::Error>> {
+    let written = unpack_pipeline_file(
+        Path::new(matches.value_of("file").unwrap()),
+        Path::new(matches.value_of("target").unwrap()),
+    )?;
+    info!("Extracted {} files", written.len());
+    Ok(())
+}
+
+/// The residency policy given with `--resources`
+fn residency_policy(matches: &ArgMatches) -> Result<Option<ResidencyPolicy>, ResourceError> {
+    matches.value_of("resources").map(str::parse).transpose()
+}
+
 async fn main_async() {
     env_logger::init();
 
     let pipeline = "pipeline";
+    let pipeline_file_help = format!(
+        "The .{} file with the requested pipeline flow and required resources",
+        PIPELINE_EXTENSION
+    );
+    let run_help = format!("{}, or an unpacked directory", pipeline_file_help);
 
     let matches = App::new("divvun-pipeline")
         .version(crate_version!())
         .about("Asynchronous parallel pipeline for text processing.")
+        .arg(Arg::with_name(pipeline).help(&run_help).index(1))
         .arg(
-            Arg::with_name(pipeline)
-                .help(&format!(
-                    "The .{} file with the requested pipeline flow and required resources",
-                    PIPELINE_EXTENSION
-                ))
-                .index(1),
+            Arg::with_name("name")
+                .help("Name of the pipeline to run if the file contains several")
+                .long("pipeline")
+                .short("p")
+                .takes_value(true),
         )
         .arg(
             Arg::with_name("modules")
-                .help("Modules search path")
+                .help("Modules search path, can be given several times")
                 .short("m")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1),
+        )
+        .arg(
+            Arg::with_name("resources")
+                .help("When loaded resources are unloaded again: keep, preload, lru:<bytes> to keep at most that many bytes mapped, or on-demand. repl, serve and daemon keep them by default, a single run unloads them on demand")
+                .long("resources")
                 .takes_value(true),
         )
+        .arg(
+            Arg::with_name("input-format")
+                .help("Format of the input, detected if not given")
+                .long("input-format")
+                .takes_value(true)
+                .possible_values(DataFormat::names()),
+        )
+        .arg(
+            Arg::with_name("output-format")
+                .help("Format of the output, the same as the input if not given")
+                .long("output-format")
+                .takes_value(true)
+                .possible_values(DataFormat::names()),
+        )
+        .arg(
+            Arg::with_name("set")
+                .help("Value for ${key} placeholders in the pipeline, as key=value")
+                .long("set")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1),
+        )
+        .arg(
+            Arg::with_name("trace")
+                .help("Write what every command did and its output to this file as JSON lines, or into this directory with --trace-format dir")
+                .long("trace")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("trace-format")
+                .help("How the trace is written")
+                .long("trace-format")
+                .takes_value(true)
+                .possible_values(TraceFormat::names())
+                .default_value("jsonl"),
+        )
+        .arg(
+            Arg::with_name("trusted-keys")
+                .help("File with the hex encoded ed25519 public keys pipeline files may be signed with, one per line, in addition to those of the config files")
+                .long("trusted-keys")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1),
+        )
+        .arg(
+            Arg::with_name("require-signature")
+                .help("Refuse to run pipeline files that aren't signed by a trusted key")
+                .long("require-signature"),
+        )
+        .subcommand(
+            SubCommand::with_name("pack")
+                .about("Create a pipeline file from pipeline definitions and resources")
+                .arg(
+                    Arg::with_name("output")
+                        .help(&pipeline_file_help)
+                        .required(true)
+                        .index(1),
+                )
+                .arg(
+                    Arg::with_name("resources")
+                        .help("Resource files or directories to include, as path or name=path")
+                        .multiple(true)
+                        .index(2),
+                )
+                .arg(
+                    Arg::with_name("pipeline")
+                        .help("Pipeline definition (.json, .yaml or .toml), as path for the default pipeline or name=path")
+                        .long("pipeline")
+                        .short("p")
+                        .takes_value(true)
+                        .multiple(true)
+                        .number_of_values(1),
+                )
+                .arg(
+                    Arg::with_name("manifest")
+                        .help("Manifest JSON to start from")
+                        .long("manifest")
+                        .takes_value(true),
+                )
+                .arg(Arg::with_name("name").long("name").takes_value(true))
+                .arg(
+                    Arg::with_name("language")
+                        .long("language")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("description")
+                        .long("description")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("modules")
+                        .help("Modules search path used for validation, can be given several times")
+                        .short("m")
+                        .takes_value(true)
+                        .multiple(true)
+                        .number_of_values(1),
+                )
+                .arg(
+                    Arg::with_name("no-validate")
+                        .help("Don't check the pipelines against the available modules")
+                        .long("no-validate"),
+                )
+                .arg(
+                    Arg::with_name("key")
+                        .help("Hex encoded ed25519 secret key to sign the pipeline file with")
+                        .long("key")
+                        .takes_value(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("sign")
+                .about("Sign a pipeline file, printing the public key to verify it with")
+                .arg(Arg::with_name("file").required(true).index(1))
+                .arg(
+                    Arg::with_name("key")
+                        .help("Hex encoded ed25519 secret key")
+                        .long("key")
+                        .takes_value(true)
+                        .required(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("unpack")
+                .about("Extract all entries of a pipeline file")
+                .arg(Arg::with_name("file").required(true).index(1))
+                .arg(Arg::with_name("target").required(true).index(2)),
+        )
+        .subcommand(
+            SubCommand::with_name("inspect")
+                .about("Show the manifest and entries of a pipeline file")
+                .arg(Arg::with_name("file").required(true).index(1)),
+        )
+        .subcommand(
+            SubCommand::with_name("repl")
+                .about("Run lines of text through a pipeline interactively")
+                .arg(Arg::with_name("file").help(&run_help).required(true).index(1))
+                .arg(
+                    Arg::with_name("name")
+                        .help("Name of the pipeline to start with")
+                        .long("pipeline")
+                        .short("p")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("modules")
+                        .help("Modules search path, can be given several times")
+                        .short("m")
+                        .takes_value(true)
+                        .multiple(true)
+                        .number_of_values(1),
+                )
+                .arg(
+                    Arg::with_name("set")
+                        .help("Value for ${key} placeholders in the pipeline, as key=value")
+                        .long("set")
+                        .takes_value(true)
+                        .multiple(true)
+                        .number_of_values(1),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("serve")
+                .about("Serve pipelines over HTTP, POST /pipelines/{name} runs one")
+                .arg(
+                    Arg::with_name("files")
+                        .help("Pipeline files or unpacked directories to serve, as path or name=path")
+                        .required(true)
+                        .multiple(true)
+                        .index(1),
+                )
+                .arg(
+                    Arg::with_name("address")
+                        .help("Address to listen on")
+                        .long("address")
+                        .takes_value(true)
+                        .default_value("127.0.0.1:8080"),
+                )
+                .arg(
+                    Arg::with_name("max-concurrent")
+                        .help("Requests handled at the same time, further ones are rejected")
+                        .long("max-concurrent")
+                        .takes_value(true)
+                        .default_value("8"),
+                )
+                .arg(
+                    Arg::with_name("modules")
+                        .help("Modules search path, can be given several times")
+                        .short("m")
+                        .takes_value(true)
+                        .multiple(true)
+                        .number_of_values(1),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("daemon")
+                .about("Answer length-prefixed JSON-RPC requests to run pipelines on stdin and stdout or a Unix socket")
+                .arg(
+                    Arg::with_name("files")
+                        .help("Pipeline files or unpacked directories to load, as path or name=path")
+                        .required(true)
+                        .multiple(true)
+                        .index(1),
+                )
+                .arg(
+                    Arg::with_name("socket")
+                        .help("Unix socket to listen on instead of stdin and stdout")
+                        .long("socket")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("max-concurrent")
+                        .help("Runs handled at the same time over all connections, further ones are rejected")
+                        .long("max-concurrent")
+                        .takes_value(true)
+                        .default_value("8"),
+                )
+                .arg(
+                    Arg::with_name("modules")
+                        .help("Modules search path, can be given several times")
+                        .short("m")
+                        .takes_value(true)
+                        .multiple(true)
+                        .number_of_values(1),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("schema")
+                .about("Print the JSON Schema of pipeline definitions"),
+        )
         .get_matches();
 
+    let result = match matches.subcommand() {
+        ("pack", Some(matches)) => Some(pack(matches)),
+        ("unpack", Some(matches)) => Some(unpack(matches)),
+        ("inspect", Some(matches)) => Some(inspect(matches)),
+        ("sign", Some(matches)) => Some(sign(matches)),
+        ("schema", Some(_)) => Some(schema()),
+        ("serve", Some(serve_matches)) => Some(serve(&matches, serve_matches)),
+        ("daemon", Some(daemon_matches)) => Some(daemon(&matches, daemon_matches)),
+        _ => None,
+    };
+    if let Some(result) = result {
+        if let Err(e) = result {
+            error!("{}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    if let ("repl", Some(repl_matches)) = matches.subcommand() {
+        let result = match open_repl(&matches, repl_matches) {
+            Ok(session) => repl(session).await,
+            Err(e) => Err(e),
+        };
+        if let Err(e) = result {
+            error!("{}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    let policy = match residency_policy(&matches) {
+        Ok(policy) => policy,
+        Err(e) => {
+            error!("{}", e);
+            return;
+        }
+    };
+
     let mut vec_buffer = Vec::new();
     io::stdin().read_to_end(&mut vec_buffer).ok();
     info!("Input size: {}", vec_buffer.len());
@@ -48,24 +714,90 @@ async fn main_async() {
         return;
     }
 
+    // Both are checked against the possible values by clap
+    let input_format = match matches.value_of("input-format") {
+        Some(format) => format.parse().unwrap(),
+        None => detect_format(&vec_buffer),
+    };
+    let output_format = match matches.value_of("output-format") {
+        Some(format) => format.parse().unwrap(),
+        None => input_format,
+    };
+    info!(
+        "Input format: {}, output format: {}",
+        input_format, output_format
+    );
+
+    let keys = match trusted_keys(&matches) {
+        Ok(keys) => keys,
+        Err(e) => {
+            error!("Error reading trusted keys: {}", e);
+            return;
+        }
+    };
+
+    let variables = match variables(&matches) {
+        Ok(variables) => variables,
+        Err(e) => {
+            error!("{}", e);
+            return;
+        }
+    };
+
+    let search_paths = match search_paths(&matches) {
+        Ok(search_paths) => search_paths,
+        Err(e) => {
+            error!("Error reading module search paths: {}", e);
+            return;
+        }
+    };
+
     if let Some(pipeline_file) = matches.value_of(pipeline) {
-        match load_pipeline_file(Path::new(pipeline_file)) {
-            Ok((pipeline, resources, _td)) => {
+        match load_pipeline_with_keys(Path::new(pipeline_file), &keys) {
+            Ok(file) => {
+                if let Some(policy) = policy {
+                    if let Err(e) = file.resources.set_policy(policy) {
+                        error!("Error loading resources: {}", e);
+                        return;
+                    }
+                }
+
+                let pipeline = match file.pipeline(matches.value_of("name")) {
+                    Ok(pipeline) => pipeline.clone(),
+                    Err(e) => {
+                        error!("Error selecting pipeline: {}", e);
+                        return;
+                    }
+                };
+
                 let mut builder = PipelineRunConfigurationBuilder::default()
                     .pipeline(pipeline)
-                    .resources(resources)
+                    .resources(file.resources)
+                    .required_modules(file.manifest.modules)
+                    .variables(variables)
+                    .module_search_paths(search_paths)
+                    .input_format(input_format)
+                    .output_format(output_format)
                     .input(vec_buffer);
 
-                if let Some(search_path) = matches.value_of("modules") {
-                    builder = builder.module_search_path(PathBuf::from(search_path));
+                if let Some(trace) = matches.value_of("trace") {
+                    builder = builder
+                        .trace(Some(PathBuf::from(trace)))
+                        .trace_format(matches.value_of("trace-format").unwrap().parse().unwrap());
                 }
 
                 let runner = builder.build().expect("failed to build pipeline runner");
-                let mut result = runner.run().await;
+                let mut result = match runner.run().await {
+                    Ok(result) => result,
+                    Err(e) => {
+                        error!("Error running pipeline: {}", e);
+                        return;
+                    }
+                };
                 io::copy(&mut result.output, &mut io::stdout()).expect("write to succeed");
             }
             Err(e) => {
-                error!("Error loading pipeline file: {:?}", e);
+                error!("Error loading pipeline file: {}", e);
                 return;
             }
         }
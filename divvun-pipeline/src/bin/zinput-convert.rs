@@ -1,29 +1,110 @@
-use std::io;
+use std::{
+    fs,
+    io::{self, Read, Write},
+    process,
+};
 
 use clap::{crate_version, App, Arg};
 
-use divvun_schema::{capnp_message, string_capnp::string};
+use divvun_pipeline::convert::{encode, schema_by_name, schema_names, write_frame, DataFormat};
 
 fn main() {
+    let schema_names = schema_names();
     let matches = App::new("zinput-convert")
         .version(crate_version!())
         .about("Utility for converting input into a divvun-pipeline compatible format.")
         .arg(
             Arg::with_name("text")
-                .value_name("PATH")
-                .help("Convert text")
+                .value_name("TEXT")
+                .help("Convert the given text, can be repeated")
                 .short("t")
                 .long("text")
                 .takes_value(true)
-                .required(true),
+                .multiple(true)
+                .number_of_values(1),
+        )
+        .arg(
+            Arg::with_name("format")
+                .help("Format of the input")
+                .long("format")
+                .takes_value(true)
+                .possible_values(&["text", "json"])
+                .default_value("text"),
+        )
+        .arg(
+            Arg::with_name("type")
+                .help("Schema of the messages to create")
+                .long("type")
+                .takes_value(true)
+                .possible_values(&schema_names)
+                .default_value("string"),
+        )
+        .arg(
+            Arg::with_name("framed")
+                .help("Write a stream of messages, each preceded by its type id. Implied by several inputs")
+                .long("framed"),
+        )
+        .arg(
+            Arg::with_name("input")
+                .value_name("FILE")
+                .help("Files to convert, - for stdin. Stdin is read if neither files nor texts are given")
+                .multiple(true),
         )
         .get_matches();
 
-    if let Some(text) = matches.value_of("text") {
-        let msg = capnp_message!(string::Builder, builder => {
-            builder.set_string(text);
-        });
+    let format: DataFormat = matches.value_of("format").unwrap().parse().unwrap();
+    let schema = schema_by_name(matches.value_of("type").unwrap()).unwrap();
+
+    let mut inputs: Vec<Vec<u8>> = matches
+        .values_of("text")
+        .into_iter()
+        .flatten()
+        .map(|text| text.as_bytes().to_vec())
+        .collect();
+
+    let files: Vec<&str> = match matches.values_of("input") {
+        Some(files) => files.collect(),
+        None if inputs.is_empty() => vec!["-"],
+        None => vec![],
+    };
+    for file in files {
+        let data = if file == "-" {
+            let mut data = Vec::new();
+            io::stdin().read_to_end(&mut data).map(|_| data)
+        } else {
+            fs::read(file)
+        };
+
+        match data {
+            Ok(data) => inputs.push(data),
+            Err(e) => {
+                eprintln!("Failed to read {}: {}", file, e);
+                process::exit(1);
+            }
+        }
+    }
+
+    let framed = matches.is_present("framed") || inputs.len() > 1;
+    let stdout = io::stdout();
+    let mut stdout = stdout.lock();
+
+    for input in inputs {
+        let message = match encode(&input, format, schema.type_id) {
+            Ok(message) => message,
+            Err(e) => {
+                eprintln!("Failed to convert input: {}", e);
+                process::exit(1);
+            }
+        };
 
-        capnp::serialize::write_message(&mut io::stdout(), &msg).unwrap();
+        let result = if framed {
+            write_frame(&mut stdout, schema.type_id, &message)
+        } else {
+            stdout.write_all(&message)
+        };
+        if let Err(e) = result {
+            eprintln!("Failed to write output: {}", e);
+            process::exit(1);
+        }
     }
 }
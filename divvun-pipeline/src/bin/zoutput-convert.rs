@@ -1,17 +1,101 @@
-use std::io::{self, Write};
+use std::{
+    fs::File,
+    io::{self, BufReader, Read, Write},
+    process,
+};
 
-use divvun_schema::string_capnp::string;
+use clap::{crate_version, App, Arg};
+
+use divvun_pipeline::convert::{decode, read_frame, schema_by_name, schema_names, DataFormat};
 
 fn main() {
-    let message =
-        capnp::serialize::read_message(&mut io::stdin(), capnp::message::ReaderOptions::new())
-            .unwrap();
+    let schema_names = schema_names();
+    let matches = App::new("zoutput-convert")
+        .version(crate_version!())
+        .about("Utility for converting divvun-pipeline output into text or JSON.")
+        .arg(
+            Arg::with_name("format")
+                .help("Format to write the messages in, messages other than strings are written as JSON in text")
+                .long("format")
+                .takes_value(true)
+                .possible_values(&["text", "json"])
+                .default_value("text"),
+        )
+        .arg(
+            Arg::with_name("type")
+                .help("Schema of the message, unless it is framed")
+                .long("type")
+                .takes_value(true)
+                .possible_values(&schema_names)
+                .default_value("string"),
+        )
+        .arg(
+            Arg::with_name("framed")
+                .help("Read a stream of messages each preceded by its type id, as written by zinput-convert")
+                .long("framed"),
+        )
+        .arg(
+            Arg::with_name("input")
+                .value_name("FILE")
+                .help("File to read instead of stdin"),
+        )
+        .get_matches();
+
+    let format: DataFormat = matches.value_of("format").unwrap().parse().unwrap();
+
+    let mut input: Box<dyn Read> = match matches.value_of("input") {
+        Some("-") | None => Box::new(io::stdin()),
+        Some(path) => match File::open(path) {
+            Ok(file) => Box::new(BufReader::new(file)),
+            Err(e) => {
+                eprintln!("Failed to open {}: {}", path, e);
+                process::exit(1);
+            }
+        },
+    };
+
+    let stdout = io::stdout();
+    let mut stdout = stdout.lock();
+
+    if !matches.is_present("framed") {
+        let schema = schema_by_name(matches.value_of("type").unwrap()).unwrap();
+        let mut message = Vec::new();
+        if let Err(e) = input.read_to_end(&mut message) {
+            eprintln!("Failed to read input: {}", e);
+            process::exit(1);
+        }
+
+        match decode(&message, format, schema.type_id) {
+            Ok(output) => stdout.write_all(&output).unwrap(),
+            Err(e) => {
+                eprintln!("Failed to convert message: {}", e);
+                process::exit(1);
+            }
+        }
+        return;
+    }
 
-    let string = message
-        .get_root::<string::Reader>()
-        .unwrap()
-        .get_string()
-        .unwrap();
+    let mut first = true;
+    loop {
+        let output = read_frame(&mut input).and_then(|frame| match frame {
+            Some((type_id, message)) => decode(&message, format, type_id).map(Some),
+            None => Ok(None),
+        });
 
-    io::stdout().write_all(string.as_bytes()).unwrap();
+        match output {
+            Ok(Some(output)) => {
+                // One message per line
+                if !first {
+                    stdout.write_all(b"\n").unwrap();
+                }
+                first = false;
+                stdout.write_all(&output).unwrap();
+            }
+            Ok(None) => break,
+            Err(e) => {
+                eprintln!("Failed to convert message: {}", e);
+                process::exit(1);
+            }
+        }
+    }
 }
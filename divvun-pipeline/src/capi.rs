@@ -0,0 +1,247 @@
+//! C API for running pipelines from other languages, declared in `include/divvun_pipeline.h`.
+//!
+//! Functions report failure by returning `NULL` or `false`, after which `dp_error_message`
+//! describes what went wrong. Pipelines can be run from several threads at once.
+
+use std::{
+    cell::RefCell,
+    collections::BTreeMap,
+    error::Error,
+    ffi::{CStr, CString},
+    os::raw::c_char,
+    panic::{self, AssertUnwindSafe},
+    path::{Path, PathBuf},
+    ptr, slice,
+};
+
+use parking_lot::Mutex;
+
+use crate::{
+    convert::DataFormat,
+    file::load_pipeline_with_keys,
+    module::{module_search_paths, trusted_keys},
+    session::{PipelineSession, SessionRequest},
+};
+
+/// A pipeline file with its modules loaded
+pub struct DpPipeline {
+    session: PipelineSession,
+    variables: Mutex<BTreeMap<String, String>>,
+}
+
+/// The output of a pipeline run
+pub struct DpOutput {
+    data: Vec<u8>,
+}
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum DpDataFormat {
+    Text,
+    Capnp,
+    Json,
+}
+
+impl From<DpDataFormat> for DataFormat {
+    fn from(format: DpDataFormat) -> DataFormat {
+        match format {
+            DpDataFormat::Text => DataFormat::Text,
+            DpDataFormat::Capnp => DataFormat::Capnp,
+            DpDataFormat::Json => DataFormat::Json,
+        }
+    }
+}
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<CString>> = RefCell::new(None);
+}
+
+fn set_error(message: String) {
+    let message = CString::new(message.replace('\0', "")).unwrap_or_default();
+    LAST_ERROR.with(|error| *error.borrow_mut() = Some(message));
+}
+
+/// Run the body of an exported function, turning errors and panics into the last error
+fn call<T, F>(failed: T, f: F) -> T
+where
+    F: FnOnce() -> Result<T, Box<dyn Error>>,
+{
+    LAST_ERROR.with(|error| *error.borrow_mut() = None);
+    match panic::catch_unwind(AssertUnwindSafe(f)) {
+        Ok(Ok(value)) => value,
+        Ok(Err(e)) => {
+            set_error(e.to_string());
+            failed
+        }
+        Err(_) => {
+            set_error("divvun-pipeline panicked".to_string());
+            failed
+        }
+    }
+}
+
+/// A UTF-8 string argument, `None` for `NULL`
+unsafe fn string_arg<'a>(
+    value: *const c_char,
+    name: &str,
+) -> Result<Option<&'a str>, Box<dyn Error>> {
+    if value.is_null() {
+        return Ok(None);
+    }
+    CStr::from_ptr(value)
+        .to_str()
+        .map(Some)
+        .map_err(|_| format!("{} is not valid UTF-8", name).into())
+}
+
+/// Load a pipeline file or unpacked directory and the modules its manifest requires. They are
+/// looked up in `module_search_path` if it isn't `NULL`, then in `DIVVUN_PIPELINE_MODULE_PATH`
+/// and the paths of the config files. A signed file has to be signed by one of the keys in
+/// `trusted_key_file`, if it isn't `NULL`, or of the config files. Returns `NULL` on failure.
+///
+/// # Safety
+///
+/// The paths have to be nul-terminated UTF-8 strings, `module_search_path` and
+/// `trusted_key_file` can be `NULL`.
+#[no_mangle]
+pub unsafe extern "C" fn dp_pipeline_open(
+    path: *const c_char,
+    module_search_path: *const c_char,
+    trusted_key_file: *const c_char,
+) -> *mut DpPipeline {
+    call(ptr::null_mut(), || {
+        let path = string_arg(path, "path")?.ok_or("path is NULL")?;
+        let explicit: Vec<PathBuf> = string_arg(module_search_path, "module_search_path")?
+            .map(PathBuf::from)
+            .into_iter()
+            .collect();
+        let key_files: Vec<PathBuf> = string_arg(trusted_key_file, "trusted_key_file")?
+            .map(PathBuf::from)
+            .into_iter()
+            .collect();
+
+        let file = load_pipeline_with_keys(Path::new(path), &trusted_keys(&key_files)?)?;
+        let session = PipelineSession::new(file, &module_search_paths(&explicit)?)?;
+        Ok(Box::into_raw(Box::new(DpPipeline {
+            session,
+            variables: Mutex::new(BTreeMap::new()),
+        })))
+    })
+}
+
+/// Set the value of `${key}` placeholders in module names and parameters for the following runs
+///
+/// # Safety
+///
+/// `pipeline` has to come from `dp_pipeline_open`, `key` and `value` have to be nul-terminated
+/// UTF-8 strings.
+#[no_mangle]
+pub unsafe extern "C" fn dp_pipeline_set_variable(
+    pipeline: *const DpPipeline,
+    key: *const c_char,
+    value: *const c_char,
+) -> bool {
+    call(false, || {
+        let pipeline = pipeline.as_ref().ok_or("pipeline is NULL")?;
+        let key = string_arg(key, "key")?.ok_or("key is NULL")?;
+        let value = string_arg(value, "value")?.ok_or("value is NULL")?;
+        pipeline
+            .variables
+            .lock()
+            .insert(key.to_string(), value.to_string());
+        Ok(true)
+    })
+}
+
+/// Run the pipeline named `name`, or the default one if it is `NULL`, on `input_size` bytes of
+/// `input`. Returns the output in `output_format`, to be released with `dp_output_free`, or
+/// `NULL` on failure.
+///
+/// # Safety
+///
+/// `pipeline` has to come from `dp_pipeline_open`, `name` has to be `NULL` or a
+/// nul-terminated string and `input` has to point to `input_size` readable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn dp_pipeline_run(
+    pipeline: *const DpPipeline,
+    name: *const c_char,
+    input: *const u8,
+    input_size: usize,
+    input_format: DpDataFormat,
+    output_format: DpDataFormat,
+) -> *mut DpOutput {
+    call(ptr::null_mut(), || {
+        let pipeline = pipeline.as_ref().ok_or("pipeline is NULL")?;
+        let input = match input_size {
+            0 => &[][..],
+            _ if input.is_null() => return Err("input is NULL".into()),
+            _ => slice::from_raw_parts(input, input_size),
+        };
+
+        let mut request = SessionRequest::new(input.to_vec(), input_format.into());
+        request.pipeline = string_arg(name, "name")?.map(str::to_string);
+        request.output_format = output_format.into();
+        request.variables = pipeline.variables.lock().clone();
+
+        let data = pipeline.session.run_blocking(request)?;
+        Ok(Box::into_raw(Box::new(DpOutput { data })))
+    })
+}
+
+/// Unload a pipeline. Outputs of its runs stay valid.
+///
+/// # Safety
+///
+/// `pipeline` has to come from `dp_pipeline_open` or be `NULL`, and must not be used afterwards.
+#[no_mangle]
+pub unsafe extern "C" fn dp_pipeline_close(pipeline: *mut DpPipeline) {
+    if !pipeline.is_null() {
+        drop(Box::from_raw(pipeline));
+    }
+}
+
+/// The bytes of an output, which are not nul-terminated
+///
+/// # Safety
+///
+/// `output` has to come from `dp_pipeline_run`.
+#[no_mangle]
+pub unsafe extern "C" fn dp_output_data(output: *const DpOutput) -> *const u8 {
+    match output.as_ref() {
+        Some(output) => output.data.as_ptr(),
+        None => ptr::null(),
+    }
+}
+
+/// The number of bytes of an output
+///
+/// # Safety
+///
+/// `output` has to come from `dp_pipeline_run`.
+#[no_mangle]
+pub unsafe extern "C" fn dp_output_size(output: *const DpOutput) -> usize {
+    output.as_ref().map_or(0, |output| output.data.len())
+}
+
+/// Release an output
+///
+/// # Safety
+///
+/// `output` has to come from `dp_pipeline_run` or be `NULL`, and must not be used afterwards.
+#[no_mangle]
+pub unsafe extern "C" fn dp_output_free(output: *mut DpOutput) {
+    if !output.is_null() {
+        drop(Box::from_raw(output));
+    }
+}
+
+/// Why the last call on this thread failed, `NULL` if it succeeded. Valid until the next call.
+#[no_mangle]
+pub extern "C" fn dp_error_message() -> *const c_char {
+    LAST_ERROR.with(|error| {
+        error
+            .borrow()
+            .as_ref()
+            .map_or(ptr::null(), |message| message.as_ptr())
+    })
+}
@@ -0,0 +1,481 @@
+use std::{
+    error::Error,
+    fmt,
+    io::{self, Cursor, Read, Write},
+    str::FromStr,
+};
+
+use capnp::{message::ReaderOptions, serialize, traits::HasTypeId};
+use divvun_schema::{
+    error_capnp::pipeline_error::{self, ErrorKind},
+    module_metadata_capnp::module_metadata,
+    string_capnp::string,
+};
+use serde::{Deserialize, Serialize};
+
+/// How data is represented outside of the pipeline
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum DataFormat {
+    /// Plain UTF-8 text, for types that hold a single text
+    Text,
+    /// A serialized capnp message, as passed between modules
+    Capnp,
+    /// The fields of the message as a JSON object
+    Json,
+}
+
+impl DataFormat {
+    pub fn names() -> &'static [&'static str] {
+        &["text", "capnp", "json"]
+    }
+}
+
+impl FromStr for DataFormat {
+    type Err = ConvertError;
+
+    fn from_str(name: &str) -> Result<DataFormat, ConvertError> {
+        match name {
+            "text" => Ok(DataFormat::Text),
+            "capnp" => Ok(DataFormat::Capnp),
+            "json" => Ok(DataFormat::Json),
+            _ => Err(ConvertError::UnknownFormat(name.to_string())),
+        }
+    }
+}
+
+impl fmt::Display for DataFormat {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DataFormat::Text => write!(f, "text"),
+            DataFormat::Capnp => write!(f, "capnp"),
+            DataFormat::Json => write!(f, "json"),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ConvertError {
+    UnknownFormat(String),
+    UnknownSchema(String),
+    UnsupportedType { type_id: u64, format: DataFormat },
+    InvalidText(std::str::Utf8Error),
+    InvalidValue(String),
+    InvalidFrame(String),
+    Json(serde_json::Error),
+    Capnp(capnp::Error),
+    Io(io::Error),
+}
+
+impl fmt::Display for ConvertError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ConvertError::UnknownFormat(ref name) => write!(
+                f,
+                "unknown format {}, expected one of {}",
+                name,
+                DataFormat::names().join(", ")
+            ),
+            ConvertError::UnknownSchema(ref name) => write!(
+                f,
+                "unknown schema {}, expected one of {}",
+                name,
+                schema_names().join(", ")
+            ),
+            ConvertError::UnsupportedType { type_id, format } => write!(
+                f,
+                "messages of type {:#x} can't be converted from or to {}",
+                type_id, format
+            ),
+            ConvertError::InvalidText(ref e) => write!(f, "input is not valid UTF-8: {}", e),
+            ConvertError::InvalidValue(ref message) => write!(f, "invalid value: {}", message),
+            ConvertError::InvalidFrame(ref message) => write!(f, "invalid frame: {}", message),
+            ConvertError::Json(ref e) => write!(f, "invalid JSON: {}", e),
+            ConvertError::Capnp(ref e) => write!(f, "invalid message: {}", e),
+            ConvertError::Io(ref e) => write!(f, "failed to read or write messages: {}", e),
+        }
+    }
+}
+
+impl Error for ConvertError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            ConvertError::InvalidText(ref e) => Some(e),
+            ConvertError::Json(ref e) => Some(e),
+            ConvertError::Capnp(ref e) => Some(e),
+            ConvertError::Io(ref e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<capnp::Error> for ConvertError {
+    fn from(e: capnp::Error) -> Self {
+        ConvertError::Capnp(e)
+    }
+}
+
+impl From<io::Error> for ConvertError {
+    fn from(e: io::Error) -> Self {
+        ConvertError::Io(e)
+    }
+}
+
+/// Type id of the `String` schema
+pub fn string_type_id() -> u64 {
+    <string::Builder as HasTypeId>::type_id()
+}
+
+/// A schema of divvun-schema whose messages can be converted from and to JSON
+#[derive(Debug, Copy, Clone)]
+pub struct Schema {
+    pub name: &'static str,
+    pub type_id: u64,
+    to_json: fn(&[u8]) -> Result<serde_json::Value, ConvertError>,
+    from_json: fn(serde_json::Value) -> Result<Vec<u8>, ConvertError>,
+}
+
+impl Schema {
+    /// The fields of a message as a JSON object
+    pub fn to_json(&self, message: &[u8]) -> Result<serde_json::Value, ConvertError> {
+        (self.to_json)(message)
+    }
+
+    /// Build a message from a JSON object with the fields of the schema
+    pub fn from_json(&self, value: serde_json::Value) -> Result<Vec<u8>, ConvertError> {
+        (self.from_json)(value)
+    }
+}
+
+/// All schemas messages can be converted for, new schemas of divvun-schema are added here
+pub fn schemas() -> Vec<Schema> {
+    vec![
+        Schema {
+            name: "string",
+            type_id: string_type_id(),
+            to_json: string_to_json,
+            from_json: string_from_json,
+        },
+        Schema {
+            name: "pipeline_error",
+            type_id: <pipeline_error::Builder as HasTypeId>::type_id(),
+            to_json: pipeline_error_to_json,
+            from_json: pipeline_error_from_json,
+        },
+        Schema {
+            name: "module_metadata",
+            type_id: <module_metadata::Builder as HasTypeId>::type_id(),
+            to_json: module_metadata_to_json,
+            from_json: module_metadata_from_json,
+        },
+    ]
+}
+
+pub fn schema_names() -> Vec<&'static str> {
+    schemas().iter().map(|schema| schema.name).collect()
+}
+
+pub fn schema_by_name(name: &str) -> Result<Schema, ConvertError> {
+    schemas()
+        .into_iter()
+        .find(|schema| schema.name == name)
+        .ok_or_else(|| ConvertError::UnknownSchema(name.to_string()))
+}
+
+pub fn schema_by_type_id(type_id: u64) -> Option<Schema> {
+    schemas()
+        .into_iter()
+        .find(|schema| schema.type_id == type_id)
+}
+
+/// Whether the data is a single complete capnp message, otherwise it is taken to be text
+pub fn detect_format(data: &[u8]) -> DataFormat {
+    let mut cursor = Cursor::new(data);
+    match serialize::read_message(&mut cursor, ReaderOptions::new()) {
+        Ok(_) if cursor.position() == data.len() as u64 => DataFormat::Capnp,
+        _ => DataFormat::Text,
+    }
+}
+
+/// Turn data in the given format into a capnp message of the type with `type_id`. Text can only
+/// be converted into `String` messages, JSON into messages of any known schema.
+pub fn encode(data: &[u8], format: DataFormat, type_id: u64) -> Result<Vec<u8>, ConvertError> {
+    match format {
+        DataFormat::Capnp => Ok(data.to_vec()),
+        DataFormat::Text if type_id == string_type_id() => {
+            string_message(std::str::from_utf8(data).map_err(ConvertError::InvalidText)?)
+        }
+        DataFormat::Json => {
+            let schema = schema_by_type_id(type_id)
+                .ok_or(ConvertError::UnsupportedType { type_id, format })?;
+            schema.from_json(serde_json::from_slice(data).map_err(ConvertError::Json)?)
+        }
+        _ => Err(ConvertError::UnsupportedType { type_id, format }),
+    }
+}
+
+/// Turn a capnp message of the type with `type_id` into the given format. As text, messages
+/// other than `String` are written as JSON.
+pub fn decode(message: &[u8], format: DataFormat, type_id: u64) -> Result<Vec<u8>, ConvertError> {
+    if format == DataFormat::Capnp {
+        return Ok(message.to_vec());
+    }
+
+    if format == DataFormat::Text && type_id == string_type_id() {
+        let reader = serialize::read_message(&mut Cursor::new(message), ReaderOptions::new())?;
+        let text = reader.get_root::<string::Reader>()?.get_string()?;
+        return Ok(text.as_bytes().to_vec());
+    }
+
+    let schema =
+        schema_by_type_id(type_id).ok_or(ConvertError::UnsupportedType { type_id, format })?;
+    serde_json::to_vec(&schema.to_json(message)?).map_err(ConvertError::Json)
+}
+
+/// Largest message accepted in a frame, matching the default traversal limit of capnp
+const MAX_FRAME_WORDS: u64 = 8 * 1024 * 1024;
+const MAX_FRAME_SEGMENTS: usize = 512;
+
+/// Write a message to a stream of several messages. Every message is preceded by the type id of
+/// its schema as 8 little endian bytes, so readers know how to decode it.
+pub fn write_frame<W: Write>(writer: &mut W, type_id: u64, message: &[u8]) -> io::Result<()> {
+    writer.write_all(&type_id.to_le_bytes())?;
+    writer.write_all(message)
+}
+
+/// Read the next message of a stream written with `write_frame`, returning its type id and the
+/// serialized message. Returns `None` at the end of the stream.
+pub fn read_frame<R: Read>(reader: &mut R) -> Result<Option<(u64, Vec<u8>)>, ConvertError> {
+    let mut header = [0u8; 8];
+    if !read_or_eof(reader, &mut header)? {
+        return Ok(None);
+    }
+    let type_id = u64::from_le_bytes(header);
+
+    // Copy the message as it is, using its segment table to find where it ends
+    let mut count = [0u8; 4];
+    reader.read_exact(&mut count)?;
+    let segments = u32::from_le_bytes(count) as usize + 1;
+    if segments > MAX_FRAME_SEGMENTS {
+        return Err(ConvertError::InvalidFrame(format!(
+            "message has {} segments",
+            segments
+        )));
+    }
+
+    let padding = if segments % 2 == 0 { 4 } else { 0 };
+    let mut table = vec![0u8; segments * 4 + padding];
+    reader.read_exact(&mut table)?;
+    let words: u64 = table[..segments * 4]
+        .chunks(4)
+        .map(|size| u64::from(u32::from_le_bytes([size[0], size[1], size[2], size[3]])))
+        .sum();
+    if words > MAX_FRAME_WORDS {
+        return Err(ConvertError::InvalidFrame(format!(
+            "message of {} words is too large",
+            words
+        )));
+    }
+
+    let mut message = Vec::with_capacity(4 + table.len() + words as usize * 8);
+    message.extend_from_slice(&count);
+    message.extend_from_slice(&table);
+    let start = message.len();
+    message.resize(start + words as usize * 8, 0);
+    reader.read_exact(&mut message[start..])?;
+
+    Ok(Some((type_id, message)))
+}
+
+/// Fill the buffer, returning false if the reader is at its end before the first byte
+fn read_or_eof<R: Read>(reader: &mut R, buf: &mut [u8]) -> io::Result<bool> {
+    let mut read = 0;
+    while read < buf.len() {
+        match reader.read(&mut buf[read..]) {
+            Ok(0) if read == 0 => return Ok(false),
+            Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+            Ok(n) => read += n,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(true)
+}
+
+fn read_message(
+    data: &[u8],
+) -> Result<capnp::message::Reader<serialize::OwnedSegments>, ConvertError> {
+    Ok(serialize::read_message(
+        &mut Cursor::new(data),
+        ReaderOptions::new(),
+    )?)
+}
+
+fn message_bytes<A: capnp::message::Allocator>(
+    message: &capnp::message::Builder<A>,
+) -> Result<Vec<u8>, ConvertError> {
+    let mut output = Vec::new();
+    serialize::write_message(&mut output, message).map_err(capnp::Error::from)?;
+    Ok(output)
+}
+
+#[derive(Serialize, Deserialize)]
+struct StringJson {
+    string: String,
+}
+
+fn string_message(text: &str) -> Result<Vec<u8>, ConvertError> {
+    let mut message = capnp::message::Builder::new_default();
+    message.init_root::<string::Builder>().set_string(text);
+    message_bytes(&message)
+}
+
+fn string_to_json(data: &[u8]) -> Result<serde_json::Value, ConvertError> {
+    let message = read_message(data)?;
+    let string = message
+        .get_root::<string::Reader>()?
+        .get_string()?
+        .to_string();
+    serde_json::to_value(StringJson { string }).map_err(ConvertError::Json)
+}
+
+fn string_from_json(value: serde_json::Value) -> Result<Vec<u8>, ConvertError> {
+    let json: StringJson = serde_json::from_value(value).map_err(ConvertError::Json)?;
+    string_message(&json.string)
+}
+
+#[derive(Serialize, Deserialize)]
+struct PipelineErrorJson {
+    kind: String,
+    message: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    source: Option<Box<PipelineErrorJson>>,
+}
+
+/// Error kinds by their name in the schema
+const ERROR_KINDS: &[(&str, ErrorKind)] = &[
+    ("unknownCommand", ErrorKind::UnknownCommand),
+    ("parallelError", ErrorKind::ParallelError),
+    ("sequenceError", ErrorKind::SequenceError),
+    ("moduleError", ErrorKind::ModuleError),
+    ("invalidInput", ErrorKind::InvalidInput),
+    ("invalidParameters", ErrorKind::InvalidParameters),
+];
+
+fn pipeline_error_json(reader: pipeline_error::Reader) -> Result<PipelineErrorJson, ConvertError> {
+    let kind = reader.get_kind().map_err(capnp::Error::from)?;
+    let kind = ERROR_KINDS
+        .iter()
+        .find(|(_, k)| *k == kind)
+        .map(|(name, _)| name.to_string())
+        .ok_or_else(|| ConvertError::InvalidValue("unknown error kind".to_string()))?;
+
+    let source = match reader.get_source().which().map_err(capnp::Error::from)? {
+        pipeline_error::source::NoError(()) => None,
+        pipeline_error::source::Error(error) => Some(Box::new(pipeline_error_json(error?)?)),
+    };
+
+    Ok(PipelineErrorJson {
+        kind,
+        message: reader.get_message()?.to_string(),
+        source,
+    })
+}
+
+fn build_pipeline_error(
+    mut builder: pipeline_error::Builder,
+    json: &PipelineErrorJson,
+) -> Result<(), ConvertError> {
+    let kind = ERROR_KINDS
+        .iter()
+        .find(|(name, _)| *name == json.kind)
+        .map(|(_, kind)| *kind)
+        .ok_or_else(|| ConvertError::InvalidValue(format!("unknown error kind {}", json.kind)))?;
+
+    builder.set_kind(kind);
+    builder.set_message(&json.message);
+    match json.source {
+        Some(ref source) => build_pipeline_error(builder.init_source().init_error(), source),
+        None => {
+            builder.init_source().set_no_error(());
+            Ok(())
+        }
+    }
+}
+
+fn pipeline_error_to_json(data: &[u8]) -> Result<serde_json::Value, ConvertError> {
+    let message = read_message(data)?;
+    let json = pipeline_error_json(message.get_root::<pipeline_error::Reader>()?)?;
+    serde_json::to_value(json).map_err(ConvertError::Json)
+}
+
+fn pipeline_error_from_json(value: serde_json::Value) -> Result<Vec<u8>, ConvertError> {
+    let json: PipelineErrorJson = serde_json::from_value(value).map_err(ConvertError::Json)?;
+    let mut message = capnp::message::Builder::new_default();
+    build_pipeline_error(message.init_root::<pipeline_error::Builder>(), &json)?;
+    message_bytes(&message)
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ModuleMetadataJson {
+    module_name: String,
+    module_version: String,
+    #[serde(default)]
+    commands: Vec<ModuleCommandMetadataJson>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct ModuleCommandMetadataJson {
+    name: String,
+    #[serde(default)]
+    inputs: Vec<u64>,
+    output: u64,
+}
+
+fn module_metadata_to_json(data: &[u8]) -> Result<serde_json::Value, ConvertError> {
+    let message = read_message(data)?;
+    module_metadata_json(message.get_root::<module_metadata::Reader>()?)
+}
+
+/// The metadata a module reports, in the JSON form of the `module_metadata` schema
+pub fn module_metadata_json(
+    reader: module_metadata::Reader,
+) -> Result<serde_json::Value, ConvertError> {
+    let mut commands = Vec::new();
+    for command in reader.get_commands()?.iter() {
+        commands.push(ModuleCommandMetadataJson {
+            name: command.get_name()?.to_string(),
+            inputs: command.get_inputs()?.iter().collect(),
+            output: command.get_output(),
+        });
+    }
+
+    serde_json::to_value(ModuleMetadataJson {
+        module_name: reader.get_module_name()?.to_string(),
+        module_version: reader.get_module_version()?.to_string(),
+        commands,
+    })
+    .map_err(ConvertError::Json)
+}
+
+fn module_metadata_from_json(value: serde_json::Value) -> Result<Vec<u8>, ConvertError> {
+    let json: ModuleMetadataJson = serde_json::from_value(value).map_err(ConvertError::Json)?;
+
+    let mut message = capnp::message::Builder::new_default();
+    let mut root = message.init_root::<module_metadata::Builder>();
+    root.set_module_name(&json.module_name);
+    root.set_module_version(&json.module_version);
+
+    let mut commands = root.init_commands(json.commands.len() as u32);
+    for (i, command) in json.commands.iter().enumerate() {
+        let mut builder = commands.reborrow().get(i as u32);
+        builder.set_name(&command.name);
+        builder.set_output(command.output);
+        let mut inputs = builder.init_inputs(command.inputs.len() as u32);
+        for (j, input) in command.inputs.iter().enumerate() {
+            inputs.set(j as u32, *input);
+        }
+    }
+
+    message_bytes(&message)
+}
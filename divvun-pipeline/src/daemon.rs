@@ -0,0 +1,493 @@
+use std::{
+    collections::{hash_map::Entry, BTreeMap, HashMap},
+    convert::TryFrom,
+    io::{self, BufReader, BufWriter, Read, Write},
+    panic::{self, AssertUnwindSafe},
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc,
+    },
+    thread,
+};
+
+use log::{error, warn};
+use parking_lot::{Condvar, Mutex};
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+use crate::{
+    convert::DataFormat,
+    hex::{decode_hex, encode_hex},
+    pipeline::PipelineError,
+    session::{PipelinePackages, SessionRequest},
+};
+
+#[cfg(unix)]
+use crate::serve::ShutdownHandle;
+#[cfg(unix)]
+use std::{
+    fs,
+    net::Shutdown,
+    os::unix::{
+        fs::FileTypeExt,
+        net::{UnixListener, UnixStream},
+    },
+    path::Path,
+    time::Duration,
+};
+
+/// Largest message accepted
+pub const MAX_MESSAGE_SIZE: usize = 64 * 1024 * 1024;
+#[cfg(unix)]
+const ACCEPT_INTERVAL: Duration = Duration::from_millis(20);
+
+pub const PARSE_ERROR: i64 = -32700;
+pub const INVALID_REQUEST: i64 = -32600;
+pub const METHOD_NOT_FOUND: i64 = -32601;
+pub const INVALID_PARAMS: i64 = -32602;
+pub const REQUEST_CANCELLED: i64 = -32800;
+pub const RUN_FAILED: i64 = -32000;
+pub const TOO_MANY_RUNS: i64 = -32001;
+
+/// Read a message prefixed with its length as a little endian u32, `None` at the end of the stream
+pub fn read_message<R: Read>(reader: &mut R) -> io::Result<Option<Vec<u8>>> {
+    let mut length = [0u8; 4];
+    let mut read = 0;
+    while read < length.len() {
+        match reader.read(&mut length[read..]) {
+            Ok(0) if read == 0 => return Ok(None),
+            Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+            Ok(n) => read += n,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+
+    let length = u32::from_le_bytes(length) as usize;
+    if length > MAX_MESSAGE_SIZE {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("message of {} bytes is too large", length),
+        ));
+    }
+
+    let mut message = vec![0; length];
+    reader.read_exact(&mut message)?;
+    Ok(Some(message))
+}
+
+pub fn write_message<W: Write>(writer: &mut W, message: &[u8]) -> io::Result<()> {
+    let length = u32::try_from(message.len())
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "message is too large"))?;
+    writer.write_all(&length.to_le_bytes())?;
+    writer.write_all(message)?;
+    writer.flush()
+}
+
+#[derive(Deserialize)]
+struct Request {
+    #[serde(default)]
+    id: Option<Value>,
+    method: String,
+    #[serde(default)]
+    params: Value,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RunParams {
+    pipeline: String,
+    input: Value,
+    #[serde(default)]
+    format: Option<String>,
+    #[serde(default)]
+    output_format: Option<String>,
+    #[serde(default)]
+    variables: BTreeMap<String, String>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct CancelParams {
+    id: Value,
+}
+
+struct RpcError {
+    code: i64,
+    message: String,
+}
+
+impl RpcError {
+    fn new<M: ToString>(code: i64, message: M) -> RpcError {
+        RpcError {
+            code,
+            message: message.to_string(),
+        }
+    }
+}
+
+fn response(id: Value, result: Result<Value, RpcError>) -> Value {
+    match result {
+        Ok(result) => json!({ "jsonrpc": "2.0", "id": id, "result": result }),
+        Err(e) => json!({
+            "jsonrpc": "2.0",
+            "id": id,
+            "error": { "code": e.code, "message": e.message },
+        }),
+    }
+}
+
+fn parse_format(name: &Option<String>) -> Result<Option<DataFormat>, RpcError> {
+    name.as_ref()
+        .map(|name| name.parse().map_err(|e| RpcError::new(INVALID_PARAMS, e)))
+        .transpose()
+}
+
+/// The input of a run, text as a string, JSON as any value and Cap'n Proto as a hex string
+fn input_data(input: Value, format: DataFormat) -> Result<Vec<u8>, RpcError> {
+    match (format, input) {
+        (DataFormat::Text, Value::String(text)) => Ok(text.into_bytes()),
+        (DataFormat::Json, value) => {
+            serde_json::to_vec(&value).map_err(|e| RpcError::new(INVALID_PARAMS, e))
+        }
+        (DataFormat::Capnp, Value::String(hex)) => decode_hex(&hex)
+            .ok_or_else(|| RpcError::new(INVALID_PARAMS, "capnp input must be a hex string")),
+        (format, _) => Err(RpcError::new(
+            INVALID_PARAMS,
+            format!("{} input must be a string", format),
+        )),
+    }
+}
+
+fn output_value(output: Vec<u8>, format: DataFormat) -> Result<Value, RpcError> {
+    match format {
+        DataFormat::Text => Ok(Value::String(String::from_utf8_lossy(&output).into_owned())),
+        DataFormat::Json => {
+            serde_json::from_slice(&output).map_err(|e| RpcError::new(RUN_FAILED, e))
+        }
+        DataFormat::Capnp => Ok(Value::String(encode_hex(&output))),
+    }
+}
+
+/// Runs in flight on a connection, by the JSON of their request id
+type RunningRequests = Arc<Mutex<HashMap<String, Arc<AtomicBool>>>>;
+
+/// Number of runs in flight on a connection, so its end can wait for them
+#[derive(Default)]
+struct RunCounter {
+    count: Mutex<usize>,
+    finished: Condvar,
+}
+
+impl RunCounter {
+    fn wait(&self) {
+        let mut count = self.count.lock();
+        while *count > 0 {
+            self.finished.wait(&mut count);
+        }
+    }
+}
+
+/// Holds a slot of the daemon's limit and counts towards the runs of its connection until the
+/// run is answered
+struct ActiveRun {
+    active: Arc<AtomicUsize>,
+    connection: Arc<RunCounter>,
+}
+
+impl Drop for ActiveRun {
+    fn drop(&mut self) {
+        self.active.fetch_sub(1, Ordering::SeqCst);
+        *self.connection.count.lock() -= 1;
+        self.connection.finished.notify_all();
+    }
+}
+
+/// Answers requests for the pipelines of one or more pipeline files, which are loaded once and
+/// shared by all connections.
+///
+/// Every message is a JSON-RPC 2.0 request or response prefixed with its length as a little
+/// endian u32. `list_pipelines` returns the pipeline names, `run` runs a pipeline on an input
+/// and `cancel` stops a run of the same connection before its next command. Runs are answered
+/// as they finish, not necessarily in the order they were requested. At most `max_concurrent`
+/// runs happen at a time over all connections, further ones are rejected.
+#[derive(Clone)]
+pub struct Daemon {
+    packages: Arc<PipelinePackages>,
+    max_concurrent: usize,
+    active: Arc<AtomicUsize>,
+}
+
+impl Daemon {
+    pub fn new(packages: PipelinePackages, max_concurrent: usize) -> Daemon {
+        Daemon {
+            packages: Arc::new(packages),
+            max_concurrent,
+            active: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    /// Answer the requests of a connection until the reader ends, then wait for the runs in
+    /// flight
+    pub fn serve<R, W>(&self, mut reader: R, writer: W) -> io::Result<()>
+    where
+        R: Read,
+        W: Write + Send + 'static,
+    {
+        let writer = Arc::new(Mutex::new(writer));
+        let running: RunningRequests = Default::default();
+        let runs = Arc::new(RunCounter::default());
+
+        while let Some(message) = read_message(&mut reader)? {
+            let request = match serde_json::from_slice::<Value>(&message) {
+                Ok(value) => value,
+                Err(e) => {
+                    let error = RpcError::new(PARSE_ERROR, e);
+                    send(&writer, response(Value::Null, Err(error)))?;
+                    continue;
+                }
+            };
+            let request = match serde_json::from_value::<Request>(request) {
+                Ok(request) => request,
+                Err(e) => {
+                    let error = RpcError::new(INVALID_REQUEST, e);
+                    send(&writer, response(Value::Null, Err(error)))?;
+                    continue;
+                }
+            };
+
+            // Requests without an id are notifications, which are never answered
+            let notification = request.id.is_none();
+            let id = request.id.unwrap_or(Value::Null);
+            let result = match &*request.method {
+                "list_pipelines" => Ok(json!(self.packages.pipeline_names())),
+                "cancel" => self.cancel(request.params, &running),
+                "run" => match self.start_run(
+                    id.clone(),
+                    request.params,
+                    notification,
+                    &writer,
+                    &running,
+                    &runs,
+                ) {
+                    Ok(()) => continue,
+                    Err(e) => Err(e),
+                },
+                method => Err(RpcError::new(
+                    METHOD_NOT_FOUND,
+                    format!("unknown method {}", method),
+                )),
+            };
+            if !notification {
+                send(&writer, response(id, result))?;
+            }
+        }
+
+        runs.wait();
+        Ok(())
+    }
+
+    pub fn serve_stdio(&self) -> io::Result<()> {
+        self.serve(BufReader::new(io::stdin()), BufWriter::new(io::stdout()))
+    }
+
+    /// Accept connections on a Unix socket until shut down. Open connections then stop being
+    /// read from and the runs in flight are waited for.
+    #[cfg(unix)]
+    pub fn serve_unix(&self, path: &Path, shutdown: ShutdownHandle) -> io::Result<()> {
+        // A socket left behind by an earlier daemon can't be bound again
+        let stale = fs::symlink_metadata(path)
+            .map(|metadata| metadata.file_type().is_socket())
+            .unwrap_or(false);
+        if stale && UnixStream::connect(path).is_err() {
+            fs::remove_file(path)?;
+        }
+        let listener = UnixListener::bind(path)?;
+        listener.set_nonblocking(true)?;
+        let active = Arc::new(AtomicUsize::new(0));
+        let connections = Arc::new(Mutex::new(HashMap::new()));
+        let mut next_connection = 0usize;
+
+        while !shutdown.is_shutdown() {
+            let stream = match listener.accept() {
+                Ok((stream, _)) => stream,
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    thread::sleep(ACCEPT_INTERVAL);
+                    continue;
+                }
+                Err(e) => {
+                    warn!("Failed to accept connection: {}", e);
+                    continue;
+                }
+            };
+            stream.set_nonblocking(false)?;
+            let reader = stream.try_clone()?;
+            let connection = next_connection;
+            next_connection += 1;
+            connections.lock().insert(connection, stream.try_clone()?);
+
+            let daemon = self.clone();
+            let active = Arc::clone(&active);
+            let connections = Arc::clone(&connections);
+            active.fetch_add(1, Ordering::SeqCst);
+            thread::spawn(move || {
+                if let Err(e) = daemon.serve(BufReader::new(reader), stream) {
+                    warn!("Connection failed: {}", e);
+                }
+                connections.lock().remove(&connection);
+                active.fetch_sub(1, Ordering::SeqCst);
+            });
+        }
+
+        // Idle clients would keep their connection open forever, so end the reading side of
+        // every connection. They are then only open until their runs in flight are answered.
+        for stream in connections.lock().values() {
+            let _ = stream.shutdown(Shutdown::Read);
+        }
+        while active.load(Ordering::SeqCst) > 0 {
+            thread::sleep(ACCEPT_INTERVAL);
+        }
+        fs::remove_file(path)
+    }
+
+    fn cancel(&self, params: Value, running: &RunningRequests) -> Result<Value, RpcError> {
+        let params: CancelParams =
+            serde_json::from_value(params).map_err(|e| RpcError::new(INVALID_PARAMS, e))?;
+        let cancelled = match running.lock().get(&params.id.to_string()) {
+            Some(cancel) => {
+                cancel.store(true, Ordering::SeqCst);
+                true
+            }
+            None => false,
+        };
+        Ok(json!({ "cancelled": cancelled }))
+    }
+
+    fn start_run<W: Write + Send + 'static>(
+        &self,
+        id: Value,
+        params: Value,
+        notification: bool,
+        writer: &Arc<Mutex<W>>,
+        running: &RunningRequests,
+        runs: &Arc<RunCounter>,
+    ) -> Result<(), RpcError> {
+        let RunParams {
+            pipeline: name,
+            input,
+            format,
+            output_format,
+            variables,
+        } = serde_json::from_value(params).map_err(|e| RpcError::new(INVALID_PARAMS, e))?;
+        self.packages
+            .resolve(&name)
+            .map_err(|e| RpcError::new(INVALID_PARAMS, e))?;
+
+        let input_format = parse_format(&format)?.unwrap_or(match input {
+            Value::String(_) => DataFormat::Text,
+            _ => DataFormat::Json,
+        });
+        let output_format = parse_format(&output_format)?.unwrap_or(input_format);
+        let input = input_data(input, input_format)?;
+
+        // Notifications can't be cancelled, as there is no id to refer to them by
+        let cancel = Arc::new(AtomicBool::new(false));
+        let key = id.to_string();
+        if !notification {
+            match running.lock().entry(key.clone()) {
+                Entry::Occupied(_) => {
+                    return Err(RpcError::new(
+                        INVALID_REQUEST,
+                        format!("a run with id {} is already in flight", key),
+                    ))
+                }
+                Entry::Vacant(entry) => {
+                    entry.insert(Arc::clone(&cancel));
+                }
+            }
+        }
+
+        if self.active.fetch_add(1, Ordering::SeqCst) >= self.max_concurrent {
+            self.active.fetch_sub(1, Ordering::SeqCst);
+            if !notification {
+                running.lock().remove(&key);
+            }
+            warn!("Rejecting run of {}, too many runs", name);
+            return Err(RpcError::new(TOO_MANY_RUNS, "too many runs in flight"));
+        }
+        *runs.count.lock() += 1;
+        let active_run = ActiveRun {
+            active: Arc::clone(&self.active),
+            connection: Arc::clone(runs),
+        };
+
+        let packages = Arc::clone(&self.packages);
+        let writer = Arc::clone(writer);
+        let running = Arc::clone(running);
+        thread::spawn(move || {
+            // Released once the run is answered
+            let _active_run = active_run;
+
+            let (session, pipeline) = packages
+                .resolve(&name)
+                .expect("pipeline was resolved before");
+            let mut request = SessionRequest::new(input, input_format);
+            request.pipeline = pipeline;
+            request.output_format = output_format;
+            request.variables = variables;
+            request.cancel = Some(cancel);
+
+            let result =
+                match panic::catch_unwind(AssertUnwindSafe(|| session.run_blocking(request))) {
+                    Ok(Ok(output)) => output_value(output, output_format)
+                        .map(|output| json!({ "output": output })),
+                    Ok(Err(e)) => match e.downcast_ref::<PipelineError>() {
+                        Some(PipelineError::Cancelled) => Err(RpcError::new(REQUEST_CANCELLED, e)),
+                        _ => {
+                            error!("Running {} failed: {}", name, e);
+                            Err(RpcError::new(RUN_FAILED, e))
+                        }
+                    },
+                    Err(_) => {
+                        error!("Running {} panicked", name);
+                        Err(RpcError::new(RUN_FAILED, "running the pipeline panicked"))
+                    }
+                };
+
+            if notification {
+                return;
+            }
+            running.lock().remove(&key);
+            if let Err(e) = send(&writer, response(id, result)) {
+                warn!("Failed to write response: {}", e);
+            }
+        });
+        Ok(())
+    }
+}
+
+fn send<W: Write>(writer: &Mutex<W>, message: Value) -> io::Result<()> {
+    let message = serde_json::to_vec(&message)?;
+    write_message(&mut *writer.lock(), &message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn frame_messages() {
+        let mut data = Vec::new();
+        write_message(&mut data, b"{}").unwrap();
+        write_message(&mut data, b"[1,2]").unwrap();
+        assert_eq!(&data[..4], &[2, 0, 0, 0]);
+
+        let mut reader = &data[..];
+        assert_eq!(read_message(&mut reader).unwrap().unwrap(), b"{}");
+        assert_eq!(read_message(&mut reader).unwrap().unwrap(), b"[1,2]");
+        assert!(read_message(&mut reader).unwrap().is_none());
+
+        assert!(read_message(&mut &data[..3]).is_err());
+        assert!(read_message(&mut &[0xff, 0xff, 0xff, 0xff][..]).is_err());
+    }
+}
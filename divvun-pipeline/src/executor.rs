@@ -0,0 +1,41 @@
+use std::thread;
+
+/// A blocking module call, to be run to completion by a `PipelineExecutor`
+pub type BlockingTask = Box<dyn FnOnce() + Send + 'static>;
+
+/// Runs the module calls of a pipeline away from the thread polling the run, so the branches of
+/// parallel nodes run at the same time and modules don't block the async executor. Without one
+/// every module is called on the thread polling the run.
+///
+/// Every task has to be run or dropped eventually: a run that is dropped before it is done
+/// waits for the module calls it started.
+///
+/// Any function taking a `BlockingTask` is an executor, e.g. with tokio:
+///
+/// ```ignore
+/// let executor: Arc<dyn PipelineExecutor> = Arc::new(|task: BlockingTask| {
+///     tokio::task::spawn_blocking(task);
+/// });
+/// ```
+pub trait PipelineExecutor: Send + Sync {
+    fn spawn_blocking(&self, task: BlockingTask);
+}
+
+impl<F> PipelineExecutor for F
+where
+    F: Fn(BlockingTask) + Send + Sync,
+{
+    fn spawn_blocking(&self, task: BlockingTask) {
+        self(task)
+    }
+}
+
+/// Calls every module on a thread of its own
+#[derive(Debug, Default, Clone, Copy)]
+pub struct ThreadExecutor;
+
+impl PipelineExecutor for ThreadExecutor {
+    fn spawn_blocking(&self, task: BlockingTask) {
+        thread::spawn(task);
+    }
+}
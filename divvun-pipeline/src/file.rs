@@ -1,37 +1,373 @@
 use std::{
+    collections::BTreeMap,
+    error::Error,
+    fmt,
     fs::{self, File},
-    io::{self, BufReader},
+    io::{self, BufReader, Cursor, Read, Seek},
+    mem,
     path::{Path, PathBuf},
     sync::Arc,
 };
 use tempfile::{tempdir, TempDir};
 
 use log::{error, info, warn};
-use memmap::MmapOptions;
-use serde_json::Value;
-use zip::{CompressionMethod, ZipArchive};
+use zip::{result::ZipError, CompressionMethod, ZipArchive};
 
 use crate::{
-    pipeline::Pipeline,
-    resources::{LoadableResource, Resource, ResourceRegistry},
+    format::{parse_pipeline, PipelineFormat, PipelineParseError},
+    manifest::{Manifest, DEFAULT_PIPELINE_NAME, MANIFEST_FILE_NAME, MANIFEST_VERSION},
+    pipeline::{Pipeline, PipelineInclude, PipelineNodeSerial},
+    resources::{IntegrityError, LoadableResource, Resource, ResourceError, ResourceRegistry},
+    signature::{PipelineSignature, SignatureError, TrustedKeys, SIGNATURE_FILE_NAME},
 };
 
 pub static PIPELINE_EXTENSION: &'static str = "zpipe";
-static JSON_EXTENSION: &'static str = "json";
+/// Preferred pipeline file in archives without a manifest
+static LEGACY_PIPELINE_FILE_STEM: &'static str = "pipeline";
 
 #[derive(Debug)]
 pub enum FileLoadError {
-    NotExisting,
-    NotAFile,
-    InvalidExtension,
-    NoTempDir,
-    UnsupportedResource,
+    NotExisting(PathBuf),
+    NotAFile(PathBuf),
+    NotADirectory(PathBuf),
+    InvalidExtension(PathBuf),
+    Io {
+        path: PathBuf,
+        error: io::Error,
+    },
+    NoTempDir(io::Error),
+    InvalidArchive(ZipError),
+    InvalidEntry {
+        index: usize,
+        error: ZipError,
+    },
+    InvalidEntryName(String),
+    PathTraversal(String),
+    ReadEntry {
+        name: String,
+        error: io::Error,
+    },
+    ExtractEntry {
+        name: String,
+        error: io::Error,
+    },
+    TruncatedEntry(String),
     NoJsonFile,
+    AmbiguousPipeline(Vec<String>),
+    UnknownPipeline {
+        name: String,
+        available: Vec<String>,
+    },
+    DuplicatePipeline(String),
+    /// The resources of an included file couldn't be added, e.g. because of a name conflict
+    IncludedResources {
+        path: PathBuf,
+        error: ResourceError,
+    },
+    InvalidPipeline {
+        name: String,
+        error: PipelineParseError,
+    },
+    InvalidManifest(serde_json::Error),
+    UnsupportedManifestVersion {
+        found: u32,
+        supported: u32,
+    },
+    MissingModule {
+        name: String,
+        error: String,
+    },
+    ModuleVersionMismatch {
+        name: String,
+        required: String,
+        found: String,
+    },
+    MissingResource(String),
+    CorruptResource {
+        name: String,
+        error: IntegrityError,
+    },
+    UnknownInclude {
+        name: String,
+        included_from: String,
+    },
+    IncludeCycle(Vec<String>),
+    IncludedFile {
+        path: PathBuf,
+        error: Box<FileLoadError>,
+    },
+    Signature(SignatureError),
+    UnsignedEntry(String),
 }
 
-pub fn load_pipeline_file(
+impl fmt::Display for FileLoadError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FileLoadError::NotExisting(ref path) => write!(f, "{} does not exist", path.display()),
+            FileLoadError::NotAFile(ref path) => write!(f, "{} is not a file", path.display()),
+            FileLoadError::NotADirectory(ref path) => {
+                write!(f, "{} is not a directory", path.display())
+            }
+            FileLoadError::InvalidExtension(ref path) => write!(
+                f,
+                "{} does not have the .{} extension",
+                path.display(),
+                PIPELINE_EXTENSION
+            ),
+            FileLoadError::Io {
+                ref path,
+                ref error,
+            } => write!(f, "failed to read {}: {}", path.display(), error),
+            FileLoadError::NoTempDir(ref e) => {
+                write!(f, "failed to create temporary directory: {}", e)
+            }
+            FileLoadError::InvalidArchive(ref e) => write!(f, "invalid archive: {}", e),
+            FileLoadError::InvalidEntry { index, ref error } => {
+                write!(f, "invalid archive entry {}: {}", index, error)
+            }
+            FileLoadError::InvalidEntryName(ref name) => {
+                write!(f, "{:?} is not a valid entry name", name)
+            }
+            FileLoadError::PathTraversal(ref name) => {
+                write!(f, "entry {} points outside of the archive", name)
+            }
+            FileLoadError::ReadEntry {
+                ref name,
+                ref error,
+            } => write!(f, "failed to read entry {}: {}", name, error),
+            FileLoadError::ExtractEntry {
+                ref name,
+                ref error,
+            } => write!(f, "failed to extract entry {}: {}", name, error),
+            FileLoadError::TruncatedEntry(ref name) => {
+                write!(f, "entry {} extends past the end of the archive", name)
+            }
+            FileLoadError::NoJsonFile => write!(f, "no pipeline found in archive"),
+            FileLoadError::AmbiguousPipeline(ref names) => write!(
+                f,
+                "archive contains several pipeline files: {}",
+                names.join(", ")
+            ),
+            FileLoadError::UnknownPipeline {
+                ref name,
+                ref available,
+            } => write!(
+                f,
+                "no pipeline named {}, available: {}",
+                name,
+                available.join(", ")
+            ),
+            FileLoadError::DuplicatePipeline(ref name) => {
+                write!(f, "pipeline {} is defined more than once", name)
+            }
+            FileLoadError::IncludedResources {
+                ref path,
+                ref error,
+            } => write!(
+                f,
+                "failed to add the resources of {}: {}",
+                path.display(),
+                error
+            ),
+            FileLoadError::InvalidPipeline {
+                ref name,
+                ref error,
+            } => write!(f, "invalid pipeline {}: {}", name, error),
+            FileLoadError::InvalidManifest(ref e) => {
+                write!(f, "invalid {}: {}", MANIFEST_FILE_NAME, e)
+            }
+            FileLoadError::UnsupportedManifestVersion { found, supported } => write!(
+                f,
+                "manifest version {} is not supported, the latest supported version is {}",
+                found, supported
+            ),
+            FileLoadError::MissingModule {
+                ref name,
+                ref error,
+            } => write!(f, "required module {} failed to load: {}", name, error),
+            FileLoadError::ModuleVersionMismatch {
+                ref name,
+                ref required,
+                ref found,
+            } => write!(
+                f,
+                "module {} has version {} but at least {} is required",
+                name, found, required
+            ),
+            FileLoadError::MissingResource(ref name) => write!(f, "resource {} is missing", name),
+            FileLoadError::CorruptResource {
+                ref name,
+                ref error,
+            } => write!(f, "resource {} is corrupt: {}", name, error),
+            FileLoadError::UnknownInclude {
+                ref name,
+                ref included_from,
+            } => write!(
+                f,
+                "pipeline {} includes {}, which does not exist",
+                included_from, name
+            ),
+            FileLoadError::IncludeCycle(ref names) => {
+                write!(f, "pipelines include each other: {}", names.join(" -> "))
+            }
+            FileLoadError::IncludedFile {
+                ref path,
+                ref error,
+            } => write!(
+                f,
+                "failed to load included file {}: {}",
+                path.display(),
+                error
+            ),
+            FileLoadError::Signature(ref e) => write!(f, "invalid signature: {}", e),
+            FileLoadError::UnsignedEntry(ref name) => {
+                write!(f, "entry {} is not listed in the signed manifest", name)
+            }
+        }
+    }
+}
+
+impl Error for FileLoadError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            FileLoadError::Io { ref error, .. }
+            | FileLoadError::ReadEntry { ref error, .. }
+            | FileLoadError::ExtractEntry { ref error, .. } => Some(error),
+            FileLoadError::NoTempDir(ref e) => Some(e),
+            FileLoadError::InvalidArchive(ref e) => Some(e),
+            FileLoadError::InvalidEntry { ref error, .. } => Some(error),
+            FileLoadError::InvalidPipeline { ref error, .. } => Some(error),
+            FileLoadError::InvalidManifest(ref e) => Some(e),
+            FileLoadError::CorruptResource { ref error, .. } => Some(error),
+            FileLoadError::IncludedFile { ref error, .. } => Some(&**error),
+            FileLoadError::IncludedResources { ref error, .. } => Some(error),
+            FileLoadError::Signature(ref e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+/// The pipelines loaded from a .zpipe archive
+pub struct PipelineFile {
+    pub manifest: Manifest,
+    pub pipelines: BTreeMap<String, Pipeline>,
+    pub resources: Arc<ResourceRegistry>,
+    /// Directory holding entries that had to be extracted, removed when dropped
+    pub temp_dir: Option<TempDir>,
+    /// Pipeline files referenced by includes, kept open for their resources
+    pub included: Vec<PipelineFile>,
+}
+
+impl PipelineFile {
+    pub fn pipeline_names(&self) -> Vec<String> {
+        self.pipelines.keys().cloned().collect()
+    }
+
+    /// The named pipeline, or the default one if no name is given. Without a default pipeline
+    /// the archive has to contain exactly one.
+    pub fn pipeline(&self, name: Option<&str>) -> Result<&Pipeline, FileLoadError> {
+        if let Some(name) = name {
+            return self
+                .pipelines
+                .get(name)
+                .ok_or_else(|| FileLoadError::UnknownPipeline {
+                    name: name.to_string(),
+                    available: self.pipeline_names(),
+                });
+        }
+
+        if let Some(pipeline) = self.pipelines.get(DEFAULT_PIPELINE_NAME) {
+            return Ok(pipeline);
+        }
+
+        match self.pipelines.len() {
+            0 => Err(FileLoadError::NoJsonFile),
+            1 => Ok(self.pipelines.values().next().unwrap()),
+            _ => Err(FileLoadError::AmbiguousPipeline(self.pipeline_names())),
+        }
+    }
+}
+
+/// Load a pipeline from a .zpipe file or an unpacked directory
+pub fn load_pipeline(path: &Path) -> Result<PipelineFile, FileLoadError> {
+    load_pipeline_with_keys(path, &TrustedKeys::default())
+}
+
+/// Load a pipeline from a .zpipe file or an unpacked directory, which has to be signed by one of
+/// the trusted keys if it is signed or `keys` requires a signature
+pub fn load_pipeline_with_keys(
+    path: &Path,
+    keys: &TrustedKeys,
+) -> Result<PipelineFile, FileLoadError> {
+    if path.is_dir() {
+        load_pipeline_dir_with_keys(path, keys)
+    } else {
+        load_pipeline_file_with_keys(path, keys)
+    }
+}
+
+/// Load a pipeline from a .zpipe file. A signature in the file is checked, but without trusted
+/// keys any signing key is accepted.
+pub fn load_pipeline_file(pipeline_file: &Path) -> Result<PipelineFile, FileLoadError> {
+    load_pipeline_file_with_keys(pipeline_file, &TrustedKeys::default())
+}
+
+pub fn load_pipeline_file_with_keys(
     pipeline_file: &Path,
-) -> Result<(Pipeline, Arc<ResourceRegistry>, TempDir), FileLoadError> {
+    keys: &TrustedKeys,
+) -> Result<PipelineFile, FileLoadError> {
+    let file = open_pipeline_file(pipeline_file, keys)?;
+    resolve_includes(file, Some(pipeline_file), keys)
+}
+
+/// Load a pipeline from a zpipe archive held in memory. Stored entries are served straight from
+/// the buffer, compressed ones are decompressed into memory, so no files are touched. Files
+/// referenced by includes are looked up relative to the working directory.
+pub fn load_pipeline_bytes<B: Into<Arc<[u8]>>>(bytes: B) -> Result<PipelineFile, FileLoadError> {
+    load_pipeline_bytes_with_keys(bytes, &TrustedKeys::default())
+}
+
+pub fn load_pipeline_bytes_with_keys<B: Into<Arc<[u8]>>>(
+    bytes: B,
+    keys: &TrustedKeys,
+) -> Result<PipelineFile, FileLoadError> {
+    let bytes = bytes.into();
+    info!("Loading pipeline from {} bytes", bytes.len());
+
+    let archive =
+        ZipArchive::new(Cursor::new(Arc::clone(&bytes))).map_err(FileLoadError::InvalidArchive)?;
+
+    let file = load_archive(archive, ArchiveSource::Bytes(bytes), keys)?;
+    resolve_includes(file, None, keys)
+}
+
+/// Load a pipeline from an unpacked archive. Resources are mapped from the files in the
+/// directory and verified against the manifest, if there is one, on their first load.
+pub fn load_pipeline_dir(dir: &Path) -> Result<PipelineFile, FileLoadError> {
+    load_pipeline_dir_with_keys(dir, &TrustedKeys::default())
+}
+
+pub fn load_pipeline_dir_with_keys(
+    dir: &Path,
+    keys: &TrustedKeys,
+) -> Result<PipelineFile, FileLoadError> {
+    let file = open_pipeline_dir(dir, keys)?;
+    resolve_includes(file, Some(dir), keys)
+}
+
+/// Load a pipeline file or directory without resolving its includes
+fn open_pipeline(path: &Path, keys: &TrustedKeys) -> Result<PipelineFile, FileLoadError> {
+    if path.is_dir() {
+        open_pipeline_dir(path, keys)
+    } else {
+        open_pipeline_file(path, keys)
+    }
+}
+
+fn open_pipeline_file(
+    pipeline_file: &Path,
+    keys: &TrustedKeys,
+) -> Result<PipelineFile, FileLoadError> {
     info!("Supplied file path: {}", pipeline_file.display());
 
     if !pipeline_file.exists() {
@@ -39,7 +375,7 @@ pub fn load_pipeline_file(
             "The supplied file {} does not exist",
             pipeline_file.display()
         );
-        return Err(FileLoadError::NotExisting);
+        return Err(FileLoadError::NotExisting(pipeline_file.to_path_buf()));
     }
 
     if !pipeline_file.is_file() {
@@ -48,7 +384,7 @@ pub fn load_pipeline_file(
             PIPELINE_EXTENSION
         );
 
-        return Err(FileLoadError::NotAFile);
+        return Err(FileLoadError::NotAFile(pipeline_file.to_path_buf()));
     }
 
     if pipeline_file.extension().is_none()
@@ -59,112 +395,721 @@ pub fn load_pipeline_file(
             PIPELINE_EXTENSION
         );
 
-        return Err(FileLoadError::InvalidExtension);
+        return Err(FileLoadError::InvalidExtension(pipeline_file.to_path_buf()));
     }
 
-    // Temporary dir to extract archive to
-    let temp_target_dir = match tempdir() {
-        Ok(dir) => dir,
-        Err(_) => {
-            error!("Failed to create temporary directory");
-            return Err(FileLoadError::NoTempDir);
+    let io_error = |error| FileLoadError::Io {
+        path: pipeline_file.to_path_buf(),
+        error,
+    };
+    let zip_file = File::open(pipeline_file).map_err(io_error)?;
+    let len = zip_file.metadata().map_err(io_error)?.len();
+    let archive =
+        ZipArchive::new(BufReader::new(zip_file)).map_err(FileLoadError::InvalidArchive)?;
+
+    load_archive(
+        archive,
+        ArchiveSource::File {
+            path: pipeline_file,
+            len,
+        },
+        keys,
+    )
+}
+
+fn open_pipeline_dir(dir: &Path, keys: &TrustedKeys) -> Result<PipelineFile, FileLoadError> {
+    info!("Supplied directory: {}", dir.display());
+
+    if !dir.exists() {
+        error!("The supplied directory {} does not exist", dir.display());
+        return Err(FileLoadError::NotExisting(dir.to_path_buf()));
+    }
+
+    if !dir.is_dir() {
+        return Err(FileLoadError::NotADirectory(dir.to_path_buf()));
+    }
+
+    let read_file = |name: &str| -> Result<Option<Vec<u8>>, FileLoadError> {
+        let path = dir.join(name);
+        if !path.is_file() {
+            return Ok(None);
         }
+        fs::read(&path)
+            .map(Some)
+            .map_err(|error| FileLoadError::Io { path, error })
     };
 
+    let manifest_data = read_file(MANIFEST_FILE_NAME)?;
+    let signature = match read_file(SIGNATURE_FILE_NAME)? {
+        Some(data) => Some(PipelineSignature::from_slice(&data).map_err(FileLoadError::Signature)?),
+        None => None,
+    };
+    let signed = verify_signature(keys, manifest_data.as_ref(), signature.as_ref())?;
+    let manifest = read_manifest(manifest_data)?;
+
+    let has_manifest_pipelines = has_pipelines(&manifest);
     let resource_registry = Arc::new(ResourceRegistry::new());
+    let mut pipeline_files = Vec::new();
 
-    let zip_file = File::open(pipeline_file).unwrap();
-    let reader = BufReader::new(&zip_file);
-    let mut archive = ZipArchive::new(reader).expect("zip");
+    let mut files = Vec::new();
+    collect_dir_files(dir, "", &mut files)?;
+
+    for (name, path) in files {
+        if name == MANIFEST_FILE_NAME || name == SIGNATURE_FILE_NAME {
+            continue;
+        }
+        check_signed_entry(signed, &manifest, &name)?;
+
+        if !has_manifest_pipelines && !name.contains('/') && is_legacy_pipeline_file(&name) {
+            info!("Found {}, reading", path.display());
+            let text = fs::read_to_string(&path).map_err(|error| FileLoadError::Io {
+                path: path.clone(),
+                error,
+            })?;
+            verify_pipeline_text(&manifest, &name, &text)?;
+            pipeline_files.push((name, text));
+            continue;
+        }
+
+        let resource = Resource::new_file(&path);
+        let resource = match manifest.resource(&name).cloned() {
+            Some(metadata) => LoadableResource::with_metadata(resource, metadata),
+            None => {
+                if !manifest.resources.is_empty() {
+                    warn!("Resource {} is not listed in the manifest", name);
+                }
+                LoadableResource::from(resource)
+            }
+        };
+
+        resource_registry.add_resource(&name, resource);
+        info!("Found resource file {:?}, adding to registry", name);
+    }
+
+    check_resources(&manifest, &resource_registry, &pipeline_files)?;
+    let pipelines = create_pipelines(&manifest, pipeline_files)?;
+
+    Ok(PipelineFile {
+        manifest,
+        pipelines,
+        resources: resource_registry,
+        temp_dir: None,
+        included: Vec::new(),
+    })
+}
+
+/// Collect the files below `dir` recursively, named by their path relative to the root
+/// directory with `/` as separator. Hidden files and directories are skipped.
+fn collect_dir_files(
+    dir: &Path,
+    prefix: &str,
+    files: &mut Vec<(String, PathBuf)>,
+) -> Result<(), FileLoadError> {
+    let io_error = |error| FileLoadError::Io {
+        path: dir.to_path_buf(),
+        error,
+    };
+    let mut entries = fs::read_dir(dir)
+        .and_then(|entries| entries.collect::<Result<Vec<_>, _>>())
+        .map_err(io_error)?;
+    entries.sort_by_key(|entry| entry.file_name());
+
+    for entry in entries {
+        let path = entry.path();
+        let name = match entry.file_name().into_string() {
+            Ok(name) => name,
+            Err(name) => {
+                error!("Mangled filename: {:?}", name);
+                return Err(FileLoadError::InvalidEntryName(
+                    name.to_string_lossy().to_string(),
+                ));
+            }
+        };
+
+        if name.starts_with('.') {
+            continue;
+        }
+
+        let name = format!("{}{}", prefix, name);
+        let file_type = entry.file_type().map_err(io_error)?;
+        if file_type.is_dir() {
+            collect_dir_files(&path, &format!("{}/", name), files)?;
+        } else if path.is_file() {
+            files.push((name, path));
+        } else {
+            warn!("Ignoring {}, only files are supported", path.display());
+        }
+    }
+
+    Ok(())
+}
+
+/// Turn the path of an archive entry into a resource name: components are separated by `/`,
+/// empty and `.` components are dropped. Absolute paths and `..` components are rejected.
+pub fn resource_name(path: &str) -> Result<String, FileLoadError> {
+    let path = path.replace('\\', "/");
+    let is_absolute = path.starts_with('/')
+        || path
+            .split('/')
+            .next()
+            .map(|first| first.ends_with(':'))
+            .unwrap_or(false);
+
+    let mut components = Vec::new();
+    for component in path.split('/') {
+        match component {
+            "" | "." => continue,
+            ".." => {
+                error!("Entry {} points outside of the archive", path);
+                return Err(FileLoadError::PathTraversal(path.clone()));
+            }
+            component => components.push(component),
+        }
+    }
+
+    if is_absolute {
+        error!("Entry {} is an absolute path", path);
+        return Err(FileLoadError::PathTraversal(path.clone()));
+    }
+
+    if components.is_empty() {
+        return Err(FileLoadError::InvalidEntryName(path));
+    }
+
+    Ok(components.join("/"))
+}
+
+/// Where the stored entries of an archive are read from
+enum ArchiveSource<'a> {
+    File { path: &'a Path, len: u64 },
+    Bytes(Arc<[u8]>),
+}
+
+impl<'a> ArchiveSource<'a> {
+    fn len(&self) -> u64 {
+        match self {
+            ArchiveSource::File { len, .. } => *len,
+            ArchiveSource::Bytes(ref bytes) => bytes.len() as u64,
+        }
+    }
+}
+
+/// Parse the manifest, if there is one
+fn read_manifest(data: Option<Vec<u8>>) -> Result<Manifest, FileLoadError> {
+    let data = match data {
+        Some(data) => data,
+        None => {
+            warn!(
+                "No {} found, resources are not verified",
+                MANIFEST_FILE_NAME
+            );
+            return Ok(Manifest::default());
+        }
+    };
+
+    let manifest: Manifest = serde_json::from_slice(&data).map_err(|e| {
+        error!("Invalid {}: {}", MANIFEST_FILE_NAME, e);
+        FileLoadError::InvalidManifest(e)
+    })?;
+
+    if manifest.version > MANIFEST_VERSION {
+        error!(
+            "Manifest version {} is newer than the supported version {}",
+            manifest.version, MANIFEST_VERSION
+        );
+        return Err(FileLoadError::UnsupportedManifestVersion {
+            found: manifest.version,
+            supported: MANIFEST_VERSION,
+        });
+    }
+
+    Ok(manifest)
+}
+
+/// Check the signature of the manifest against the trusted keys, returning whether it is signed
+fn verify_signature(
+    keys: &TrustedKeys,
+    manifest_data: Option<&Vec<u8>>,
+    signature: Option<&PipelineSignature>,
+) -> Result<bool, FileLoadError> {
+    let manifest_data = manifest_data.map(|data| &data[..]).unwrap_or(&[]);
+    keys.verify(manifest_data, signature)
+        .map_err(FileLoadError::Signature)
+}
+
+/// Everything in a signed pipeline file has to be covered by the hashes in the manifest
+fn check_signed_entry(signed: bool, manifest: &Manifest, name: &str) -> Result<(), FileLoadError> {
+    if signed && manifest.resource(name).is_none() {
+        error!("Entry {} is not listed in the signed manifest", name);
+        return Err(FileLoadError::UnsignedEntry(name.to_string()));
+    }
+    Ok(())
+}
+
+/// Pipeline files are parsed rather than registered as resources, so a listed one is checked
+/// against its hash right away. In a signed file `check_signed_entry` ensures it is listed.
+fn verify_pipeline_text(manifest: &Manifest, name: &str, text: &str) -> Result<(), FileLoadError> {
+    if let Some(metadata) = manifest.resource(name) {
+        if let Err(e) = metadata.verify(text.as_bytes()) {
+            error!("Pipeline file {} is corrupt: {}", name, e);
+            return Err(FileLoadError::CorruptResource {
+                name: name.to_string(),
+                error: e,
+            });
+        }
+    }
+    Ok(())
+}
+
+/// Read an entry of the archive, if it exists
+fn read_entry<R: Read + Seek>(
+    archive: &mut ZipArchive<R>,
+    name: &str,
+) -> Result<Option<Vec<u8>>, FileLoadError> {
+    let mut file = match archive.by_name(name) {
+        Ok(file) => file,
+        Err(ZipError::FileNotFound) => return Ok(None),
+        Err(e) => {
+            error!("Failed to read {}: {}", name, e);
+            return Err(FileLoadError::InvalidArchive(e));
+        }
+    };
+
+    let mut data = Vec::new();
+    file.read_to_end(&mut data)
+        .map_err(|error| FileLoadError::ReadEntry {
+            name: name.to_string(),
+            error,
+        })?;
+    Ok(Some(data))
+}
+
+fn has_pipelines(manifest: &Manifest) -> bool {
+    manifest.pipeline.is_some() || !manifest.pipelines.is_empty()
+}
+
+fn load_archive<R: Read + Seek>(
+    mut archive: ZipArchive<R>,
+    source: ArchiveSource,
+    keys: &TrustedKeys,
+) -> Result<PipelineFile, FileLoadError> {
+    // Temporary dir to extract compressed entries of archive files to, created on first use
+    let mut temp_target_dir: Option<TempDir> = None;
+
+    let resource_registry = Arc::new(ResourceRegistry::new());
 
     info!("File count: {}", archive.len());
 
-    let mut json_file_path = None;
+    let manifest_data = read_entry(&mut archive, MANIFEST_FILE_NAME)?;
+    let signature = match read_entry(&mut archive, SIGNATURE_FILE_NAME)? {
+        Some(data) => Some(PipelineSignature::from_slice(&data).map_err(FileLoadError::Signature)?),
+        None => None,
+    };
+    let signed = verify_signature(keys, manifest_data.as_ref(), signature.as_ref())?;
+    let manifest = read_manifest(manifest_data)?;
+
+    let has_manifest_pipelines = has_pipelines(&manifest);
+    let mut pipeline_files = Vec::new();
 
     for i in 0..archive.len() {
-        let mut file = archive.by_index(i).unwrap();
-        let filename = file.sanitized_name();
-        info!("File {}: {:?}", i, filename);
-        let ext = filename.extension();
+        let mut file = archive
+            .by_index(i)
+            .map_err(|error| FileLoadError::InvalidEntry { index: i, error })?;
+        info!("File {}: {:?}", i, file.name());
+
+        if file.is_dir() {
+            info!("Skipping directory entry {}", file.name());
+            continue;
+        }
 
-        if (&*file.name()).ends_with('/') {
-            error!("Unexpected directory in zip file {:?} ignoring", filename);
-            return Err(FileLoadError::UnsupportedResource);
+        let name = resource_name(file.name())?;
+        if name == MANIFEST_FILE_NAME || name == SIGNATURE_FILE_NAME {
+            continue;
         }
+        check_signed_entry(signed, &manifest, &name)?;
 
-        if ext.is_some() && ext.unwrap() == JSON_EXTENSION {
-            info!("Found {:?}, extracting", filename);
+        let filename = PathBuf::from(&name);
 
-            let json_file_dest = temp_target_dir.path().join(filename.clone());
-            let mut outfile = fs::File::create(&json_file_dest).unwrap();
-            io::copy(&mut file, &mut outfile).unwrap();
+        if !has_manifest_pipelines && !name.contains('/') && is_legacy_pipeline_file(&name) {
+            info!("Found {:?}, reading", filename);
 
-            json_file_path = Some(json_file_dest);
+            let mut text = String::new();
+            file.read_to_string(&mut text)
+                .map_err(|error| FileLoadError::ReadEntry {
+                    name: name.clone(),
+                    error,
+                })?;
+            verify_pipeline_text(&manifest, &name, &text)?;
+
+            pipeline_files.push((name, text));
         } else {
+            let metadata = manifest.resource(&name).cloned();
+            if metadata.is_none() && !manifest.resources.is_empty() {
+                warn!("Resource {} is not listed in the manifest", name);
+            }
+
             let resource = if file.compression() != CompressionMethod::Stored {
-                // Extract the resource first
-                let full_file_path = temp_target_dir.path().join(filename.to_owned());
-                warn!(
-                    "File {} is not stored, extracing to {}",
-                    filename.display(),
-                    full_file_path.display()
-                );
+                let mut data = Vec::new();
+                file.read_to_end(&mut data)
+                    .map_err(|error| FileLoadError::ReadEntry {
+                        name: name.clone(),
+                        error,
+                    })?;
 
-                let mut outfile = fs::File::create(&full_file_path).unwrap();
-                io::copy(&mut file, &mut outfile).unwrap();
+                // Extracted entries are verified right away, there's no mapping to defer to
+                if let Some(ref metadata) = metadata {
+                    if let Err(e) = metadata.verify(&data) {
+                        error!("Resource {} is corrupt: {}", name, e);
+                        return Err(FileLoadError::CorruptResource { name, error: e });
+                    }
+                }
 
-                LoadableResource::from(Resource::new_file(&full_file_path))
-            } else {
-                // Load resource directly mapped from the zip
-                let mmap = unsafe {
-                    MmapOptions::new()
-                        .offset(file.data_start())
-                        .len(file.size() as usize)
-                        .map(&zip_file)
-                        .expect("mmap failed")
-                };
-                LoadableResource::from(Resource::Mmap(mmap))
-            };
+                match source {
+                    ArchiveSource::File { .. } => {
+                        let temp_dir = match temp_target_dir {
+                            Some(ref temp_dir) => temp_dir,
+                            None => temp_target_dir.get_or_insert(tempdir().map_err(|e| {
+                                error!("Failed to create temporary directory: {}", e);
+                                FileLoadError::NoTempDir(e)
+                            })?),
+                        };
+
+                        // Extract the resource first
+                        let full_file_path = temp_dir.path().join(&filename);
+                        warn!(
+                            "File {} is not stored, extracing to {}",
+                            filename.display(),
+                            full_file_path.display()
+                        );
+
+                        let extract_error = |error| FileLoadError::ExtractEntry {
+                            name: name.clone(),
+                            error,
+                        };
+                        if let Some(parent) = full_file_path.parent() {
+                            fs::create_dir_all(parent).map_err(extract_error)?;
+                        }
+                        fs::write(&full_file_path, &data).map_err(extract_error)?;
 
-            match filename.to_str() {
-                Some(filename) => {
-                    resource_registry.add_resource(filename, resource);
-                    info!("Found resource file {:?}, adding to registry", filename);
+                        LoadableResource::from(Resource::new_file(&full_file_path))
+                    }
+                    ArchiveSource::Bytes(_) => {
+                        warn!("File {} is not stored, decompressing", filename.display());
+                        LoadableResource::from(Resource::Bytes(data))
+                    }
                 }
-                None => {
-                    error!("Mangled filename: {:?}", filename);
-                    return Err(FileLoadError::UnsupportedResource);
+            } else {
+                // Mapping past the end of a truncated archive would crash on access
+                let end = file.data_start().checked_add(file.size());
+                if end.map(|end| end > source.len()).unwrap_or(true) {
+                    error!("Entry {} extends past the end of the archive", name);
+                    return Err(FileLoadError::TruncatedEntry(name));
+                }
+
+                // Load resource directly from the archive, verified on the first load
+                let resource = match source {
+                    ArchiveSource::File { path, .. } => {
+                        Resource::new_file_range(path, file.data_start(), file.size() as usize)
+                    }
+                    ArchiveSource::Bytes(ref bytes) => Resource::new_shared_range(
+                        Arc::clone(bytes),
+                        file.data_start() as usize,
+                        file.size() as usize,
+                    ),
+                };
+                match metadata {
+                    Some(metadata) => LoadableResource::with_metadata(resource, metadata),
+                    None => LoadableResource::from(resource),
                 }
             };
+
+            resource_registry.add_resource(&name, resource);
+            info!("Found resource file {:?}, adding to registry", name);
+        }
+    }
+
+    check_resources(&manifest, &resource_registry, &pipeline_files)?;
+    let pipelines = create_pipelines(&manifest, pipeline_files)?;
+
+    Ok(PipelineFile {
+        manifest,
+        pipelines,
+        resources: resource_registry,
+        temp_dir: temp_target_dir,
+        included: Vec::new(),
+    })
+}
+
+/// Every resource listed in the manifest has to be present, as a resource or a pipeline file
+fn check_resources(
+    manifest: &Manifest,
+    registry: &ResourceRegistry,
+    pipeline_files: &[(String, String)],
+) -> Result<(), FileLoadError> {
+    for metadata in &manifest.resources {
+        if !registry.contains(&metadata.name)
+            && !pipeline_files
+                .iter()
+                .any(|(name, _)| *name == metadata.name)
+        {
+            error!(
+                "Resource {} listed in the manifest is missing",
+                metadata.name
+            );
+            return Err(FileLoadError::MissingResource(metadata.name.clone()));
         }
     }
 
-    let json_file_path = if json_file_path.is_none() {
-        error!("No .json file found");
-        return Err(FileLoadError::NoJsonFile);
+    Ok(())
+}
+
+fn create_pipelines(
+    manifest: &Manifest,
+    pipeline_files: Vec<(String, String)>,
+) -> Result<BTreeMap<String, Pipeline>, FileLoadError> {
+    let mut pipelines = BTreeMap::new();
+    if has_pipelines(manifest) {
+        if let Some(ref root) = manifest.pipeline {
+            pipelines.insert(
+                DEFAULT_PIPELINE_NAME.to_string(),
+                Pipeline { root: root.clone() },
+            );
+        }
+        for (name, root) in &manifest.pipelines {
+            if pipelines.contains_key(name) {
+                error!("Pipeline {} is defined more than once", name);
+                return Err(FileLoadError::DuplicatePipeline(name.clone()));
+            }
+            pipelines.insert(name.clone(), Pipeline { root: root.clone() });
+        }
     } else {
-        json_file_path.unwrap()
-    };
+        let (name, text) = legacy_pipeline_file(pipeline_files)?;
+        pipelines.insert(
+            DEFAULT_PIPELINE_NAME.to_string(),
+            create_pipeline(name, text)?,
+        );
+    }
+    info!("Pipelines: {:?}", pipelines.keys().collect::<Vec<_>>());
 
-    Ok((
-        create_pipeline(&json_file_path),
-        resource_registry,
-        temp_target_dir,
-    ))
+    Ok(pipelines)
 }
 
-fn create_pipeline(json_file: &Path) -> Pipeline {
-    info!("json_file_path: {:?}", json_file);
-    let file = File::open(json_file).unwrap();
-    let reader = BufReader::new(&file);
+/// Whether a top-level entry can hold the pipeline of an archive without pipelines in its
+/// manifest: any .json file as before, other formats only as pipeline.yaml, .yml or .toml so
+/// that e.g. a settings.yaml next to the pipeline stays a resource
+fn is_legacy_pipeline_file(name: &str) -> bool {
+    let path = Path::new(name);
+    match PipelineFormat::from_path(path) {
+        Some(PipelineFormat::Json) => true,
+        Some(_) => {
+            path.file_stem().and_then(|stem| stem.to_str()) == Some(LEGACY_PIPELINE_FILE_STEM)
+        }
+        None => false,
+    }
+}
+
+/// Archives without a pipeline in their manifest contain the pipeline as a separate .json,
+/// .yaml or .toml file. If there are several, the one called pipeline.* is used.
+fn legacy_pipeline_file(
+    mut pipeline_files: Vec<(String, String)>,
+) -> Result<(String, String), FileLoadError> {
+    if pipeline_files.len() > 1 {
+        let preferred = pipeline_files
+            .iter()
+            .enumerate()
+            .filter(|(_, (name, _))| {
+                Path::new(name).file_stem().and_then(|stem| stem.to_str())
+                    == Some(LEGACY_PIPELINE_FILE_STEM)
+            })
+            .map(|(index, _)| index)
+            .collect::<Vec<_>>();
 
-    let value: Value = serde_json::from_reader(reader).unwrap();
+        if preferred.len() == 1 {
+            return Ok(pipeline_files.swap_remove(preferred[0]));
+        }
 
-    let json_str = serde_json::to_string(&value).unwrap();
+        let names = pipeline_files.into_iter().map(|(name, _)| name).collect();
+        error!("Multiple pipeline files found: {:?}", names);
+        return Err(FileLoadError::AmbiguousPipeline(names));
+    }
 
-    let pipeline: Pipeline = Pipeline {
-        root: serde_json::from_str(&json_str).unwrap(),
+    match pipeline_files.pop() {
+        Some((name, text)) => {
+            info!("Using {} as pipeline", name);
+            Ok((name, text))
+        }
+        None => {
+            error!("No pipeline file found");
+            Err(FileLoadError::NoJsonFile)
+        }
+    }
+}
+
+fn create_pipeline(name: String, text: String) -> Result<Pipeline, FileLoadError> {
+    let format = PipelineFormat::from_path(Path::new(&name)).unwrap_or(PipelineFormat::Json);
+    let root = parse_pipeline(&text, format).map_err(|error| {
+        error!("Invalid pipeline {}: {}", name, error);
+        FileLoadError::InvalidPipeline { name, error }
+    })?;
+
+    Ok(Pipeline { root })
+}
+
+/// A pipeline file whose pipelines can be included
+struct IncludeScope {
+    /// Canonical path of the file, not known for archives loaded from memory
+    path: Option<PathBuf>,
+    pipelines: BTreeMap<String, Pipeline>,
+}
+
+impl IncludeScope {
+    fn label(&self, name: &str) -> String {
+        match self.path {
+            Some(ref path) => format!("{}:{}", path.display(), name),
+            None => name.to_string(),
+        }
+    }
+}
+
+/// Replaces include nodes by the pipelines they refer to. The first scope is the file being
+/// loaded, further scopes are opened as they are referenced.
+struct IncludeResolver<'a> {
+    keys: &'a TrustedKeys,
+    scopes: Vec<IncludeScope>,
+    files: Vec<PipelineFile>,
+    /// Pipelines currently being resolved, as scope index and name
+    stack: Vec<(usize, String)>,
+}
+
+impl<'a> IncludeResolver<'a> {
+    fn resolve_pipeline(
+        &mut self,
+        scope: usize,
+        name: &str,
+    ) -> Result<PipelineNodeSerial, FileLoadError> {
+        if let Some(start) = self
+            .stack
+            .iter()
+            .position(|(s, n)| *s == scope && n == name)
+        {
+            let cycle = self.stack[start..]
+                .iter()
+                .map(|(s, n)| self.scopes[*s].label(n))
+                .chain(std::iter::once(self.scopes[scope].label(name)))
+                .collect::<Vec<_>>();
+            error!("Pipelines include each other: {:?}", cycle);
+            return Err(FileLoadError::IncludeCycle(cycle));
+        }
+
+        let root = match self.scopes[scope].pipelines.get(name) {
+            Some(pipeline) => pipeline.root.clone(),
+            None => {
+                let included_from = match self.stack.last() {
+                    Some((s, n)) => self.scopes[*s].label(n),
+                    None => String::new(),
+                };
+                error!(
+                    "Pipeline {} includes unknown pipeline {}",
+                    included_from, name
+                );
+                return Err(FileLoadError::UnknownInclude {
+                    name: self.scopes[scope].label(name),
+                    included_from,
+                });
+            }
+        };
+
+        self.stack.push((scope, name.to_string()));
+        let resolved = root.resolve_includes(&mut |include| self.resolve_include(scope, include));
+        self.stack.pop();
+        resolved
+    }
+
+    fn resolve_include(
+        &mut self,
+        scope: usize,
+        include: &PipelineInclude,
+    ) -> Result<PipelineNodeSerial, FileLoadError> {
+        let target = match include.file {
+            Some(ref file) => self.open_scope(scope, file)?,
+            None => scope,
+        };
+
+        let mut root = self.resolve_pipeline(target, &include.pipeline)?;
+        root.substitute(&include.parameters);
+        Ok(root)
+    }
+
+    /// The scope of `file`, relative to the file of `scope`, opening it if it isn't yet
+    fn open_scope(&mut self, scope: usize, file: &str) -> Result<usize, FileLoadError> {
+        let path = match self.scopes[scope]
+            .path
+            .as_ref()
+            .and_then(|path| path.parent())
+        {
+            Some(dir) => dir.join(file),
+            None => PathBuf::from(file),
+        };
+        let included_file_error = |error| FileLoadError::IncludedFile {
+            path: path.clone(),
+            error: Box::new(error),
+        };
+
+        let path = fs::canonicalize(&path)
+            .map_err(|_| included_file_error(FileLoadError::NotExisting(path.clone())))?;
+        if let Some(index) = self
+            .scopes
+            .iter()
+            .position(|scope| scope.path.as_ref() == Some(&path))
+        {
+            return Ok(index);
+        }
+
+        info!("Opening included file {}", path.display());
+        let mut file = open_pipeline(&path, self.keys).map_err(included_file_error)?;
+        self.scopes.push(IncludeScope {
+            path: Some(path),
+            pipelines: mem::replace(&mut file.pipelines, BTreeMap::new()),
+        });
+        self.files.push(file);
+        Ok(self.scopes.len() - 1)
+    }
+}
+
+/// Resolve the includes of all pipelines of `file`, which was loaded from `path`. Resources of
+/// included files are added to the registry of `file` and the modules they require to its
+/// manifest.
+fn resolve_includes(
+    mut file: PipelineFile,
+    path: Option<&Path>,
+    keys: &TrustedKeys,
+) -> Result<PipelineFile, FileLoadError> {
+    let mut resolver = IncludeResolver {
+        keys,
+        scopes: vec![IncludeScope {
+            path: path.and_then(|path| fs::canonicalize(path).ok()),
+            pipelines: file.pipelines.clone(),
+        }],
+        files: Vec::new(),
+        stack: Vec::new(),
     };
 
-    pipeline
+    for (name, pipeline) in file.pipelines.iter_mut() {
+        pipeline.root = resolver.resolve_pipeline(0, name)?;
+    }
+
+    for (included, scope) in resolver.files.iter().zip(&resolver.scopes[1..]) {
+        file.resources
+            .add_resources_from(&included.resources)
+            .map_err(|error| FileLoadError::IncludedResources {
+                path: scope.path.clone().unwrap_or_default(),
+                error,
+            })?;
+        for requirement in &included.manifest.modules {
+            file.manifest.require_module(requirement.clone());
+        }
+    }
+    file.included = resolver.files;
+
+    Ok(file)
 }
@@ -0,0 +1,75 @@
+use std::{error::Error, fmt, path::Path};
+
+use schemars::{schema::RootSchema, schema_for};
+
+use crate::pipeline::{PipelineNode, PipelineNodeSerial};
+
+/// The formats pipeline definitions can be written in
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum PipelineFormat {
+    Json,
+    Yaml,
+    Toml,
+}
+
+impl PipelineFormat {
+    pub fn from_extension(extension: &str) -> Option<PipelineFormat> {
+        match extension {
+            "json" => Some(PipelineFormat::Json),
+            "yaml" | "yml" => Some(PipelineFormat::Yaml),
+            "toml" => Some(PipelineFormat::Toml),
+            _ => None,
+        }
+    }
+
+    pub fn from_path(path: &Path) -> Option<PipelineFormat> {
+        path.extension()
+            .and_then(|extension| extension.to_str())
+            .and_then(PipelineFormat::from_extension)
+    }
+}
+
+#[derive(Debug)]
+pub enum PipelineParseError {
+    Json(serde_json::Error),
+    Yaml(serde_yaml::Error),
+    Toml(toml::de::Error),
+}
+
+impl fmt::Display for PipelineParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PipelineParseError::Json(ref e) => write!(f, "{}", e),
+            PipelineParseError::Yaml(ref e) => write!(f, "{}", e),
+            PipelineParseError::Toml(ref e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl Error for PipelineParseError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            PipelineParseError::Json(ref e) => Some(e),
+            PipelineParseError::Yaml(ref e) => Some(e),
+            PipelineParseError::Toml(ref e) => Some(e),
+        }
+    }
+}
+
+/// Parse a pipeline definition, in either the tagged or the untagged form. TOML documents have
+/// to be a table, so only the tagged form can be written in TOML.
+pub fn parse_pipeline(
+    text: &str,
+    format: PipelineFormat,
+) -> Result<PipelineNodeSerial, PipelineParseError> {
+    match format {
+        PipelineFormat::Json => serde_json::from_str(text).map_err(PipelineParseError::Json),
+        PipelineFormat::Yaml => serde_yaml::from_str(text).map_err(PipelineParseError::Yaml),
+        PipelineFormat::Toml => toml::from_str(text).map_err(PipelineParseError::Toml),
+    }
+}
+
+/// JSON Schema of the tagged pipeline definition, for validating pipeline files in editors
+pub fn pipeline_schema() -> RootSchema {
+    schema_for!(PipelineNode)
+}
@@ -1,10 +1,23 @@
 #![feature(async_await)]
 
+pub mod capi;
+pub mod convert;
+pub mod daemon;
+pub mod executor;
 pub mod file;
+pub mod format;
+pub(crate) mod hex;
+pub mod manifest;
 pub mod module;
+pub mod pack;
 pub mod pipeline;
+pub mod repl;
 pub mod resources;
 pub mod run;
+pub mod serve;
+pub mod session;
+pub mod signature;
+pub mod trace;
 
 #[macro_use]
 extern crate derive_builder;
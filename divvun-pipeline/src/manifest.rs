@@ -0,0 +1,147 @@
+use std::{cmp::Ordering, collections::BTreeMap};
+
+use log::{error, info};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    file::FileLoadError, module::ModuleRegistry, pipeline::PipelineNodeSerial,
+    resources::ResourceMetadata,
+};
+
+pub static MANIFEST_FILE_NAME: &'static str = "manifest.json";
+
+/// The latest manifest format version understood by this crate. Manifests without a version
+/// are treated as version 0, which only lists resources.
+pub const MANIFEST_VERSION: u32 = 1;
+
+/// The name the manifest's `pipeline` or a legacy pipeline .json file is available under
+pub static DEFAULT_PIPELINE_NAME: &'static str = "default";
+
+/// A module a pipeline depends on
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ModuleRequirement {
+    pub name: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub min_version: Option<String>,
+}
+
+/// Describes the contents of a pipeline archive
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct Manifest {
+    #[serde(default)]
+    pub version: u32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub language: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub modules: Vec<ModuleRequirement>,
+    /// The default pipeline
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pipeline: Option<PipelineNodeSerial>,
+    /// Further pipelines by name, sharing the archive's resources
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub pipelines: BTreeMap<String, PipelineNodeSerial>,
+    #[serde(default)]
+    pub resources: Vec<ResourceMetadata>,
+}
+
+impl Manifest {
+    pub fn resource(&self, name: &str) -> Option<&ResourceMetadata> {
+        self.resources.iter().find(|resource| resource.name == name)
+    }
+
+    /// Add a module requirement, a module that is already required keeps the higher minimum
+    /// version of both
+    pub fn require_module(&mut self, requirement: ModuleRequirement) {
+        let existing = match self
+            .modules
+            .iter_mut()
+            .find(|module| module.name == requirement.name)
+        {
+            Some(existing) => existing,
+            None => return self.modules.push(requirement),
+        };
+
+        let higher = match (&existing.min_version, &requirement.min_version) {
+            (Some(a), Some(b)) => compare_versions(b, a) == Ordering::Greater,
+            (None, Some(_)) => true,
+            _ => false,
+        };
+        if higher {
+            existing.min_version = requirement.min_version;
+        }
+    }
+}
+
+/// Load every required module and check it is recent enough
+pub fn check_module_requirements(
+    registry: &ModuleRegistry,
+    requirements: &[ModuleRequirement],
+) -> Result<(), FileLoadError> {
+    for requirement in requirements {
+        let module =
+            registry
+                .get_module(&requirement.name)
+                .map_err(|e| FileLoadError::MissingModule {
+                    name: requirement.name.clone(),
+                    error: e.to_string(),
+                })?;
+
+        let min_version = match requirement.min_version {
+            Some(ref min_version) => min_version,
+            None => continue,
+        };
+
+        let version = module.version().unwrap_or_default();
+        if compare_versions(&version, min_version) == Ordering::Less {
+            error!(
+                "Module {} has version {}, {} is required",
+                requirement.name, version, min_version
+            );
+            return Err(FileLoadError::ModuleVersionMismatch {
+                name: requirement.name.clone(),
+                required: min_version.clone(),
+                found: version,
+            });
+        }
+
+        info!(
+            "Module {} {} satisfies {}",
+            requirement.name, version, min_version
+        );
+    }
+
+    Ok(())
+}
+
+/// Compare dotted version numbers component by component, missing components count as 0
+fn compare_versions(a: &str, b: &str) -> Ordering {
+    let parse = |version: &str| {
+        version
+            .split('.')
+            .map(|part| part.trim().parse::<u64>().unwrap_or(0))
+            .collect::<Vec<_>>()
+    };
+
+    let (mut a, mut b) = (parse(a), parse(b));
+    let len = a.len().max(b.len());
+    a.resize(len, 0);
+    b.resize(len, 0);
+    a.cmp(&b)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn versions_compared_numerically() {
+        assert_eq!(compare_versions("0.0.2", "0.0.1"), Ordering::Greater);
+        assert_eq!(compare_versions("0.10.0", "0.9"), Ordering::Greater);
+        assert_eq!(compare_versions("1.0", "1.0.0"), Ordering::Equal);
+        assert_eq!(compare_versions("", "0.0.1"), Ordering::Less);
+    }
+}
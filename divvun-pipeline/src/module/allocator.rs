@@ -44,4 +44,17 @@ impl ModuleAllocator {
         self.mmaps.write().push(mmap);
         Ok(ptr)
     }
+
+    /// Unmap the allocation starting at `ptr`, which must not be used afterwards. Returns false
+    /// if it wasn't handed out by this allocator.
+    pub fn free(&self, ptr: *const u8) -> bool {
+        let mut mmaps = self.mmaps.write();
+        match mmaps.iter().position(|mmap| mmap.as_ptr() == ptr) {
+            Some(index) => {
+                mmaps.swap_remove(index);
+                true
+            }
+            None => false,
+        }
+    }
 }
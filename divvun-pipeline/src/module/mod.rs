@@ -1,7 +1,9 @@
 mod allocator;
 mod module;
 mod registry;
+mod search_path;
 
 pub use allocator::*;
 pub use module::*;
 pub use registry::*;
+pub use search_path::*;
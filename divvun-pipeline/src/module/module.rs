@@ -1,7 +1,10 @@
 use capnp::message::TypedReader;
 use divvun_schema::{
     error_capnp::pipeline_error,
-    interface::{ModuleInterface, ModuleRunParameters},
+    interface::{
+        LoadResourceStatus, ModuleInterface, ModuleRunParameters, ObjectDestructorFn,
+        ResourceHandleId,
+    },
 };
 use std::{ffi::CStr, fmt};
 
@@ -13,11 +16,14 @@ use std::{
     ffi::CString,
     os::raw::{c_char, c_void},
     path::Path,
-    sync::Arc,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
 };
 
 use super::ModuleAllocator;
-use crate::resources::{ResourceHandle, ResourceRegistry};
+use crate::resources::{ObjectOwner, ResourceError, ResourceHandle, ResourceRegistry};
 
 type ModuleRunFn = fn(*const ModuleRunParameters) -> bool;
 
@@ -27,7 +33,8 @@ type ModuleInfoFn = fn(*mut *const u8, *mut usize) -> bool;
 struct ModuleInterfaceData {
     pub allocator: Arc<ModuleAllocator>,
     pub resource_registry: Arc<ResourceRegistry>,
-    resource_handles: Mutex<HashMap<String, Arc<ResourceHandle>>>,
+    next_handle_id: AtomicU64,
+    resource_handles: Mutex<HashMap<ResourceHandleId, ResourceHandle>>,
 }
 
 impl ModuleInterfaceData {
@@ -38,25 +45,63 @@ impl ModuleInterfaceData {
         ModuleInterfaceData {
             allocator,
             resource_registry,
+            next_handle_id: AtomicU64::new(1),
             resource_handles: Mutex::new(HashMap::new()),
         }
     }
 
-    pub fn load_resource(&self, name: &str) -> Option<Arc<ResourceHandle>> {
-        if let Some(handle) = self.resource_registry.get(name) {
-            let handle = Arc::new(handle);
-            // Keep track of the handle
-            self.resource_handles
-                .lock()
-                .insert(name.to_string(), handle.clone());
-            return Some(handle);
-        }
+    /// Claim the named resource, returning the id of the claim along with its data.
+    /// Every call creates a separate claim, so the same resource can be held multiple times.
+    pub fn load_resource(
+        &self,
+        name: &str,
+    ) -> Result<(ResourceHandleId, *const u8, usize), ResourceError> {
+        let handle = self.resource_registry.get(name)?;
+        let (ptr, size) = match (handle.as_ptr(), handle.size()) {
+            (Some(ptr), Some(size)) => (ptr, size),
+            _ => {
+                return Err(ResourceError::LoadFailed {
+                    name: name.to_string(),
+                    source: "resource has no data".into(),
+                })
+            }
+        };
+
+        // Keep track of the handle until the module releases it
+        let id = self.next_handle_id.fetch_add(1, Ordering::SeqCst);
+        self.resource_handles.lock().insert(id, handle);
+        Ok((id, ptr, size))
+    }
 
-        None
+    pub fn release_resource(&self, id: ResourceHandleId) -> bool {
+        self.resource_handles.lock().remove(&id).is_some()
     }
 
-    pub fn release_resource(&self, name: &str) -> bool {
-        return self.resource_handles.lock().remove(name).is_some();
+    pub fn resource_object(&self, id: ResourceHandleId, key: &str) -> Option<*mut c_void> {
+        self.resource_handles.lock().get(&id)?.object(key)
+    }
+
+    /// Objects this module attaches to resources are recorded under the address of its data
+    fn owner(&self) -> ObjectOwner {
+        self as *const ModuleInterfaceData as ObjectOwner
+    }
+
+    pub fn attach_resource_object(
+        &self,
+        id: ResourceHandleId,
+        key: &str,
+        object: *mut c_void,
+        destructor: ObjectDestructorFn,
+    ) -> Option<*mut c_void> {
+        let handles = self.resource_handles.lock();
+        match handles.get(&id) {
+            Some(handle) => Some(handle.attach_object(self.owner(), key, object, destructor)),
+            None => {
+                drop(handles);
+                destructor(object);
+                None
+            }
+        }
     }
 }
 
@@ -75,30 +120,79 @@ extern "C" fn alloc(data: *mut c_void, size: usize) -> *mut u8 {
 extern "C" fn load_resource(
     data: *mut c_void,
     name: *const c_char,
+    handle: *mut ResourceHandleId,
     output: *mut *const u8,
     output_size: *mut usize,
-) -> bool {
+) -> LoadResourceStatus {
     let name = unsafe { CStr::from_ptr(name).to_string_lossy() };
-    let data = data as *mut ModuleInterfaceData;
+    let data = unsafe { &*(data as *mut ModuleInterfaceData) };
+
+    let error = match data.load_resource(&*name) {
+        Ok((id, ptr, size)) => {
+            unsafe {
+                *handle = id;
+                *output = ptr;
+                *output_size = size;
+            }
+            return LoadResourceStatus::Ok;
+        }
+        Err(e) => e,
+    };
 
-    let handle = unsafe { (*data).load_resource(&*name) };
+    error!("{}", error);
 
-    if let Some(handle) = handle {
-        unsafe {
-            *output = handle.as_ptr().unwrap();
-            *output_size = handle.size().unwrap();
+    // Hand the error message to the module through its allocator
+    let message = error.to_string();
+    let memory = data
+        .allocator
+        .alloc(message.len())
+        .unwrap_or(std::ptr::null_mut());
+    unsafe {
+        if memory.is_null() {
+            *output = std::ptr::null();
+            *output_size = 0;
+        } else {
+            std::ptr::copy_nonoverlapping(message.as_ptr(), memory, message.len());
+            *output = memory;
+            *output_size = message.len();
         }
-        return true;
     }
 
-    false
+    match error {
+        ResourceError::NotFound(_) => LoadResourceStatus::NotFound,
+        ResourceError::LoadFailed { .. } => LoadResourceStatus::LoadFailed,
+    }
 }
 
-extern "C" fn release_resource(data: *mut c_void, name: *const c_char) -> bool {
-    let name = unsafe { CStr::from_ptr(name).to_string_lossy() };
+extern "C" fn release_resource(data: *mut c_void, handle: ResourceHandleId) -> bool {
     let data = data as *mut ModuleInterfaceData;
 
-    unsafe { (*data).release_resource(&*name) }
+    unsafe { (*data).release_resource(handle) }
+}
+
+extern "C" fn get_resource_object(
+    data: *mut c_void,
+    handle: ResourceHandleId,
+    key: *const c_char,
+) -> *mut c_void {
+    let key = unsafe { CStr::from_ptr(key).to_string_lossy() };
+    let data = data as *mut ModuleInterfaceData;
+
+    unsafe { (*data).resource_object(handle, &*key) }.unwrap_or(std::ptr::null_mut())
+}
+
+extern "C" fn attach_resource_object(
+    data: *mut c_void,
+    handle: ResourceHandleId,
+    key: *const c_char,
+    object: *mut c_void,
+    destructor: ObjectDestructorFn,
+) -> *mut c_void {
+    let key = unsafe { CStr::from_ptr(key).to_string_lossy() };
+    let data = data as *mut ModuleInterfaceData;
+
+    unsafe { (*data).attach_resource_object(handle, &*key, object, destructor) }
+        .unwrap_or(std::ptr::null_mut())
 }
 
 pub type MetadataType = TypedReader<
@@ -203,6 +297,17 @@ fn log_metadata(metadata: &MetadataType) -> Result<(), Box<dyn Error>> {
     Ok(())
 }
 
+impl Drop for Module {
+    fn drop(&mut self) {
+        // Claims the module never released and objects it attached to resources are freed by
+        // code of the library, which is unloaded right after this
+        self.interface_data.resource_handles.lock().clear();
+        self.interface_data
+            .resource_registry
+            .free_objects_of(self.interface_data.owner());
+    }
+}
+
 impl Module {
     /// Load, initialize and request metadata of the module
     pub fn load(
@@ -222,6 +327,8 @@ impl Module {
             alloc_fn: alloc,
             load_resource_fn: load_resource,
             release_resource_fn: release_resource,
+            get_resource_object_fn: get_resource_object,
+            attach_resource_object_fn: attach_resource_object,
         });
 
         let mut module = Module {
@@ -247,6 +354,39 @@ impl Module {
         &self.metadata
     }
 
+    /// The version the module reports in its metadata
+    pub fn version(&self) -> Option<String> {
+        let metadata = self.metadata.as_ref()?.lock();
+        let version = metadata.get().ok()?.get_module_version().ok()?;
+        Some(version.to_string())
+    }
+
+    /// Whether the module's metadata lists the command
+    pub fn has_command(&self, name: &str) -> bool {
+        let metadata = match self.metadata {
+            Some(ref metadata) => metadata.lock(),
+            None => return false,
+        };
+        let commands = match metadata.get().and_then(|metadata| metadata.get_commands()) {
+            Ok(commands) => commands,
+            Err(_) => return false,
+        };
+        commands
+            .iter()
+            .any(|command| command.get_name().map(|n| n == name).unwrap_or(false))
+    }
+
+    /// Type ids of the inputs and the output of a command, as listed in the module's metadata
+    pub fn command_types(&self, name: &str) -> Option<(Vec<u64>, u64)> {
+        let metadata = self.metadata.as_ref()?.lock();
+        let commands = metadata.get().ok()?.get_commands().ok()?;
+        let command = commands
+            .iter()
+            .find(|command| command.get_name().map(|n| n == name).unwrap_or(false))?;
+        let inputs = command.get_inputs().ok()?.iter().collect();
+        Some((inputs, command.get_output()))
+    }
+
     fn call_init(&self) -> Result<(), Box<dyn Error>> {
         let func: libloading::Symbol<ModuleInitFn> = unsafe { self.library.get(b"pipeline_init")? };
 
@@ -1,5 +1,5 @@
 use std::{
-    collections::{HashMap, HashSet},
+    collections::HashMap,
     error::Error,
     fmt,
     path::{Path, PathBuf},
@@ -38,38 +38,42 @@ impl Error for ModuleLoadError {}
 pub struct ModuleRegistry {
     allocator: Arc<ModuleAllocator>,
     resource_registry: Arc<ResourceRegistry>,
-    search_paths: HashSet<PathBuf>,
+    search_paths: Vec<PathBuf>,
     registry: RwLock<HashMap<String, Arc<Module>>>,
 }
 
 impl ModuleRegistry {
-    /// Create a new module registry with the default search path of '{current_dir}/modules'
-    /// and using the passed in allocator to initialize all modules loaded in the future
+    /// Create a new module registry without any search paths, using the passed in allocator to
+    /// initialize all modules loaded in the future
     pub fn new(
         allocator: Arc<ModuleAllocator>,
         resource_registry: Arc<ResourceRegistry>,
     ) -> Result<ModuleRegistry, Box<dyn Error>> {
-        let mut search_paths = HashSet::new();
-
-        // Add default search path
-        let mut path = PathBuf::from(std::env::current_dir()?);
-        path.push("modules");
-        search_paths.insert(path);
-
         Ok(ModuleRegistry {
             allocator,
             resource_registry,
-            search_paths,
+            search_paths: Vec::new(),
             registry: RwLock::new(HashMap::new()),
         })
     }
 
-    /// Add a search path to be searched when loading modules
+    /// Add a search path to be searched when loading modules, after the ones added before it
     pub fn add_search_path(&mut self, path: &Path) {
-        self.search_paths.insert(path.into());
+        if !self
+            .search_paths
+            .iter()
+            .any(|search_path| search_path == path)
+        {
+            self.search_paths.push(path.into());
+        }
+    }
+
+    /// The search paths in the order they are searched
+    pub fn search_paths(&self) -> &[PathBuf] {
+        &self.search_paths
     }
 
-    /// Enumerate the registry's search paths and try to load the module with the given name.
+    /// Go through the registry's search paths in order and try to load the module with the given name.
     /// If found, initializes the module and returns it. Alternatively returns a list of
     /// errors for each attempted load (if there are multiple search paths).
     pub fn get_module(&self, module_name: &str) -> Result<Arc<Module>, Box<dyn Error>> {
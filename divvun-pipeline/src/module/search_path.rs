@@ -0,0 +1,178 @@
+use std::{
+    env,
+    error::Error,
+    ffi::OsStr,
+    fmt, fs, io,
+    path::{Path, PathBuf},
+};
+
+use serde::Deserialize;
+
+use crate::{
+    run::DEFAULT_MODULE_SEARCH_PATH,
+    signature::{SignatureError, TrustedKeys},
+};
+
+/// Environment variable with module search paths, separated like `PATH`
+pub const MODULE_PATH_ENV: &str = "DIVVUN_PIPELINE_MODULE_PATH";
+
+const CONFIG_DIR_NAME: &str = "divvun-pipeline";
+const CONFIG_FILE_NAME: &str = "config.toml";
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Io(PathBuf, io::Error),
+    Parse(PathBuf, toml::de::Error),
+    /// A key file listed in `trusted_keys` couldn't be read
+    Keys(SignatureError),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ConfigError::Io(ref path, ref e) => write!(f, "{}: {}", path.display(), e),
+            ConfigError::Parse(ref path, ref e) => write!(f, "{}: {}", path.display(), e),
+            ConfigError::Keys(ref e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl Error for ConfigError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            ConfigError::Io(_, ref e) => Some(e),
+            ConfigError::Parse(_, ref e) => Some(e),
+            ConfigError::Keys(ref e) => Some(e),
+        }
+    }
+}
+
+/// Contents of a `config.toml`
+#[derive(Debug, Default, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PipelineConfig {
+    /// Module search paths, relative ones are relative to the directory of the config file
+    #[serde(default)]
+    pub module_paths: Vec<PathBuf>,
+    /// Files with the public keys pipeline files may be signed with, relative like
+    /// `module_paths`
+    #[serde(default)]
+    pub trusted_keys: Vec<PathBuf>,
+    /// Reject unsigned pipeline files
+    #[serde(default)]
+    pub require_signature: bool,
+}
+
+impl PipelineConfig {
+    /// Read a config file, `None` if it doesn't exist
+    pub fn load(path: &Path) -> Result<Option<PipelineConfig>, ConfigError> {
+        let text = match fs::read_to_string(path) {
+            Ok(text) => text,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(ConfigError::Io(path.to_path_buf(), e)),
+        };
+        let mut config: PipelineConfig =
+            toml::from_str(&text).map_err(|e| ConfigError::Parse(path.to_path_buf(), e))?;
+
+        let dir = path.parent().unwrap_or_else(|| Path::new(""));
+        for path in config
+            .module_paths
+            .iter_mut()
+            .chain(config.trusted_keys.iter_mut())
+        {
+            *path = dir.join(&*path);
+        }
+        Ok(Some(config))
+    }
+}
+
+/// The config files that are read, the user's one in `$XDG_CONFIG_HOME` or `~/.config` first
+/// and then `/etc/divvun-pipeline/config.toml`
+pub fn config_files() -> Vec<PathBuf> {
+    let mut files = Vec::new();
+
+    let user_dir = env::var_os("XDG_CONFIG_HOME")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|home| Path::new(&home).join(".config")));
+    if let Some(dir) = user_dir {
+        files.push(dir.join(CONFIG_DIR_NAME).join(CONFIG_FILE_NAME));
+    }
+    files.push(
+        Path::new("/etc")
+            .join(CONFIG_DIR_NAME)
+            .join(CONFIG_FILE_NAME),
+    );
+
+    files
+}
+
+/// Module search paths in the order they are searched: `explicit` ones (e.g. from `-m`), those
+/// from `DIVVUN_PIPELINE_MODULE_PATH` and then those of the config files. Falls back to
+/// `modules` in the current directory if none are given anywhere.
+pub fn module_search_paths(explicit: &[PathBuf]) -> Result<Vec<PathBuf>, ConfigError> {
+    let env_paths = env::var_os(MODULE_PATH_ENV);
+    resolve_search_paths(
+        explicit,
+        env_paths.as_ref().map(|paths| &**paths),
+        &config_files(),
+    )
+}
+
+/// Like `module_search_paths`, with the value of the environment variable and the config files
+/// to read given
+pub fn resolve_search_paths(
+    explicit: &[PathBuf],
+    env_paths: Option<&OsStr>,
+    config_files: &[PathBuf],
+) -> Result<Vec<PathBuf>, ConfigError> {
+    let mut paths = Vec::new();
+    let mut add = |path: PathBuf| {
+        if !path.as_os_str().is_empty() && !paths.contains(&path) {
+            paths.push(path);
+        }
+    };
+
+    explicit.iter().cloned().for_each(&mut add);
+    if let Some(env_paths) = env_paths {
+        env::split_paths(env_paths).for_each(&mut add);
+    }
+    for file in config_files {
+        if let Some(config) = PipelineConfig::load(file)? {
+            config.module_paths.into_iter().for_each(&mut add);
+        }
+    }
+
+    if paths.is_empty() {
+        paths.push(PathBuf::from(DEFAULT_MODULE_SEARCH_PATH));
+    }
+    Ok(paths)
+}
+
+/// The keys pipeline files may be signed with: those in `key_files` (e.g. from `--trusted-keys`)
+/// and those of the config files. Without any every signing key is accepted.
+pub fn trusted_keys(key_files: &[PathBuf]) -> Result<TrustedKeys, ConfigError> {
+    resolve_trusted_keys(key_files, &config_files())
+}
+
+/// Like `trusted_keys`, with the config files to read given
+pub fn resolve_trusted_keys(
+    key_files: &[PathBuf],
+    config_files: &[PathBuf],
+) -> Result<TrustedKeys, ConfigError> {
+    let mut keys = TrustedKeys::new();
+    for path in key_files {
+        keys.add_key_file(path).map_err(ConfigError::Keys)?;
+    }
+
+    let mut require_signature = false;
+    for file in config_files {
+        if let Some(config) = PipelineConfig::load(file)? {
+            for path in &config.trusted_keys {
+                keys.add_key_file(path).map_err(ConfigError::Keys)?;
+            }
+            require_signature |= config.require_signature;
+        }
+    }
+    Ok(keys.require_signature(require_signature))
+}
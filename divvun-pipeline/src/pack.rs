@@ -0,0 +1,432 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    error::Error,
+    fmt,
+    fs::{self, File},
+    io::{self, BufReader, Read, Write},
+    mem,
+    path::{Path, PathBuf},
+};
+
+use ed25519_dalek::Keypair;
+use log::{error, info, warn};
+use tempfile::NamedTempFile;
+use zip::{result::ZipError, write::FileOptions, CompressionMethod, ZipArchive, ZipWriter};
+
+use crate::{
+    file::{resource_name, PIPELINE_EXTENSION},
+    manifest::{Manifest, ModuleRequirement, MANIFEST_FILE_NAME, MANIFEST_VERSION},
+    module::ModuleRegistry,
+    pipeline::{find_placeholder, Pipeline},
+    resources::ResourceMetadata,
+    signature::{sign_manifest, PipelineSignature, SIGNATURE_FILE_NAME},
+};
+
+/// Stored resources start on a page boundary so they can be mapped directly from the archive
+pub const RESOURCE_ALIGNMENT: u16 = 4096;
+
+#[derive(Debug)]
+pub enum PackError {
+    Io(io::Error),
+    Zip(ZipError),
+    InvalidExtension,
+    InvalidManifest(serde_json::Error),
+    InvalidResourceName(String),
+    DuplicateResource(String),
+    NoPipeline,
+    MissingModule { name: String, error: String },
+    UnknownCommand { module: String, command: String },
+    NoManifest,
+    UnlistedEntry(String),
+}
+
+impl fmt::Display for PackError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PackError::Io(ref e) => write!(f, "{}", e),
+            PackError::Zip(ref e) => write!(f, "{}", e),
+            PackError::InvalidExtension => write!(
+                f,
+                "pipeline file must have the .{} extension",
+                PIPELINE_EXTENSION
+            ),
+            PackError::InvalidManifest(ref e) => write!(f, "invalid {}: {}", MANIFEST_FILE_NAME, e),
+            PackError::InvalidResourceName(ref name) => {
+                write!(f, "{} is not a valid resource name", name)
+            }
+            PackError::DuplicateResource(ref name) => {
+                write!(f, "resource {} was added more than once", name)
+            }
+            PackError::NoPipeline => write!(f, "no pipeline to pack"),
+            PackError::MissingModule {
+                ref name,
+                ref error,
+            } => write!(f, "module {} failed to load: {}", name, error),
+            PackError::UnknownCommand {
+                ref module,
+                ref command,
+            } => write!(f, "module {} has no command {}", module, command),
+            PackError::NoManifest => write!(f, "pipeline file has no {}", MANIFEST_FILE_NAME),
+            PackError::UnlistedEntry(ref name) => write!(
+                f,
+                "entry {} is not listed in {}, it can't be signed",
+                name, MANIFEST_FILE_NAME
+            ),
+        }
+    }
+}
+
+impl Error for PackError {}
+
+impl From<io::Error> for PackError {
+    fn from(e: io::Error) -> Self {
+        PackError::Io(e)
+    }
+}
+
+impl From<ZipError> for PackError {
+    fn from(e: ZipError) -> Self {
+        PackError::Zip(e)
+    }
+}
+
+/// Check that every module used by the pipeline is available and knows the command
+pub fn validate_pipeline(pipeline: &Pipeline, registry: &ModuleRegistry) -> Result<(), PackError> {
+    for command in pipeline.commands() {
+        let module =
+            registry
+                .get_module(&command.module)
+                .map_err(|e| PackError::MissingModule {
+                    name: command.module.clone(),
+                    error: e.to_string(),
+                })?;
+
+        if !module.has_command(&command.command) {
+            error!(
+                "Module {} has no command {}",
+                command.module, command.command
+            );
+            return Err(PackError::UnknownCommand {
+                module: command.module.clone(),
+                command: command.command.clone(),
+            });
+        }
+    }
+
+    Ok(())
+}
+
+/// Builds a .zpipe archive from a manifest and resource files
+pub struct PipelinePacker {
+    manifest: Manifest,
+    resources: Vec<(String, PathBuf)>,
+    signing_key: Option<Keypair>,
+}
+
+impl PipelinePacker {
+    /// The manifest's pipelines and metadata are written as is, its resources are replaced
+    /// by the ones added to the packer. The media type and module of a resource the manifest
+    /// lists are kept, a resource that isn't listed with a module gets the one of the commands
+    /// it is passed to, if that is only one. Every module the pipelines use is added to the
+    /// required modules.
+    pub fn new(manifest: Manifest) -> PipelinePacker {
+        PipelinePacker {
+            manifest,
+            resources: Vec::new(),
+            signing_key: None,
+        }
+    }
+
+    /// Sign the manifest of the written archive with `keypair`
+    pub fn sign_with(&mut self, keypair: Keypair) {
+        self.signing_key = Some(keypair);
+    }
+
+    /// Add a resource file, available to modules under `name`. Names may contain `/` to
+    /// place the resource in a subdirectory of the archive.
+    pub fn add_resource(&mut self, name: &str, path: &Path) -> Result<(), PackError> {
+        let name = match resource_name(name) {
+            Ok(ref name) if name != MANIFEST_FILE_NAME && !name.starts_with('.') => name.clone(),
+            _ => return Err(PackError::InvalidResourceName(name.to_string())),
+        };
+
+        if self.resources.iter().any(|(existing, _)| *existing == name) {
+            return Err(PackError::DuplicateResource(name));
+        }
+
+        self.resources.push((name, path.to_path_buf()));
+        Ok(())
+    }
+
+    /// Add all files below `dir` as resources named `{prefix}/{relative path}`, skipping
+    /// hidden files
+    pub fn add_resource_dir(&mut self, prefix: &str, dir: &Path) -> Result<(), PackError> {
+        let mut entries = fs::read_dir(dir)?.collect::<Result<Vec<_>, _>>()?;
+        entries.sort_by_key(|entry| entry.file_name());
+
+        for entry in entries {
+            let file_name = entry.file_name();
+            let file_name = match file_name.to_str() {
+                Some(file_name) if !file_name.starts_with('.') => file_name,
+                _ => continue,
+            };
+
+            let name = format!("{}/{}", prefix, file_name);
+            if entry.file_type()?.is_dir() {
+                self.add_resource_dir(&name, &entry.path())?;
+            } else {
+                self.add_resource(&name, &entry.path())?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// All pipelines in the archive
+    pub fn pipelines(&self) -> Vec<Pipeline> {
+        self.manifest
+            .pipeline
+            .iter()
+            .chain(self.manifest.pipelines.values())
+            .map(|root| Pipeline { root: root.clone() })
+            .collect()
+    }
+
+    /// Check all pipelines against the modules available in the registry
+    pub fn validate(&self, registry: &ModuleRegistry) -> Result<(), PackError> {
+        for pipeline in self.pipelines() {
+            validate_pipeline(&pipeline, registry)?;
+        }
+        Ok(())
+    }
+
+    /// Write the archive, returning the manifest that was stored in it
+    pub fn write(self, path: &Path) -> Result<Manifest, PackError> {
+        if path.extension().is_none() || path.extension().unwrap() != PIPELINE_EXTENSION {
+            return Err(PackError::InvalidExtension);
+        }
+
+        if self.manifest.pipeline.is_none() && self.manifest.pipelines.is_empty() {
+            return Err(PackError::NoPipeline);
+        }
+
+        let pipelines = self.pipelines();
+        let mut manifest = self.manifest;
+        manifest.version = MANIFEST_VERSION;
+        let listed = mem::replace(&mut manifest.resources, Vec::new());
+
+        // Module names with placeholders are only known when the pipeline is run
+        let mut consumers: BTreeMap<&str, BTreeSet<&str>> = BTreeMap::new();
+        for pipeline in &pipelines {
+            for command in pipeline.commands() {
+                if find_placeholder(&command.module).is_some() {
+                    continue;
+                }
+                manifest.require_module(ModuleRequirement {
+                    name: command.module.clone(),
+                    min_version: None,
+                });
+                for parameter in command.parameters.iter().flatten() {
+                    consumers
+                        .entry(parameter)
+                        .or_default()
+                        .insert(&command.module);
+                }
+            }
+        }
+
+        let mut zip = ZipWriter::new(File::create(path)?);
+        let options = FileOptions::default().compression_method(CompressionMethod::Stored);
+
+        for (name, resource_path) in &self.resources {
+            info!("Packing {} from {}", name, resource_path.display());
+            let data = fs::read(resource_path)?;
+            zip.start_file_aligned(name.as_str(), options, RESOURCE_ALIGNMENT)?;
+            zip.write_all(&data)?;
+            let mut metadata = ResourceMetadata::new(name, &data);
+            if let Some(listed) = listed.iter().find(|resource| resource.name == *name) {
+                metadata.media_type = listed.media_type.clone();
+                metadata.module = listed.module.clone();
+            }
+            if metadata.module.is_none() {
+                metadata.module = match consumers.get(name.as_str()) {
+                    Some(modules) if modules.len() == 1 => {
+                        modules.iter().next().map(|module| module.to_string())
+                    }
+                    _ => None,
+                };
+            }
+            manifest.resources.push(metadata);
+        }
+
+        let manifest_json =
+            serde_json::to_vec_pretty(&manifest).map_err(PackError::InvalidManifest)?;
+        zip.start_file(MANIFEST_FILE_NAME, options)?;
+        zip.write_all(&manifest_json)?;
+        if let Some(ref keypair) = self.signing_key {
+            write_signature(&mut zip, &sign_manifest(&manifest_json, keypair))?;
+        }
+        zip.finish()?;
+
+        info!("Wrote {}", path.display());
+        Ok(manifest)
+    }
+}
+
+/// Describes a single entry of an archive
+#[derive(Debug, Clone)]
+pub struct ArchiveEntry {
+    pub name: String,
+    pub size: u64,
+    pub compressed_size: u64,
+    pub stored: bool,
+    pub data_start: u64,
+}
+
+impl ArchiveEntry {
+    /// Whether the entry can be mapped directly from the archive without copying
+    pub fn is_aligned(&self) -> bool {
+        self.stored && self.data_start % RESOURCE_ALIGNMENT as u64 == 0
+    }
+}
+
+/// The manifest and entries of an archive
+#[derive(Debug)]
+pub struct ArchiveInfo {
+    pub manifest: Option<Manifest>,
+    pub entries: Vec<ArchiveEntry>,
+}
+
+fn open_archive(path: &Path) -> Result<ZipArchive<BufReader<File>>, PackError> {
+    Ok(ZipArchive::new(BufReader::new(File::open(path)?))?)
+}
+
+/// List the entries of an archive without loading it
+pub fn inspect_pipeline_file(path: &Path) -> Result<ArchiveInfo, PackError> {
+    let mut archive = open_archive(path)?;
+
+    let manifest = match archive.by_name(MANIFEST_FILE_NAME) {
+        Ok(file) => Some(serde_json::from_reader(file).map_err(PackError::InvalidManifest)?),
+        Err(ZipError::FileNotFound) => None,
+        Err(e) => return Err(e.into()),
+    };
+
+    let mut entries = Vec::new();
+    for i in 0..archive.len() {
+        let file = archive.by_index(i)?;
+        entries.push(ArchiveEntry {
+            name: file.name().to_string(),
+            size: file.size(),
+            compressed_size: file.compressed_size(),
+            stored: file.compression() == CompressionMethod::Stored,
+            data_start: file.data_start(),
+        });
+    }
+
+    Ok(ArchiveInfo { manifest, entries })
+}
+
+/// Extract all entries of an archive into `target_dir`
+pub fn unpack_pipeline_file(path: &Path, target_dir: &Path) -> Result<Vec<PathBuf>, PackError> {
+    let mut archive = open_archive(path)?;
+    fs::create_dir_all(target_dir)?;
+
+    let mut written = Vec::new();
+    for i in 0..archive.len() {
+        let mut file = archive.by_index(i)?;
+        if file.is_dir() {
+            continue;
+        }
+
+        let name = match resource_name(file.name()) {
+            Ok(name) => name,
+            Err(e) => {
+                warn!("Skipping entry {}: {}", file.name(), e);
+                continue;
+            }
+        };
+
+        let target = target_dir.join(name);
+        if let Some(parent) = target.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let mut data = Vec::new();
+        file.read_to_end(&mut data)?;
+        fs::write(&target, &data)?;
+        info!("Extracted {}", target.display());
+        written.push(target);
+    }
+
+    Ok(written)
+}
+
+fn write_signature<W: Write + io::Seek>(
+    zip: &mut ZipWriter<W>,
+    signature: &PipelineSignature,
+) -> Result<(), PackError> {
+    let signature_json =
+        serde_json::to_vec_pretty(signature).map_err(PackError::InvalidManifest)?;
+    zip.start_file(
+        SIGNATURE_FILE_NAME,
+        FileOptions::default().compression_method(CompressionMethod::Stored),
+    )?;
+    zip.write_all(&signature_json)?;
+    Ok(())
+}
+
+/// Sign an existing archive with `keypair`, replacing any previous signature. The archive is
+/// rewritten with its entries unchanged and stored entries kept aligned.
+pub fn sign_pipeline_file(path: &Path, keypair: &Keypair) -> Result<PipelineSignature, PackError> {
+    let mut archive = open_archive(path)?;
+
+    let mut manifest_json = Vec::new();
+    match archive.by_name(MANIFEST_FILE_NAME) {
+        Ok(mut file) => file.read_to_end(&mut manifest_json)?,
+        Err(ZipError::FileNotFound) => return Err(PackError::NoManifest),
+        Err(e) => return Err(e.into()),
+    };
+    let manifest: Manifest =
+        serde_json::from_slice(&manifest_json).map_err(PackError::InvalidManifest)?;
+
+    let dir = path.parent().unwrap_or_else(|| Path::new("."));
+    let temp_file = NamedTempFile::new_in(dir)?;
+    let mut zip = ZipWriter::new(temp_file.reopen()?);
+
+    for i in 0..archive.len() {
+        let mut file = archive.by_index(i)?;
+        let name = file.name().to_string();
+        if file.is_dir() || name == SIGNATURE_FILE_NAME {
+            continue;
+        }
+
+        if name != MANIFEST_FILE_NAME
+            && manifest
+                .resource(&resource_name(&name).unwrap_or_default())
+                .is_none()
+        {
+            error!("Entry {} is not listed in the manifest", name);
+            return Err(PackError::UnlistedEntry(name));
+        }
+
+        let mut data = Vec::new();
+        file.read_to_end(&mut data)?;
+        let options = FileOptions::default().compression_method(file.compression());
+        if file.compression() == CompressionMethod::Stored && name != MANIFEST_FILE_NAME {
+            zip.start_file_aligned(name.as_str(), options, RESOURCE_ALIGNMENT)?;
+        } else {
+            zip.start_file(name.as_str(), options)?;
+        }
+        zip.write_all(&data)?;
+    }
+
+    let signature = sign_manifest(&manifest_json, keypair);
+    write_signature(&mut zip, &signature)?;
+    zip.finish()?;
+    drop(archive);
+
+    temp_file
+        .persist(path)
+        .map_err(|e| PackError::Io(e.error))?;
+    info!("Signed {} with {}", path.display(), signature.public_key);
+    Ok(signature)
+}
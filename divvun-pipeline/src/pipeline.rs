@@ -1,40 +1,228 @@
-use std::sync::Arc;
+use std::{
+    collections::BTreeMap,
+    error::Error,
+    fmt,
+    sync::{mpsc, Arc},
+    time::{Duration, Instant},
+};
 
-use futures::future::{join_all, FutureExt};
+use futures::{
+    channel::oneshot,
+    executor::block_on,
+    future::{join_all, FutureExt},
+};
 use log::info;
-use serde::{Deserialize, Serialize};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize, Serializer};
 
-use crate::module::ModuleRegistry;
+use crate::{
+    executor::PipelineExecutor,
+    module::ModuleRegistry,
+    resources::{ResourceError, ResourceRegistry},
+};
 
 #[derive(Debug)]
 pub enum PipelineError {
     NodeFailed,
+    UnresolvedInclude(String),
+    Cancelled,
+    /// A module name still has a `${name}` placeholder no value was given for
+    UnsetVariable { module: String, variable: String },
+    /// The module of a command couldn't be loaded or its command failed
+    ModuleFailed {
+        module: String,
+        command: String,
+        message: String,
+    },
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+impl fmt::Display for PipelineError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PipelineError::NodeFailed => write!(f, "pipeline node failed"),
+            PipelineError::UnresolvedInclude(ref name) => {
+                write!(f, "include of pipeline {} was not resolved", name)
+            }
+            PipelineError::Cancelled => write!(f, "pipeline run was cancelled"),
+            PipelineError::UnsetVariable {
+                ref module,
+                ref variable,
+            } => write!(f, "unset variable {} in module name {}", variable, module),
+            PipelineError::ModuleFailed {
+                ref module,
+                ref command,
+                ref message,
+            } => write!(f, "{} {} failed: {}", module, command, message),
+        }
+    }
+}
+
+impl Error for PipelineError {}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Pipeline {
     pub root: PipelineNodeSerial,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
 pub struct PipelineCommand {
     pub module: String,
     pub command: String,
     pub parameters: Option<Vec<String>>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-#[serde(untagged)]
+/// Reference to another pipeline, replaced by that pipeline when the pipeline file is loaded
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct PipelineInclude {
+    /// Name of the included pipeline
+    pub pipeline: String,
+    /// Pipeline file or unpacked directory the pipeline is taken from, relative to the including
+    /// one. The pipeline is looked up in the including file if not given.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub file: Option<String>,
+    /// Values for the `${name}` placeholders in the parameters of the included pipeline
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub parameters: BTreeMap<String, String>,
+}
+
+/// Explicitly tagged pipeline definition, e.g. `{ "serial": [{ "run": { ... } }] }`. Accepted
+/// anywhere a pipeline is read, next to the untagged nesting of serial and parallel arrays.
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum PipelineNode {
+    /// Run the nodes one after another, each one getting the output of the previous one
+    Serial(Vec<PipelineNode>),
+    /// Run the nodes side by side on the same input, concatenating their outputs
+    Parallel(Vec<PipelineNode>),
+    /// Run a single module command
+    Run(PipelineCommand),
+    /// Run another pipeline
+    Include(PipelineInclude),
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(untagged, from = "SerialDefinition")]
 pub enum PipelineNodeSerial {
     SerialSingle(PipelineCommand),
     SerialMultiple(Vec<PipelineNodeParallel>),
+    #[serde(serialize_with = "serialize_include")]
+    SerialInclude(PipelineInclude),
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-#[serde(untagged)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(untagged, from = "ParallelDefinition")]
 pub enum PipelineNodeParallel {
     ParallelSingle(PipelineCommand),
     ParallelMultiple(Vec<PipelineNodeSerial>),
+    #[serde(serialize_with = "serialize_include")]
+    ParallelInclude(PipelineInclude),
+}
+
+/// Includes have no untagged form, they are always written tagged
+fn serialize_include<S: Serializer>(
+    include: &PipelineInclude,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    PipelineNode::Include(include.clone()).serialize(serializer)
+}
+
+/// Either form of a serial node as found in pipeline files
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum SerialDefinition {
+    Tagged(PipelineNode),
+    Single(PipelineCommand),
+    Multiple(Vec<PipelineNodeParallel>),
+}
+
+/// Either form of a parallel node as found in pipeline files
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum ParallelDefinition {
+    Tagged(PipelineNode),
+    Single(PipelineCommand),
+    Multiple(Vec<PipelineNodeSerial>),
+}
+
+impl From<SerialDefinition> for PipelineNodeSerial {
+    fn from(definition: SerialDefinition) -> Self {
+        match definition {
+            SerialDefinition::Tagged(node) => node.into(),
+            SerialDefinition::Single(command) => PipelineNodeSerial::SerialSingle(command),
+            SerialDefinition::Multiple(nodes) => PipelineNodeSerial::SerialMultiple(nodes),
+        }
+    }
+}
+
+impl From<ParallelDefinition> for PipelineNodeParallel {
+    fn from(definition: ParallelDefinition) -> Self {
+        match definition {
+            ParallelDefinition::Tagged(node) => node.into(),
+            ParallelDefinition::Single(command) => PipelineNodeParallel::ParallelSingle(command),
+            ParallelDefinition::Multiple(nodes) => PipelineNodeParallel::ParallelMultiple(nodes),
+        }
+    }
+}
+
+impl From<PipelineNode> for PipelineNodeSerial {
+    fn from(node: PipelineNode) -> Self {
+        match node {
+            PipelineNode::Run(command) => PipelineNodeSerial::SerialSingle(command),
+            PipelineNode::Include(include) => PipelineNodeSerial::SerialInclude(include),
+            PipelineNode::Serial(nodes) => {
+                PipelineNodeSerial::SerialMultiple(nodes.into_iter().map(Into::into).collect())
+            }
+            PipelineNode::Parallel(nodes) => {
+                PipelineNodeSerial::SerialMultiple(vec![PipelineNodeParallel::ParallelMultiple(
+                    nodes.into_iter().map(Into::into).collect(),
+                )])
+            }
+        }
+    }
+}
+
+impl From<PipelineNode> for PipelineNodeParallel {
+    fn from(node: PipelineNode) -> Self {
+        match node {
+            PipelineNode::Run(command) => PipelineNodeParallel::ParallelSingle(command),
+            PipelineNode::Include(include) => PipelineNodeParallel::ParallelInclude(include),
+            PipelineNode::Parallel(nodes) => {
+                PipelineNodeParallel::ParallelMultiple(nodes.into_iter().map(Into::into).collect())
+            }
+            // A parallel node with a single branch behaves like that branch
+            PipelineNode::Serial(nodes) => {
+                PipelineNodeParallel::ParallelMultiple(vec![PipelineNodeSerial::SerialMultiple(
+                    nodes.into_iter().map(Into::into).collect(),
+                )])
+            }
+        }
+    }
+}
+
+impl From<&PipelineNodeSerial> for PipelineNode {
+    fn from(node: &PipelineNodeSerial) -> Self {
+        match node {
+            PipelineNodeSerial::SerialSingle(command) => PipelineNode::Run(command.clone()),
+            PipelineNodeSerial::SerialMultiple(nodes) => {
+                PipelineNode::Serial(nodes.iter().map(Into::into).collect())
+            }
+            PipelineNodeSerial::SerialInclude(include) => PipelineNode::Include(include.clone()),
+        }
+    }
+}
+
+impl From<&PipelineNodeParallel> for PipelineNode {
+    fn from(node: &PipelineNodeParallel) -> Self {
+        match node {
+            PipelineNodeParallel::ParallelSingle(command) => PipelineNode::Run(command.clone()),
+            PipelineNodeParallel::ParallelMultiple(nodes) => {
+                PipelineNode::Parallel(nodes.iter().map(Into::into).collect())
+            }
+            PipelineNodeParallel::ParallelInclude(include) => {
+                PipelineNode::Include(include.clone())
+            }
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -43,27 +231,202 @@ pub struct PipelineData {
     pub size: usize,
 }
 
-type PipelineType = Arc<Vec<Arc<PipelineData>>>;
+pub type PipelineType = Arc<Vec<Arc<PipelineData>>>;
 
 unsafe impl Send for PipelineData {}
 unsafe impl Sync for PipelineData {}
 
+impl PipelineData {
+    pub fn as_slice(&self) -> &[u8] {
+        if self.size == 0 {
+            return &[];
+        }
+        unsafe { std::slice::from_raw_parts(self.data, self.size) }
+    }
+}
+
+/// Position of a node in the pipeline, as the indices of the nodes leading to it from the root
+#[derive(Debug, Clone, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct NodePath(pub Vec<usize>);
+
+impl NodePath {
+    fn child(&self, index: usize) -> NodePath {
+        let mut path = self.0.clone();
+        path.push(index);
+        NodePath(path)
+    }
+}
+
+impl fmt::Display for NodePath {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.0.is_empty() {
+            return write!(f, "root");
+        }
+
+        let indices = self.0.iter().map(usize::to_string).collect::<Vec<_>>();
+        write!(f, "{}", indices.join("."))
+    }
+}
+
+/// Notified of every command run by `Pipeline::run_observed`, e.g. to show intermediate outputs
+pub trait PipelineObserver: Send + Sync {
+    fn command_finished(
+        &self,
+        path: &NodePath,
+        command: &PipelineCommand,
+        input: &PipelineType,
+        output: &PipelineType,
+        duration: Duration,
+    );
+
+    /// Checked before every command, the run stops with `PipelineError::Cancelled` once true
+    fn cancelled(&self) -> bool {
+        false
+    }
+}
+
 impl Pipeline {
+    /// All commands of the pipeline in definition order
+    pub fn commands(&self) -> Vec<&PipelineCommand> {
+        let mut commands = Vec::new();
+        self.root.collect_commands(&mut commands);
+        commands
+    }
+
+    /// A copy of the pipeline with the `${name}` placeholders in module names and parameters
+    /// replaced by the given variables
+    pub fn with_variables(&self, variables: &BTreeMap<String, String>) -> Pipeline {
+        let mut root = self.root.clone();
+        root.substitute(variables);
+        Pipeline { root }
+    }
+
+    /// Fail if a module name has a placeholder left after `with_variables`, which would
+    /// otherwise be looked up as a module
+    pub fn check_variables(&self) -> Result<(), PipelineError> {
+        for command in self.commands() {
+            if let Some(variable) = find_placeholder(&command.module) {
+                return Err(PipelineError::UnsetVariable {
+                    module: command.module.clone(),
+                    variable: variable.to_string(),
+                });
+            }
+        }
+        Ok(())
+    }
+
+    /// Load every resource referenced by a command parameter ahead of the first run
+    pub fn warm_resources(&self, resources: &ResourceRegistry) -> Result<(), ResourceError> {
+        let names = self
+            .commands()
+            .into_iter()
+            .filter_map(|command| command.parameters.as_ref())
+            .flatten()
+            .filter(|parameter| resources.contains(parameter))
+            .map(|parameter| &**parameter)
+            .collect::<Vec<_>>();
+
+        info!("warming resources: {:?}", names);
+        resources.warm(names)
+    }
+
     pub async fn run(
         &self,
         registry: Arc<ModuleRegistry>,
         input: PipelineType,
+    ) -> Result<PipelineType, PipelineError> {
+        self.run_with(registry, input, None, None).await
+    }
+
+    /// Run the pipeline, passing the output of every command to the observer as it finishes
+    pub async fn run_observed(
+        &self,
+        registry: Arc<ModuleRegistry>,
+        input: PipelineType,
+        observer: Arc<dyn PipelineObserver>,
+    ) -> Result<PipelineType, PipelineError> {
+        self.run_with(registry, input, Some(observer), None).await
+    }
+
+    /// Run the pipeline with an optional observer, calling the modules on `executor` if given
+    pub async fn run_with(
+        &self,
+        registry: Arc<ModuleRegistry>,
+        input: PipelineType,
+        observer: Option<Arc<dyn PipelineObserver>>,
+        executor: Option<Arc<dyn PipelineExecutor>>,
     ) -> Result<PipelineType, PipelineError> {
         // TODO: Validate here
-        self.root.run(registry, input).await
+        self.check_variables()?;
+        let context = RunContext {
+            registry,
+            observer,
+            executor,
+        };
+        self.root.run(context, input, NodePath::default()).await
+    }
+
+    /// Run the pipeline on the current thread, for callers without an async executor
+    pub fn run_blocking(
+        &self,
+        registry: Arc<ModuleRegistry>,
+        input: PipelineType,
+    ) -> Result<PipelineType, PipelineError> {
+        block_on(self.run(registry, input))
     }
 }
 
 impl PipelineNodeSerial {
+    fn collect_commands<'a>(&'a self, commands: &mut Vec<&'a PipelineCommand>) {
+        match self {
+            PipelineNodeSerial::SerialSingle(command) => commands.push(command),
+            PipelineNodeSerial::SerialMultiple(nodes) => {
+                for node in nodes {
+                    node.collect_commands(commands);
+                }
+            }
+            PipelineNodeSerial::SerialInclude(_) => {}
+        }
+    }
+
+    /// Replace every include with the pipeline returned by `resolve`
+    pub fn resolve_includes<E>(
+        &self,
+        resolve: &mut dyn FnMut(&PipelineInclude) -> Result<PipelineNodeSerial, E>,
+    ) -> Result<PipelineNodeSerial, E> {
+        Ok(match self {
+            PipelineNodeSerial::SerialSingle(command) => {
+                PipelineNodeSerial::SerialSingle(command.clone())
+            }
+            PipelineNodeSerial::SerialMultiple(nodes) => PipelineNodeSerial::SerialMultiple(
+                nodes
+                    .iter()
+                    .map(|node| node.resolve_includes(resolve))
+                    .collect::<Result<_, _>>()?,
+            ),
+            PipelineNodeSerial::SerialInclude(include) => resolve(include)?,
+        })
+    }
+
+    /// Replace `${name}` placeholders in module names, command parameters and the parameters
+    /// passed on to includes. Placeholders without a value are left as they are.
+    pub fn substitute(&mut self, values: &BTreeMap<String, String>) {
+        match self {
+            PipelineNodeSerial::SerialSingle(command) => command.substitute(values),
+            PipelineNodeSerial::SerialMultiple(nodes) => {
+                for node in nodes {
+                    node.substitute(values);
+                }
+            }
+            PipelineNodeSerial::SerialInclude(include) => include.substitute(values),
+        }
+    }
+
     fn run<'a>(
         &'a self,
-        registry: Arc<ModuleRegistry>,
+        context: RunContext,
         input: PipelineType,
+        path: NodePath,
     ) -> std::pin::Pin<
         Box<dyn futures::future::Future<Output = Result<PipelineType, PipelineError>> + 'a + Send>,
     > {
@@ -72,17 +435,20 @@ impl PipelineNodeSerial {
         async move {
             match self {
                 PipelineNodeSerial::SerialSingle(command) => {
-                    process_single(registry, command, input)
+                    process_single(context, command, input, path).await
                 }
                 PipelineNodeSerial::SerialMultiple(nodes) => {
                     let mut input = input.clone();
 
-                    for node in nodes {
-                        input = node.run(Arc::clone(&registry), input).await?;
+                    for (index, node) in nodes.iter().enumerate() {
+                        input = node.run(context.clone(), input, path.child(index)).await?;
                     }
 
                     Ok(input)
                 }
+                PipelineNodeSerial::SerialInclude(include) => {
+                    Err(PipelineError::UnresolvedInclude(include.pipeline.clone()))
+                }
             }
         }
             .boxed()
@@ -90,24 +456,76 @@ impl PipelineNodeSerial {
 }
 
 impl PipelineNodeParallel {
+    fn collect_commands<'a>(&'a self, commands: &mut Vec<&'a PipelineCommand>) {
+        match self {
+            PipelineNodeParallel::ParallelSingle(command) => commands.push(command),
+            PipelineNodeParallel::ParallelMultiple(nodes) => {
+                for node in nodes {
+                    node.collect_commands(commands);
+                }
+            }
+            PipelineNodeParallel::ParallelInclude(_) => {}
+        }
+    }
+
+    fn resolve_includes<E>(
+        &self,
+        resolve: &mut dyn FnMut(&PipelineInclude) -> Result<PipelineNodeSerial, E>,
+    ) -> Result<PipelineNodeParallel, E> {
+        Ok(match self {
+            PipelineNodeParallel::ParallelSingle(command) => {
+                PipelineNodeParallel::ParallelSingle(command.clone())
+            }
+            PipelineNodeParallel::ParallelMultiple(nodes) => {
+                PipelineNodeParallel::ParallelMultiple(
+                    nodes
+                        .iter()
+                        .map(|node| node.resolve_includes(resolve))
+                        .collect::<Result<_, _>>()?,
+                )
+            }
+            // The included pipeline becomes the only branch
+            PipelineNodeParallel::ParallelInclude(include) => {
+                PipelineNodeParallel::ParallelMultiple(vec![resolve(include)?])
+            }
+        })
+    }
+
+    fn substitute(&mut self, values: &BTreeMap<String, String>) {
+        match self {
+            PipelineNodeParallel::ParallelSingle(command) => command.substitute(values),
+            PipelineNodeParallel::ParallelMultiple(nodes) => {
+                for node in nodes {
+                    node.substitute(values);
+                }
+            }
+            PipelineNodeParallel::ParallelInclude(include) => include.substitute(values),
+        }
+    }
+
     fn run<'a>(
         &'a self,
-        registry: Arc<ModuleRegistry>,
+        context: RunContext,
         input: PipelineType,
+        path: NodePath,
     ) -> std::pin::Pin<
         Box<dyn futures::future::Future<Output = Result<PipelineType, PipelineError>> + 'a + Send>,
     > {
         async move {
             match self {
                 PipelineNodeParallel::ParallelSingle(command) => {
-                    process_single(registry, command, input)
+                    process_single(context, command, input, path).await
                 }
                 PipelineNodeParallel::ParallelMultiple(nodes) => {
                     let new_input = input.clone();
 
                     let mut vector = Vec::new();
-                    for node in nodes {
-                        vector.push(node.run(Arc::clone(&registry), new_input.clone()));
+                    for (index, node) in nodes.iter().enumerate() {
+                        vector.push(node.run(
+                            context.clone(),
+                            new_input.clone(),
+                            path.child(index),
+                        ));
                     }
 
                     let future_results = join_all(vector).await;
@@ -134,25 +552,152 @@ impl PipelineNodeParallel {
                         .collect::<Vec<_>>();
 
                     if errors.len() > 0 {
-                        // TODO: Flatten the errors into something useful instead of just returning this
-                        Err(PipelineError::NodeFailed)
+                        // TODO: Flatten the errors into something useful instead of just returning the first
+                        Err(errors.remove(0))
                     } else {
                         Ok(Arc::new(outputs))
                     }
                 }
+                PipelineNodeParallel::ParallelInclude(include) => {
+                    Err(PipelineError::UnresolvedInclude(include.pipeline.clone()))
+                }
             }
         }
             .boxed()
     }
 }
 
-fn process_single(
+impl PipelineCommand {
+    fn substitute(&mut self, values: &BTreeMap<String, String>) {
+        self.module = substitute_placeholders(&self.module, values);
+        if let Some(ref mut parameters) = self.parameters {
+            for parameter in parameters {
+                *parameter = substitute_placeholders(parameter, values);
+            }
+        }
+    }
+}
+
+impl PipelineInclude {
+    fn substitute(&mut self, values: &BTreeMap<String, String>) {
+        for value in self.parameters.values_mut() {
+            *value = substitute_placeholders(value, values);
+        }
+    }
+}
+
+/// Replace every `${name}` in `text` that has a value in `values`
+pub fn substitute_placeholders(text: &str, values: &BTreeMap<String, String>) -> String {
+    let mut result = String::with_capacity(text.len());
+    let mut rest = text;
+
+    while let Some(start) = rest.find("${") {
+        let end = match rest[start + 2..].find('}') {
+            Some(end) => start + 2 + end,
+            None => break,
+        };
+
+        result.push_str(&rest[..start]);
+        match values.get(&rest[start + 2..end]) {
+            Some(value) => result.push_str(value),
+            None => result.push_str(&rest[start..=end]),
+        }
+        rest = &rest[end + 1..];
+    }
+
+    result.push_str(rest);
+    result
+}
+
+/// Name of the first `${name}` placeholder in `text`
+pub fn find_placeholder(text: &str) -> Option<&str> {
+    let start = text.find("${")? + 2;
+    let end = text[start..].find('}')?;
+    Some(&text[start..start + end])
+}
+
+/// What the nodes of a run share
+#[derive(Clone)]
+struct RunContext {
     registry: Arc<ModuleRegistry>,
+    observer: Option<Arc<dyn PipelineObserver>>,
+    executor: Option<Arc<dyn PipelineExecutor>>,
+}
+
+async fn process_single(
+    context: RunContext,
     command: &PipelineCommand,
     input: PipelineType,
+    path: NodePath,
+) -> Result<PipelineType, PipelineError> {
+    if context
+        .observer
+        .as_ref()
+        .map_or(false, |observer| observer.cancelled())
+    {
+        return Err(PipelineError::Cancelled);
+    }
+
+    let executor = match context.executor {
+        Some(ref executor) => Arc::clone(executor),
+        None => return call_observed(&context, command, &input, &path),
+    };
+
+    let (sender, receiver) = oneshot::channel();
+    let (done, finished) = mpsc::channel::<()>();
+    let task_command = command.clone();
+    executor.spawn_blocking(Box::new(move || {
+        // Dropped once the call returned, or when the executor drops the task without running it
+        let _done = done;
+        // The task reports to the observer itself, so a run that is given up on still learns
+        // about the output
+        let _ = sender.send(call_observed(&context, &task_command, &input, &path));
+    }));
+
+    let _in_flight = InFlight(finished);
+    // The sender is dropped without a result if the module call panicked
+    receiver.await.map_err(|_| PipelineError::NodeFailed)?
+}
+
+/// Waits for a module call on an executor to return when dropped. A run future dropped while
+/// a call is in flight, e.g. on a timeout, would otherwise free the data the call reads.
+struct InFlight(mpsc::Receiver<()>);
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        let _ = self.0.recv();
+    }
+}
+
+/// Call the module of a command and tell the observer about its output
+fn call_observed(
+    context: &RunContext,
+    command: &PipelineCommand,
+    input: &PipelineType,
+    path: &NodePath,
+) -> Result<PipelineType, PipelineError> {
+    let start = Instant::now();
+    let output = call_module(&context.registry, command, input)?;
+
+    if let Some(ref observer) = context.observer {
+        observer.command_finished(path, command, input, &output, start.elapsed());
+    }
+
+    Ok(output)
+}
+
+/// Call the module of a command, blocking until it returns
+fn call_module(
+    registry: &ModuleRegistry,
+    command: &PipelineCommand,
+    input: &PipelineType,
 ) -> Result<PipelineType, PipelineError> {
-    // TODO: fix errors
-    let module = registry.get_module(&command.module).unwrap();
+    let failed = |e: Box<dyn Error>| PipelineError::ModuleFailed {
+        module: command.module.clone(),
+        command: command.command.clone(),
+        message: e.to_string(),
+    };
+    let module = registry.get_module(&command.module).map_err(failed)?;
 
     let mut ptr_vec = Vec::new();
     let mut size_vec = Vec::new();
@@ -171,7 +716,7 @@ fn process_single(
             ptr_vec,
             size_vec,
         )
-        .unwrap();
+        .map_err(failed)?;
 
     Ok(Arc::new(vec![Arc::new(PipelineData {
         data: output.output,
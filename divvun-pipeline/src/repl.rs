@@ -0,0 +1,209 @@
+use std::{collections::BTreeMap, error::Error, fmt::Write, sync::Arc};
+
+use crate::{
+    convert::{decode, DataFormat},
+    run::output_type,
+    session::{CommandRecorder, PipelineSession, SessionRequest},
+};
+
+pub const REPL_HELP: &str = "\
+Lines are run through the pipeline as text. Commands:
+  :pipeline [name]     list the pipelines or switch to another one
+  :set [key=value]     list the variables or set one
+  :unset key           remove a variable
+  :trace on|off        show the output of every command
+  :time on|off         show how long every command took
+  :help                show this help
+  :quit                leave";
+
+/// A line entered in the REPL
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ReplCommand {
+    Help,
+    Quit,
+    Pipelines,
+    Pipeline(String),
+    Variables,
+    Set(String, String),
+    Unset(String),
+    Trace(bool),
+    Time(bool),
+    Run(String),
+}
+
+impl ReplCommand {
+    pub fn parse(line: &str) -> Result<ReplCommand, String> {
+        let line = line.trim_end_matches(|c| c == '\n' || c == '\r');
+        if !line.starts_with(':') {
+            return Ok(ReplCommand::Run(line.to_string()));
+        }
+
+        let mut parts = line[1..].splitn(2, char::is_whitespace);
+        let name = parts.next().unwrap_or("");
+        let argument = parts.next().map(str::trim).filter(|arg| !arg.is_empty());
+
+        match (name, argument) {
+            ("help", None) | ("h", None) => Ok(ReplCommand::Help),
+            ("quit", None) | ("q", None) => Ok(ReplCommand::Quit),
+            ("pipeline", None) => Ok(ReplCommand::Pipelines),
+            ("pipeline", Some(name)) => Ok(ReplCommand::Pipeline(name.to_string())),
+            ("set", None) => Ok(ReplCommand::Variables),
+            ("set", Some(value)) => match value.find('=') {
+                Some(index) => Ok(ReplCommand::Set(
+                    value[..index].trim().to_string(),
+                    value[index + 1..].trim().to_string(),
+                )),
+                None => Err(format!("{} is not of the form key=value", value)),
+            },
+            ("unset", Some(key)) => Ok(ReplCommand::Unset(key.to_string())),
+            ("trace", Some(value)) => parse_switch(value).map(ReplCommand::Trace),
+            ("time", Some(value)) => parse_switch(value).map(ReplCommand::Time),
+            _ => Err(format!("unknown command {}, see :help", line)),
+        }
+    }
+}
+
+fn parse_switch(value: &str) -> Result<bool, String> {
+    match value {
+        "on" => Ok(true),
+        "off" => Ok(false),
+        _ => Err(format!("expected on or off, got {}", value)),
+    }
+}
+
+/// State of an interactive session. Modules and resources are loaded once and reused for every
+/// line that is run.
+pub struct Repl {
+    session: PipelineSession,
+    pipeline: Option<String>,
+    variables: BTreeMap<String, String>,
+    trace: bool,
+    time: bool,
+}
+
+impl Repl {
+    pub fn new(
+        session: PipelineSession,
+        pipeline: Option<String>,
+        variables: BTreeMap<String, String>,
+    ) -> Repl {
+        Repl {
+            session,
+            pipeline,
+            variables,
+            trace: false,
+            time: false,
+        }
+    }
+
+    /// Handle a line, returning the text to show and whether the session goes on
+    pub async fn handle(&mut self, line: String) -> Result<(String, bool), Box<dyn Error>> {
+        let mut out = String::new();
+
+        match ReplCommand::parse(&line)? {
+            ReplCommand::Help => out.push_str(REPL_HELP),
+            ReplCommand::Quit => return Ok((out, false)),
+            ReplCommand::Pipelines => {
+                for name in self.session.pipeline_names() {
+                    let marker = if self.pipeline.as_ref() == Some(&name) {
+                        "*"
+                    } else {
+                        " "
+                    };
+                    writeln!(out, "{} {}", marker, name)?;
+                }
+            }
+            ReplCommand::Pipeline(name) => {
+                self.session.file().pipeline(Some(&name))?;
+                self.pipeline = Some(name);
+            }
+            ReplCommand::Variables => {
+                for (key, value) in &self.variables {
+                    writeln!(out, "{}={}", key, value)?;
+                }
+            }
+            ReplCommand::Set(key, value) => {
+                self.variables.insert(key, value);
+            }
+            ReplCommand::Unset(key) => {
+                self.variables.remove(&key);
+            }
+            ReplCommand::Trace(trace) => self.trace = trace,
+            ReplCommand::Time(time) => self.time = time,
+            ReplCommand::Run(text) => out = self.run(text).await?,
+        }
+
+        Ok((out, true))
+    }
+
+    async fn run(&self, text: String) -> Result<String, Box<dyn Error>> {
+        let mut out = String::new();
+        let mut request = SessionRequest::new(text.into_bytes(), DataFormat::Text);
+        request.pipeline = self.pipeline.clone();
+        request.variables = self.variables.clone();
+
+        let recorder = Arc::new(CommandRecorder::new());
+        let output = self.session.run_observed(request, recorder.clone()).await?;
+
+        if self.trace || self.time {
+            for command in recorder.take_outputs() {
+                write!(
+                    out,
+                    "[{}] {}.{}",
+                    command.path, command.command.module, command.command.command
+                )?;
+                if self.time {
+                    write!(
+                        out,
+                        " {:.3}ms",
+                        command.duration.as_micros() as f64 / 1000.0
+                    )?;
+                }
+                writeln!(out)?;
+
+                if self.trace {
+                    let output_type = output_type(self.session.registry(), &command.command);
+                    match decode(&command.output, DataFormat::Text, output_type) {
+                        Ok(text) => writeln!(out, "    {}", String::from_utf8_lossy(&text))?,
+                        Err(_) => writeln!(out, "    <{} bytes>", command.output.len())?,
+                    }
+                }
+            }
+        }
+
+        out.push_str(&String::from_utf8_lossy(&output));
+        Ok(out)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_commands() {
+        assert_eq!(
+            ReplCommand::parse("Hello world\n"),
+            Ok(ReplCommand::Run("Hello world".to_string()))
+        );
+        assert_eq!(
+            ReplCommand::parse(":set grammar = se/debug.bin"),
+            Ok(ReplCommand::Set(
+                "grammar".to_string(),
+                "se/debug.bin".to_string()
+            ))
+        );
+        assert_eq!(ReplCommand::parse(":set"), Ok(ReplCommand::Variables));
+        assert_eq!(
+            ReplCommand::parse(":trace on"),
+            Ok(ReplCommand::Trace(true))
+        );
+        assert_eq!(
+            ReplCommand::parse(":pipeline spell"),
+            Ok(ReplCommand::Pipeline("spell".to_string()))
+        );
+        assert!(ReplCommand::parse(":trace maybe").is_err());
+        assert!(ReplCommand::parse(":set grammar").is_err());
+        assert!(ReplCommand::parse(":frobnicate").is_err());
+    }
+}
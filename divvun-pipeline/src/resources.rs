@@ -1,20 +1,155 @@
-use log::info;
+use log::{error, info};
 use memmap::{Mmap, MmapOptions};
-use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
 
 use std::sync::Arc;
 
 use std::{
     error::Error,
+    ffi::c_void,
+    fmt,
     fs::File,
     path::{Path, PathBuf},
+    str::FromStr,
 };
 
-use parking_lot::RwLock;
+use parking_lot::{Mutex, RwLock};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 use std::collections::HashMap;
 
+#[derive(Debug)]
+pub enum ResourceError {
+    NotFound(String),
+    LoadFailed {
+        name: String,
+        source: Box<dyn Error>,
+    },
+    /// Another resource with different data is already registered under the name
+    Conflict(String),
+    UnknownPolicy(String),
+}
+
+impl fmt::Display for ResourceError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ResourceError::NotFound(ref name) => write!(f, "resource {} not found", name),
+            ResourceError::LoadFailed {
+                ref name,
+                ref source,
+            } => write!(f, "load of resource {} failed: {}", name, source),
+            ResourceError::Conflict(ref name) => {
+                write!(f, "another resource named {} is already registered", name)
+            }
+            ResourceError::UnknownPolicy(ref name) => write!(
+                f,
+                "unknown residency policy {}, expected keep, preload, lru:<bytes> or on-demand",
+                name
+            ),
+        }
+    }
+}
+
+impl Error for ResourceError {}
+
+/// Describes a resource as listed in a pipeline manifest
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ResourceMetadata {
+    pub name: String,
+    /// Lowercase hex encoded SHA-256 of the resource data
+    pub sha256: String,
+    pub size: usize,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub media_type: Option<String>,
+    /// Name of the module that consumes the resource
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub module: Option<String>,
+}
+
+impl ResourceMetadata {
+    /// Metadata with the hash and size of `data`, the media type and module are filled in by
+    /// the packer from the manifest and the pipelines
+    pub fn new(name: &str, data: &[u8]) -> ResourceMetadata {
+        ResourceMetadata {
+            name: name.to_string(),
+            sha256: sha256_hex(data),
+            size: data.len(),
+            media_type: None,
+            module: None,
+        }
+    }
+
+    /// Check the size and hash of the data against the metadata
+    pub fn verify(&self, data: &[u8]) -> Result<(), IntegrityError> {
+        if data.len() != self.size {
+            return Err(IntegrityError::SizeMismatch {
+                expected: self.size,
+                actual: data.len(),
+            });
+        }
+
+        let actual = sha256_hex(data);
+        if !actual.eq_ignore_ascii_case(&self.sha256) {
+            return Err(IntegrityError::HashMismatch {
+                expected: self.sha256.clone(),
+                actual,
+            });
+        }
+
+        Ok(())
+    }
+}
+
+pub fn sha256_hex(data: &[u8]) -> String {
+    format!("{:x}", Sha256::digest(data))
+}
+
+#[derive(Debug)]
+pub enum IntegrityError {
+    SizeMismatch { expected: usize, actual: usize },
+    HashMismatch { expected: String, actual: String },
+}
+
+impl fmt::Display for IntegrityError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            IntegrityError::SizeMismatch { expected, actual } => write!(
+                f,
+                "resource is corrupt, expected {} bytes but found {}",
+                expected, actual
+            ),
+            IntegrityError::HashMismatch {
+                ref expected,
+                ref actual,
+            } => write!(
+                f,
+                "resource is corrupt, expected sha256 {} but found {}",
+                expected, actual
+            ),
+        }
+    }
+}
+
+impl Error for IntegrityError {}
+
 pub enum Resource {
-    File { path: PathBuf, mmap: Option<Mmap> },
+    File {
+        path: PathBuf,
+        mmap: Option<Mmap>,
+    },
+    /// A range of a file, e.g. an uncompressed entry of a zip archive
+    FileRange {
+        path: PathBuf,
+        offset: u64,
+        len: usize,
+        mmap: Option<Mmap>,
+    },
+    /// A range of a shared buffer, e.g. an uncompressed entry of an archive held in memory
+    SharedRange {
+        data: Arc<[u8]>,
+        offset: usize,
+        len: usize,
+    },
     Mmap(Mmap),
     Bytes(Vec<u8>),
 }
@@ -27,6 +162,21 @@ impl Resource {
         }
     }
 
+    pub fn new_file_range(path: &Path, offset: u64, len: usize) -> Resource {
+        Resource::FileRange {
+            path: path.to_owned(),
+            offset,
+            len,
+            mmap: None,
+        }
+    }
+
+    /// Panics if the range is out of the buffer's bounds
+    pub fn new_shared_range(data: Arc<[u8]>, offset: usize, len: usize) -> Resource {
+        assert!(offset.checked_add(len).map(|end| end <= data.len()) == Some(true));
+        Resource::SharedRange { data, offset, len }
+    }
+
     pub fn load(&mut self) -> Result<(), Box<dyn Error>> {
         info!("load");
         match self {
@@ -36,6 +186,18 @@ impl Resource {
                 *mmap = Some(unsafe { MmapOptions::new().map(&file)? });
                 Ok(())
             }
+            Resource::FileRange {
+                path,
+                offset,
+                len,
+                ref mut mmap,
+            } => {
+                info!("loading {} bytes at {} of {}", len, offset, path.display());
+                let file = File::open(path)?;
+                *mmap = Some(unsafe { MmapOptions::new().offset(*offset).len(*len).map(&file)? });
+                Ok(())
+            }
+            Resource::SharedRange { .. } => Ok(()),
             Resource::Bytes(_) => Ok(()),
             Resource::Mmap(_) => Ok(()),
         }
@@ -43,74 +205,274 @@ impl Resource {
 
     pub fn unload(&mut self) -> Result<(), Box<dyn Error>> {
         match self {
-            Resource::File { ref mut mmap, .. } => {
+            Resource::File { ref mut mmap, .. } | Resource::FileRange { ref mut mmap, .. } => {
                 let _ = mmap.take();
                 Ok(())
             }
+            Resource::SharedRange { .. } => Ok(()),
             Resource::Bytes(_) => Ok(()),
             Resource::Mmap(_) => Ok(()),
         }
     }
 
-    pub fn size(&self) -> Option<usize> {
+    pub fn is_loaded(&self) -> bool {
+        match self {
+            Resource::File { ref mmap, .. } | Resource::FileRange { ref mmap, .. } => {
+                mmap.is_some()
+            }
+            Resource::SharedRange { .. } => true,
+            Resource::Bytes(_) => true,
+            Resource::Mmap(_) => true,
+        }
+    }
+
+    /// Size of the memory that is given back by unloading the resource
+    pub fn resident_size(&self) -> usize {
         match self {
-            Resource::File { ref mmap, .. } => mmap.as_ref().map(|mmap| mmap.len()),
-            Resource::Bytes(vec) => Some(vec.len()),
-            Resource::Mmap(mmap) => Some(mmap.len()),
+            Resource::File { ref mmap, .. } | Resource::FileRange { ref mmap, .. } => {
+                mmap.as_ref().map(|mmap| mmap.len()).unwrap_or(0)
+            }
+            Resource::SharedRange { .. } => 0,
+            Resource::Bytes(_) => 0,
+            Resource::Mmap(_) => 0,
         }
     }
 
+    pub fn size(&self) -> Option<usize> {
+        self.as_slice().map(|slice| slice.len())
+    }
+
     pub fn as_ptr(&self) -> Option<*const u8> {
+        self.as_slice().map(|slice| slice.as_ptr())
+    }
+
+    pub fn as_slice(&self) -> Option<&[u8]> {
         match self {
-            Resource::File { ref mmap, .. } => mmap.as_ref().map(|mmap| mmap.as_ptr()),
-            Resource::Bytes(vec) => Some(vec.as_ptr()),
-            Resource::Mmap(mmap) => Some(mmap.as_ptr()),
+            Resource::File { ref mmap, .. } | Resource::FileRange { ref mmap, .. } => {
+                mmap.as_ref().map(|mmap| &mmap[..])
+            }
+            Resource::SharedRange {
+                ref data,
+                offset,
+                len,
+            } => Some(&data[*offset..*offset + *len]),
+            Resource::Bytes(vec) => Some(&vec[..]),
+            Resource::Mmap(mmap) => Some(&mmap[..]),
+        }
+    }
+}
+
+/// Decides when loaded resources get unloaded again
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ResidencyPolicy {
+    /// Load on the first claim and unload as soon as the resource is no longer claimed
+    OnDemand,
+    /// Load every registered resource up front and never unload
+    PreloadAll,
+    /// Load on the first claim and keep the resource loaded afterwards
+    KeepResident,
+    /// Keep unclaimed resources loaded, unloading the least recently used ones once the loaded
+    /// file resources exceed the memory budget in bytes
+    Lru { memory_budget: usize },
+}
+
+impl Default for ResidencyPolicy {
+    fn default() -> Self {
+        ResidencyPolicy::OnDemand
+    }
+}
+
+impl FromStr for ResidencyPolicy {
+    type Err = ResourceError;
+
+    fn from_str(name: &str) -> Result<ResidencyPolicy, ResourceError> {
+        match name {
+            "on-demand" => Ok(ResidencyPolicy::OnDemand),
+            "preload" => Ok(ResidencyPolicy::PreloadAll),
+            "keep" => Ok(ResidencyPolicy::KeepResident),
+            _ if name.starts_with("lru:") => name["lru:".len()..]
+                .parse()
+                .map(|memory_budget| ResidencyPolicy::Lru { memory_budget })
+                .map_err(|_| ResourceError::UnknownPolicy(name.to_string())),
+            _ => Err(ResourceError::UnknownPolicy(name.to_string())),
         }
     }
 }
 
+/// Frees an object attached to a resource
+pub type ObjectDestructor = extern "C" fn(*mut c_void);
+
+/// Identifies the module that attached an object, whose library holds the destructor
+pub type ObjectOwner = usize;
+
+/// An object owned by a module that was built from the resource data, e.g. a parsed grammar.
+struct AttachedObject {
+    owner: ObjectOwner,
+    object: *mut c_void,
+    destructor: ObjectDestructor,
+}
+
+unsafe impl Send for AttachedObject {}
+unsafe impl Sync for AttachedObject {}
+
+impl Drop for AttachedObject {
+    fn drop(&mut self) {
+        (self.destructor)(self.object);
+    }
+}
+
 pub struct LoadableResource {
     resource: RwLock<Resource>,
+    metadata: Option<ResourceMetadata>,
+    verified: AtomicBool,
     ref_counter: AtomicUsize,
+    last_used: AtomicU64,
+    objects: Mutex<HashMap<String, AttachedObject>>,
 }
 
 impl LoadableResource {
-    pub fn claim(&self) {
-        let last_ref = self.ref_counter.fetch_add(1, Ordering::SeqCst);
-        if last_ref == 0 {
-            self.resource
-                .write()
-                .load()
-                .expect("load of resource failed");
+    /// A resource that is checked against the size and hash of its metadata the first time
+    /// it gets loaded
+    pub fn with_metadata(resource: Resource, metadata: ResourceMetadata) -> LoadableResource {
+        let mut loadable_resource = LoadableResource::from(resource);
+        loadable_resource.metadata = Some(metadata);
+        loadable_resource
+    }
+
+    pub fn metadata(&self) -> Option<&ResourceMetadata> {
+        self.metadata.as_ref()
+    }
+
+    fn load(&self, resource: &mut Resource) -> Result<(), Box<dyn Error>> {
+        if !resource.is_loaded() {
+            resource.load()?;
+        }
+
+        if let Some(ref metadata) = self.metadata {
+            if !self.verified.load(Ordering::SeqCst) {
+                let result = metadata.verify(resource.as_slice().unwrap_or(&[]));
+                if let Err(e) = result {
+                    resource.unload()?;
+                    return Err(e.into());
+                }
+                self.verified.store(true, Ordering::SeqCst);
+            }
         }
+
+        Ok(())
+    }
+
+    /// Increase the reference count, loading the resource if it isn't loaded yet.
+    /// The count is left untouched if the load fails.
+    pub fn claim(&self) -> Result<(), Box<dyn Error>> {
+        let mut resource = self.resource.write();
+        self.load(&mut resource)?;
+        self.ref_counter.fetch_add(1, Ordering::SeqCst);
+        Ok(())
     }
 
-    pub fn release(&self) {
+    /// Decrease the reference count, unloading the resource once it is no longer claimed
+    /// if `unload` is set.
+    pub fn release(&self, unload: bool) -> Result<(), Box<dyn Error>> {
+        let mut resource = self.resource.write();
         let last_ref = self.ref_counter.fetch_sub(1, Ordering::SeqCst);
-        if last_ref == 1 {
-            self.resource
-                .write()
-                .unload()
-                .expect("unload of resource failed");
+        if last_ref == 1 && unload {
+            self.objects.lock().clear();
+            resource.unload()?;
         }
+        Ok(())
     }
 
-    pub fn is_loaded(&self) -> bool {
+    /// Load the resource without claiming it
+    pub fn preload(&self) -> Result<(), Box<dyn Error>> {
+        let mut resource = self.resource.write();
+        self.load(&mut resource)
+    }
+
+    /// Unload the resource and free its attached objects if nobody currently holds a claim
+    /// on it. Returns whether any mapped memory was given back.
+    pub fn unload_unclaimed(&self) -> Result<bool, Box<dyn Error>> {
+        let mut resource = self.resource.write();
+        if self.ref_counter.load(Ordering::SeqCst) > 0 {
+            return Ok(false);
+        }
+        self.objects.lock().clear();
+        if resource.resident_size() == 0 {
+            return Ok(false);
+        }
+        resource.unload()?;
+        Ok(true)
+    }
+
+    /// Free the objects attached by `owner`, claimed or not, keeping the resource loaded
+    pub fn free_objects_of(&self, owner: ObjectOwner) {
+        self.objects
+            .lock()
+            .retain(|_, attached| attached.owner != owner);
+    }
+
+    /// The object attached under `key`, only valid while the resource is claimed
+    pub fn object(&self, key: &str) -> Option<*mut c_void> {
+        self.objects.lock().get(key).map(|attached| attached.object)
+    }
+
+    /// Attach an object to the resource, to be freed with `destructor` once the resource is
+    /// unloaded. If an object is already attached under `key`, the new object is freed and the
+    /// existing one returned.
+    pub fn attach_object(
+        &self,
+        owner: ObjectOwner,
+        key: &str,
+        object: *mut c_void,
+        destructor: ObjectDestructor,
+    ) -> *mut c_void {
+        let attached = AttachedObject {
+            owner,
+            object,
+            destructor,
+        };
+        self.objects
+            .lock()
+            .entry(key.to_string())
+            .or_insert(attached)
+            .object
+    }
+
+    /// Whether a module currently holds the resource, see `is_resident` for whether it is loaded
+    pub fn is_claimed(&self) -> bool {
         self.ref_counter.load(Ordering::SeqCst) > 0
     }
+
+    /// Same as `is_claimed`, the resource may be loaded without being claimed
+    pub fn is_loaded(&self) -> bool {
+        self.is_claimed()
+    }
+
+    pub fn is_resident(&self) -> bool {
+        self.resource.read().is_loaded()
+    }
+
+    pub fn resident_size(&self) -> usize {
+        self.resource.read().resident_size()
+    }
 }
 
 impl From<Resource> for LoadableResource {
     fn from(resource: Resource) -> Self {
         LoadableResource {
             resource: RwLock::new(resource),
+            metadata: None,
+            verified: AtomicBool::new(false),
             ref_counter: AtomicUsize::new(0),
+            last_used: AtomicU64::new(0),
+            objects: Mutex::new(HashMap::new()),
         }
     }
 }
 
 pub struct ResourceHandle {
     loadable_resource: Arc<LoadableResource>,
+    registry: Arc<RegistryInner>,
 }
 
 impl ResourceHandle {
@@ -121,47 +483,276 @@ impl ResourceHandle {
     pub fn as_ptr(&self) -> Option<*const u8> {
         self.loadable_resource.resource.read().as_ptr()
     }
+
+    pub fn object(&self, key: &str) -> Option<*mut c_void> {
+        self.loadable_resource.object(key)
+    }
+
+    pub fn attach_object(
+        &self,
+        owner: ObjectOwner,
+        key: &str,
+        object: *mut c_void,
+        destructor: ObjectDestructor,
+    ) -> *mut c_void {
+        self.loadable_resource
+            .attach_object(owner, key, object, destructor)
+    }
 }
 
 impl Drop for ResourceHandle {
     fn drop(&mut self) {
-        self.loadable_resource.release();
+        self.registry.release(&self.loadable_resource);
     }
 }
 
-pub struct ResourceRegistry {
+struct RegistryInner {
     available: RwLock<HashMap<String, Arc<LoadableResource>>>,
+    policy: RwLock<ResidencyPolicy>,
+    clock: AtomicU64,
+}
+
+impl RegistryInner {
+    fn touch(&self, resource: &LoadableResource) {
+        let tick = self.clock.fetch_add(1, Ordering::SeqCst);
+        resource.last_used.store(tick, Ordering::SeqCst);
+    }
+
+    fn release(&self, resource: &LoadableResource) {
+        self.touch(resource);
+        let policy = *self.policy.read();
+        if let Err(e) = resource.release(policy == ResidencyPolicy::OnDemand) {
+            error!("unload of resource failed: {}", e);
+        }
+        self.enforce_budget();
+    }
+
+    /// Unload the least recently used unclaimed resources until the budget is met
+    fn enforce_budget(&self) {
+        let memory_budget = match *self.policy.read() {
+            ResidencyPolicy::Lru { memory_budget } => memory_budget,
+            _ => return,
+        };
+
+        let mut resources = self.available.read().values().cloned().collect::<Vec<_>>();
+        let mut resident_size: usize = resources.iter().map(|res| res.resident_size()).sum();
+        if resident_size <= memory_budget {
+            return;
+        }
+
+        resources.sort_by_key(|res| res.last_used.load(Ordering::SeqCst));
+        for resource in resources {
+            if resident_size <= memory_budget {
+                break;
+            }
+
+            let size = resource.resident_size();
+            if size == 0 {
+                continue;
+            }
+
+            match resource.unload_unclaimed() {
+                Ok(true) => {
+                    info!("evicted resource of {} bytes", size);
+                    resident_size -= size;
+                }
+                Ok(false) => {}
+                Err(e) => error!("unload of resource failed: {}", e),
+            }
+        }
+    }
+}
+
+pub struct ResourceRegistry {
+    inner: Arc<RegistryInner>,
 }
 
 impl ResourceRegistry {
     pub fn new() -> ResourceRegistry {
+        Self::with_policy(ResidencyPolicy::default())
+    }
+
+    pub fn with_policy(policy: ResidencyPolicy) -> ResourceRegistry {
         ResourceRegistry {
-            available: RwLock::new(HashMap::new()),
+            inner: Arc::new(RegistryInner {
+                available: RwLock::new(HashMap::new()),
+                policy: RwLock::new(policy),
+                clock: AtomicU64::new(0),
+            }),
+        }
+    }
+
+    pub fn policy(&self) -> ResidencyPolicy {
+        *self.inner.policy.read()
+    }
+
+    /// Change the residency policy. Switching to `PreloadAll` loads every registered resource,
+    /// switching to `OnDemand` or `Lru` unloads what is no longer allowed to stay loaded.
+    pub fn set_policy(&self, policy: ResidencyPolicy) -> Result<(), ResourceError> {
+        *self.inner.policy.write() = policy;
+
+        match policy {
+            ResidencyPolicy::PreloadAll => self.preload_all(),
+            ResidencyPolicy::OnDemand => {
+                for resource in self.inner.available.read().values() {
+                    if let Err(e) = resource.unload_unclaimed() {
+                        error!("unload of resource failed: {}", e);
+                    }
+                }
+                Ok(())
+            }
+            ResidencyPolicy::KeepResident => Ok(()),
+            ResidencyPolicy::Lru { .. } => {
+                self.inner.enforce_budget();
+                Ok(())
+            }
         }
     }
 
     pub fn add_resource(&self, name: &str, resource: LoadableResource) {
-        self.available
+        let resource = Arc::new(resource);
+        self.inner
+            .available
             .write()
-            .insert(name.to_string(), Arc::new(resource));
+            .insert(name.to_string(), resource.clone());
+
+        if self.policy() == ResidencyPolicy::PreloadAll {
+            if let Err(e) = resource.preload() {
+                error!("preload of resource {} failed: {}", name, e);
+            }
+        }
     }
 
-    pub fn get(&self, name: &str) -> Option<ResourceHandle> {
-        let lock = self.available.read();
-        let resource = lock.get(name)?;
-        resource.claim();
-        Some(ResourceHandle {
-            loadable_resource: resource.clone(),
+    /// Share the resources of `other` under the same names. A name that is already registered
+    /// is only accepted again for a resource with the same hash, nothing is added otherwise.
+    pub fn add_resources_from(&self, other: &ResourceRegistry) -> Result<(), ResourceError> {
+        let resources = other.inner.available.read().clone();
+        let mut available = self.inner.available.write();
+        for (name, resource) in &resources {
+            if let Some(existing) = available.get(name) {
+                let same = match (existing.metadata(), resource.metadata()) {
+                    (Some(a), Some(b)) => a.sha256 == b.sha256,
+                    _ => Arc::ptr_eq(existing, resource),
+                };
+                if !same {
+                    error!(
+                        "Resource {} is already registered with different data",
+                        name
+                    );
+                    return Err(ResourceError::Conflict(name.clone()));
+                }
+            }
+        }
+
+        for (name, resource) in resources {
+            available.entry(name).or_insert(resource);
+        }
+        Ok(())
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.inner.available.read().contains_key(name)
+    }
+
+    pub fn metadata(&self, name: &str) -> Option<ResourceMetadata> {
+        self.inner
+            .available
+            .read()
+            .get(name)
+            .and_then(|resource| resource.metadata().cloned())
+    }
+
+    pub fn get(&self, name: &str) -> Result<ResourceHandle, ResourceError> {
+        let resource = self
+            .inner
+            .available
+            .read()
+            .get(name)
+            .cloned()
+            .ok_or_else(|| ResourceError::NotFound(name.to_string()))?;
+        resource
+            .claim()
+            .map_err(|source| ResourceError::LoadFailed {
+                name: name.to_string(),
+                source,
+            })?;
+        self.inner.touch(&resource);
+        self.inner.enforce_budget();
+
+        Ok(ResourceHandle {
+            loadable_resource: resource,
+            registry: self.inner.clone(),
         })
     }
 
-    pub fn loaded_resources_count(&self) -> usize {
-        self.available
+    /// Load the named resources ahead of their first claim, so the first run doesn't pay for
+    /// mapping them. Whether they stay loaded is up to the residency policy.
+    pub fn warm<'a, I: IntoIterator<Item = &'a str>>(&self, names: I) -> Result<(), ResourceError> {
+        for name in names {
+            let resource = self
+                .inner
+                .available
+                .read()
+                .get(name)
+                .cloned()
+                .ok_or_else(|| ResourceError::NotFound(name.to_string()))?;
+            resource
+                .preload()
+                .map_err(|source| ResourceError::LoadFailed {
+                    name: name.to_string(),
+                    source,
+                })?;
+            self.inner.touch(&resource);
+        }
+
+        self.inner.enforce_budget();
+        Ok(())
+    }
+
+    /// Load every registered resource
+    pub fn preload_all(&self) -> Result<(), ResourceError> {
+        let names = self
+            .inner
+            .available
+            .read()
+            .keys()
+            .cloned()
+            .collect::<Vec<_>>();
+        self.warm(names.iter().map(|name| &**name))
+    }
+
+    /// Free every object attached by `owner`. Their destructors are part of the module that
+    /// attached them, so this has to happen before the module is unloaded.
+    pub fn free_objects_of(&self, owner: ObjectOwner) {
+        for resource in self.inner.available.read().values() {
+            resource.free_objects_of(owner);
+        }
+    }
+
+    /// Number of resources a module currently holds
+    pub fn claimed_resources_count(&self) -> usize {
+        self.inner
+            .available
             .read()
             .values()
-            .filter(|res| res.is_loaded())
+            .filter(|res| res.is_claimed())
             .count()
     }
+
+    /// Same as `claimed_resources_count`
+    pub fn loaded_resources_count(&self) -> usize {
+        self.claimed_resources_count()
+    }
+
+    /// Number of bytes of file resources currently mapped into memory
+    pub fn resident_size(&self) -> usize {
+        self.inner
+            .available
+            .read()
+            .values()
+            .map(|res| res.resident_size())
+            .sum()
+    }
 }
 
 #[cfg(test)]
@@ -186,4 +777,230 @@ mod tests {
         }
         assert_eq!(registry.loaded_resources_count(), 0);
     }
+
+    #[test]
+    fn resources_concurrent_claims() {
+        let _ = env_logger::builder().is_test(true).try_init();
+
+        let registry = Arc::new(ResourceRegistry::new());
+        let my_data = "Hello".as_bytes();
+        registry.add_resource(
+            "lol",
+            LoadableResource::from(Resource::Bytes(my_data.to_owned())),
+        );
+
+        let threads = (0..8)
+            .map(|_| {
+                let registry = registry.clone();
+                std::thread::spawn(move || {
+                    for _ in 0..100 {
+                        let first = registry.get("lol").expect("resource");
+                        let second = registry.get("lol").expect("resource");
+                        drop(first);
+                        assert_eq!(second.size().unwrap(), 5);
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+
+        for thread in threads {
+            thread.join().unwrap();
+        }
+
+        assert_eq!(registry.loaded_resources_count(), 0);
+    }
+
+    fn file_resource(dir: &Path, name: &str, size: usize) -> LoadableResource {
+        let path = dir.join(name);
+        std::fs::write(&path, vec![0u8; size]).unwrap();
+        LoadableResource::from(Resource::new_file(&path))
+    }
+
+    #[test]
+    fn resources_keep_resident() {
+        let dir = tempfile::tempdir().unwrap();
+        let registry = ResourceRegistry::with_policy(ResidencyPolicy::KeepResident);
+        registry.add_resource("a", file_resource(dir.path(), "a", 10));
+
+        assert_eq!(registry.resident_size(), 0);
+        drop(registry.get("a").expect("resource"));
+        assert_eq!(registry.loaded_resources_count(), 0);
+        assert_eq!(registry.resident_size(), 10);
+
+        registry.set_policy(ResidencyPolicy::OnDemand).unwrap();
+        assert_eq!(registry.resident_size(), 0);
+    }
+
+    #[test]
+    fn resources_preload_all() {
+        let dir = tempfile::tempdir().unwrap();
+        let registry = ResourceRegistry::new();
+        registry.add_resource("a", file_resource(dir.path(), "a", 10));
+        registry.add_resource("b", file_resource(dir.path(), "b", 20));
+
+        registry.set_policy(ResidencyPolicy::PreloadAll).unwrap();
+        assert_eq!(registry.resident_size(), 30);
+
+        registry.add_resource("c", file_resource(dir.path(), "c", 5));
+        assert_eq!(registry.resident_size(), 35);
+    }
+
+    #[test]
+    fn resources_lru_eviction() {
+        let dir = tempfile::tempdir().unwrap();
+        let registry = ResourceRegistry::with_policy(ResidencyPolicy::Lru { memory_budget: 25 });
+        registry.add_resource("a", file_resource(dir.path(), "a", 10));
+        registry.add_resource("b", file_resource(dir.path(), "b", 10));
+        registry.add_resource("c", file_resource(dir.path(), "c", 10));
+
+        drop(registry.get("a").expect("resource"));
+        drop(registry.get("b").expect("resource"));
+        assert_eq!(registry.resident_size(), 20);
+
+        // Claimed resources are never evicted, "a" is the least recently used
+        let c = registry.get("c").expect("resource");
+        let b = registry.get("b").expect("resource");
+        assert_eq!(registry.resident_size(), 20);
+        assert_eq!(c.size().unwrap(), 10);
+        drop(b);
+        drop(c);
+
+        registry.warm(vec!["a"]).unwrap();
+        assert_eq!(registry.resident_size(), 20);
+    }
+
+    static DESTROYED: AtomicUsize = AtomicUsize::new(0);
+
+    extern "C" fn destroy_object(object: *mut c_void) {
+        unsafe { drop(Box::from_raw(object as *mut usize)) };
+        DESTROYED.fetch_add(1, Ordering::SeqCst);
+    }
+
+    #[test]
+    fn resources_attached_objects() {
+        let dir = tempfile::tempdir().unwrap();
+        let registry = ResourceRegistry::with_policy(ResidencyPolicy::KeepResident);
+        registry.add_resource("a", file_resource(dir.path(), "a", 10));
+
+        {
+            let handle = registry.get("a").expect("resource");
+            assert!(handle.object("parsed").is_none());
+
+            let first = Box::into_raw(Box::new(1usize)) as *mut c_void;
+            assert_eq!(
+                handle.attach_object(1, "parsed", first, destroy_object),
+                first
+            );
+
+            // A second attach under the same key keeps the first object
+            let second = Box::into_raw(Box::new(2usize)) as *mut c_void;
+            assert_eq!(
+                handle.attach_object(1, "parsed", second, destroy_object),
+                first
+            );
+            assert_eq!(DESTROYED.load(Ordering::SeqCst), 1);
+        }
+
+        // Kept across claims while the resource stays resident
+        let handle = registry.get("a").expect("resource");
+        let object = handle.object("parsed").expect("object");
+        assert_eq!(unsafe { *(object as *const usize) }, 1);
+
+        // Unloading a module frees its objects even while the resource is claimed, and only
+        // those of that module
+        let other = Box::into_raw(Box::new(3usize)) as *mut c_void;
+        handle.attach_object(2, "other", other, destroy_object);
+        registry.free_objects_of(1);
+        assert_eq!(DESTROYED.load(Ordering::SeqCst), 2);
+        assert!(handle.object("parsed").is_none());
+        assert_eq!(handle.object("other"), Some(other));
+        assert_eq!(registry.resident_size(), 10);
+        drop(handle);
+
+        registry.set_policy(ResidencyPolicy::OnDemand).unwrap();
+        assert_eq!(DESTROYED.load(Ordering::SeqCst), 3);
+        let handle = registry.get("a").expect("resource");
+        assert!(handle.object("parsed").is_none());
+    }
+
+    #[test]
+    fn resources_policy_names() {
+        assert_eq!(
+            "keep".parse::<ResidencyPolicy>().unwrap(),
+            ResidencyPolicy::KeepResident
+        );
+        assert_eq!(
+            "on-demand".parse::<ResidencyPolicy>().unwrap(),
+            ResidencyPolicy::OnDemand
+        );
+        assert_eq!(
+            "preload".parse::<ResidencyPolicy>().unwrap(),
+            ResidencyPolicy::PreloadAll
+        );
+        assert_eq!(
+            "lru:1048576".parse::<ResidencyPolicy>().unwrap(),
+            ResidencyPolicy::Lru {
+                memory_budget: 1048576
+            }
+        );
+        assert!("lru:1M".parse::<ResidencyPolicy>().is_err());
+        assert!("always".parse::<ResidencyPolicy>().is_err());
+    }
+
+    #[test]
+    fn resources_verified_on_first_load() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("data");
+        std::fs::write(&path, "some bytes before hello").unwrap();
+
+        let registry = ResourceRegistry::new();
+        registry.add_resource(
+            "good",
+            LoadableResource::with_metadata(
+                Resource::new_file_range(&path, 18, 5),
+                ResourceMetadata::new("good", b"hello"),
+            ),
+        );
+        registry.add_resource(
+            "corrupt",
+            LoadableResource::with_metadata(
+                Resource::new_file_range(&path, 0, 5),
+                ResourceMetadata::new("corrupt", b"hello"),
+            ),
+        );
+
+        let handle = registry.get("good").expect("resource");
+        assert_eq!(handle.size().unwrap(), 5);
+        drop(handle);
+
+        match registry.get("corrupt") {
+            Err(ResourceError::LoadFailed { source, .. }) => {
+                assert!(source.downcast_ref::<IntegrityError>().is_some())
+            }
+            _ => panic!("expected integrity failure"),
+        }
+        assert_eq!(registry.resident_size(), 0);
+    }
+
+    #[test]
+    fn resources_missing_file() {
+        let _ = env_logger::builder().is_test(true).try_init();
+
+        let registry = ResourceRegistry::new();
+        registry.add_resource(
+            "missing",
+            LoadableResource::from(Resource::new_file(Path::new("does/not/exist.bin"))),
+        );
+
+        match registry.get("missing") {
+            Err(ResourceError::LoadFailed { name, .. }) => assert_eq!(name, "missing"),
+            _ => panic!("expected load failure"),
+        }
+        assert_eq!(registry.loaded_resources_count(), 0);
+
+        match registry.get("unknown") {
+            Err(ResourceError::NotFound(name)) => assert_eq!(name, "unknown"),
+            _ => panic!("expected missing resource"),
+        }
+    }
 }
@@ -1,29 +1,69 @@
 use crate::{
-    module::{AllocationType, ModuleAllocator, ModuleRegistry},
-    pipeline::{Pipeline, PipelineData},
+    convert::{self, string_type_id, ConvertError, DataFormat},
+    executor::PipelineExecutor,
+    manifest::{check_module_requirements, ModuleRequirement},
+    module::{module_search_paths, AllocationType, ModuleAllocator, ModuleRegistry},
+    pipeline::{Pipeline, PipelineCommand, PipelineData},
     resources::ResourceRegistry,
+    trace::{PipelineTracer, TraceFormat},
 };
 use capnp::{message::ReaderOptions, serialize};
 use divvun_schema::string_capnp::string;
-use log::info;
+use futures::executor::block_on;
+use log::{info, warn};
 use std::{
+    collections::BTreeMap,
+    error::Error,
     io::{Cursor, Read},
     path::PathBuf,
     sync::Arc,
 };
 
-const DEFAULT_MODULE_SEARCH_PATH: &str = "modules";
+pub const DEFAULT_MODULE_SEARCH_PATH: &str = "modules";
 
 #[derive(Builder)]
 #[builder(pattern = "owned")]
 pub struct PipelineRunConfiguration {
-    #[builder(default = PathBuf::from(DEFAULT_MODULE_SEARCH_PATH))]
-    module_search_path: PathBuf,
+    /// Directories modules are looked up in, in order. Those of `module::module_search_paths`,
+    /// from the environment and the config files, if none are given.
+    #[builder(default)]
+    module_search_paths: Vec<PathBuf>,
     pipeline: Pipeline,
     resources: Arc<ResourceRegistry>,
     input: Vec<u8>,
     #[builder(default = "AllocationType::Memory")]
     allocation_type: AllocationType,
+    /// Modules that have to be available before the pipeline is run
+    #[builder(default)]
+    required_modules: Vec<ModuleRequirement>,
+    /// Values for `${name}` placeholders in module names and parameters of the pipeline
+    #[builder(default)]
+    variables: BTreeMap<String, String>,
+    /// Format of `input`, converted to the input type of the pipeline's first command
+    #[builder(default = "DataFormat::Capnp")]
+    input_format: DataFormat,
+    /// Format the output of the pipeline's last command is converted to
+    #[builder(default = "DataFormat::Capnp")]
+    output_format: DataFormat,
+    /// File or directory to write a record of every command's output to
+    #[builder(default)]
+    trace: Option<PathBuf>,
+    #[builder(default)]
+    trace_format: TraceFormat,
+    /// Where module calls run, on the thread polling the run if not given
+    #[builder(default)]
+    executor: Option<Arc<dyn PipelineExecutor>>,
+}
+
+impl PipelineRunConfigurationBuilder {
+    /// Add a directory to look up modules in, after the ones added before it. Replaces the
+    /// default search paths.
+    pub fn module_search_path(mut self, path: PathBuf) -> Self {
+        self.module_search_paths
+            .get_or_insert_with(Vec::new)
+            .push(path);
+        self
+    }
 }
 
 pub struct PipelineRunOutput {
@@ -32,51 +72,175 @@ pub struct PipelineRunOutput {
 }
 
 impl PipelineRunConfiguration {
-    pub async fn run(&self) -> PipelineRunOutput {
+    pub async fn run(&self) -> Result<PipelineRunOutput, Box<dyn Error>> {
+        let search_paths = if self.module_search_paths.is_empty() {
+            module_search_paths(&[])?
+        } else {
+            self.module_search_paths.clone()
+        };
         let allocator = Arc::new(ModuleAllocator::new(self.allocation_type));
-        let mut registry =
-            ModuleRegistry::new(Arc::clone(&allocator), Arc::clone(&self.resources)).unwrap();
-        registry.add_search_path(&self.module_search_path);
-        let registry = Arc::new(registry);
-
-        let result = self
-            .pipeline
-            .run(
-                registry.clone(),
-                Arc::new(vec![Arc::new(PipelineData {
-                    data: self.input.as_ptr(),
-                    size: self.input.len(),
-                })]),
-            )
-            .await;
-
-        let inter_output = result.unwrap();
-        let output = inter_output.get(0).unwrap();
+        let registry = Arc::new(module_registry(
+            Arc::clone(&allocator),
+            Arc::clone(&self.resources),
+            &search_paths,
+            &self.required_modules,
+        )?);
+
+        let pipeline = self.pipeline.with_variables(&self.variables);
+        pipeline.check_variables()?;
+        let input = match self.input_format {
+            DataFormat::Capnp => None,
+            format => Some(encode_input(&registry, &pipeline, &self.input, format)?),
+        };
+        let input = input.as_ref().unwrap_or(&self.input);
+
+        let input = Arc::new(vec![Arc::new(PipelineData {
+            data: input.as_ptr(),
+            size: input.len(),
+        })]);
+
+        let result = match self.trace {
+            Some(ref path) => {
+                let tracer = Arc::new(PipelineTracer::create(
+                    path,
+                    self.trace_format,
+                    registry.clone(),
+                    allocator.clone(),
+                )?);
+                let result = pipeline
+                    .run_with(
+                        registry.clone(),
+                        input,
+                        Some(tracer.clone()),
+                        self.executor.clone(),
+                    )
+                    .await;
+                // The trace is most useful when the run failed, so it is written out regardless
+                tracer.finish()?;
+                result
+            }
+            None => {
+                pipeline
+                    .run_with(registry.clone(), input, None, self.executor.clone())
+                    .await
+            }
+        };
+
+        let inter_output = result?;
+        let output = inter_output.get(0).ok_or("pipeline produced no output")?;
 
         let output_data = output.data;
         let output_size = output.size;
 
         let slice = unsafe { std::slice::from_raw_parts(output_data, output_size) };
         info!("output size {}", output_size);
-        let cursor = Cursor::new(slice);
 
-        PipelineRunOutput {
-            allocator,
-            output: Box::new(cursor),
-        }
+        let output: Box<dyn Read> = match self.output_format {
+            DataFormat::Capnp => Box::new(Cursor::new(slice)),
+            format => Box::new(Cursor::new(decode_output(
+                &registry, &pipeline, slice, format,
+            )?)),
+        };
+
+        Ok(PipelineRunOutput { allocator, output })
+    }
+
+    /// Run the pipeline on the current thread, for callers without an async executor
+    pub fn run_blocking(&self) -> Result<PipelineRunOutput, Box<dyn Error>> {
+        block_on(self.run())
+    }
+}
+
+/// Module registry for running a pipeline, with the required modules checked to be available
+pub fn module_registry(
+    allocator: Arc<ModuleAllocator>,
+    resources: Arc<ResourceRegistry>,
+    module_search_paths: &[PathBuf],
+    required_modules: &[ModuleRequirement],
+) -> Result<ModuleRegistry, Box<dyn Error>> {
+    let mut registry = ModuleRegistry::new(allocator, resources)?;
+    for path in module_search_paths {
+        registry.add_search_path(path);
     }
+    check_module_requirements(&registry, required_modules)?;
+    Ok(registry)
+}
+
+/// Convert input in the given format to the input type of the pipeline's first command
+pub fn encode_input(
+    registry: &ModuleRegistry,
+    pipeline: &Pipeline,
+    input: &[u8],
+    format: DataFormat,
+) -> Result<Vec<u8>, ConvertError> {
+    let input_type = pipeline
+        .commands()
+        .first()
+        .and_then(|command| command_types(registry, &command.module, &command.command))
+        .and_then(|(inputs, _)| inputs.first().cloned())
+        .unwrap_or_else(string_type_id);
+    convert::encode(input, format, input_type)
+}
+
+/// Convert the output of the pipeline's last command to the given format
+pub fn decode_output(
+    registry: &ModuleRegistry,
+    pipeline: &Pipeline,
+    output: &[u8],
+    format: DataFormat,
+) -> Result<Vec<u8>, ConvertError> {
+    let output_type = pipeline
+        .commands()
+        .last()
+        .map(|command| output_type(registry, command))
+        .unwrap_or_else(string_type_id);
+    convert::decode(output, format, output_type)
+}
+
+/// The output type of a command, assuming text if its module doesn't list it
+pub fn output_type(registry: &ModuleRegistry, command: &PipelineCommand) -> u64 {
+    command_types(registry, &command.module, &command.command)
+        .map(|(_, output)| output)
+        .unwrap_or_else(string_type_id)
+}
+
+/// The types of a command from its module's metadata, assuming text if it isn't listed
+pub(crate) fn command_types(
+    registry: &ModuleRegistry,
+    module: &str,
+    command: &str,
+) -> Option<(Vec<u64>, u64)> {
+    let types = registry
+        .get_module(module)
+        .ok()
+        .and_then(|module| module.command_types(command));
+    if types.is_none() {
+        warn!(
+            "Module {} does not list the types of {}, assuming String",
+            module, command
+        );
+    }
+    types
 }
 
 pub async fn run(
     pipeline: Pipeline,
     resources: Arc<ResourceRegistry>,
     input: Vec<u8>,
-) -> PipelineRunOutput {
+) -> Result<PipelineRunOutput, Box<dyn Error>> {
     let pipeline = PipelineRunConfigurationBuilder::default()
         .pipeline(pipeline)
         .resources(resources)
         .input(input)
-        .build()
-        .unwrap();
+        .build()?;
     pipeline.run().await
 }
+
+/// Like `run`, blocking the current thread until the pipeline is done
+pub fn run_blocking(
+    pipeline: Pipeline,
+    resources: Arc<ResourceRegistry>,
+    input: Vec<u8>,
+) -> Result<PipelineRunOutput, Box<dyn Error>> {
+    block_on(run(pipeline, resources, input))
+}
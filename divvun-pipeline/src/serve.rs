@@ -0,0 +1,442 @@
+use std::{
+    io::{self, BufRead, BufReader, Read, Write},
+    net::{Shutdown, SocketAddr, TcpListener, TcpStream, ToSocketAddrs},
+    panic::{self, AssertUnwindSafe},
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc,
+    },
+    thread,
+    time::Duration,
+};
+
+use log::{error, info, warn};
+use serde::Serialize;
+
+use crate::{
+    convert::{detect_format, DataFormat},
+    session::{PipelinePackages, SessionRequest},
+};
+
+/// Largest request body accepted
+pub const MAX_BODY_SIZE: usize = 16 * 1024 * 1024;
+const MAX_HEADER_SIZE: usize = 64 * 1024;
+const ACCEPT_INTERVAL: Duration = Duration::from_millis(20);
+const READ_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Content types of the data formats, `text/plain` is taken as text
+const CONTENT_TYPES: &[(&str, DataFormat)] = &[
+    ("text/plain", DataFormat::Text),
+    ("application/json", DataFormat::Json),
+    ("application/x-capnp", DataFormat::Capnp),
+    ("application/octet-stream", DataFormat::Capnp),
+];
+
+fn content_type(format: DataFormat) -> &'static str {
+    match format {
+        DataFormat::Text => "text/plain; charset=utf-8",
+        DataFormat::Json => "application/json",
+        DataFormat::Capnp => "application/x-capnp",
+    }
+}
+
+/// The data format of a `Content-Type` or `Accept` header, ignoring parameters like the charset
+fn header_format(value: &str) -> Option<DataFormat> {
+    value.split(',').find_map(|value| {
+        let media_type = value.split(';').next().unwrap_or("").trim();
+        CONTENT_TYPES
+            .iter()
+            .find(|(name, _)| name.eq_ignore_ascii_case(media_type))
+            .map(|(_, format)| *format)
+    })
+}
+
+struct Request {
+    method: String,
+    path: String,
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+}
+
+impl Request {
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| &**value)
+    }
+}
+
+struct Response {
+    status: u16,
+    content_type: &'static str,
+    body: Vec<u8>,
+}
+
+impl Response {
+    fn new(status: u16, format: DataFormat, body: Vec<u8>) -> Response {
+        Response {
+            status,
+            content_type: content_type(format),
+            body,
+        }
+    }
+
+    fn error(status: u16, message: &str) -> Response {
+        Response::new(status, DataFormat::Text, message.as_bytes().to_vec())
+    }
+
+    fn write_to<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        write!(
+            writer,
+            "HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+            self.status,
+            reason(self.status),
+            self.content_type,
+            self.body.len()
+        )?;
+        writer.write_all(&self.body)?;
+        writer.flush()
+    }
+}
+
+fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        400 => "Bad Request",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        411 => "Length Required",
+        413 => "Payload Too Large",
+        500 => "Internal Server Error",
+        503 => "Service Unavailable",
+        _ => "",
+    }
+}
+
+/// Read a request, returning the response to send right away if it is malformed
+fn read_request<R: BufRead>(reader: &mut R) -> Result<Request, Response> {
+    let mut head = Vec::new();
+    // Reading a line buffers all of it, so a client can't send more than the limit at all
+    let mut limited = reader.by_ref().take(MAX_HEADER_SIZE as u64 + 1);
+    loop {
+        let read = limited
+            .read_until(b'\n', &mut head)
+            .map_err(|_| Response::error(400, "failed to read request"))?;
+        if head.len() > MAX_HEADER_SIZE {
+            return Err(Response::error(400, "request header is too large"));
+        }
+        if read == 0 || head.ends_with(b"\r\n\r\n") || head.ends_with(b"\n\n") {
+            break;
+        }
+    }
+
+    let head = String::from_utf8(head).map_err(|_| Response::error(400, "invalid request"))?;
+    let mut lines = head.lines();
+    let mut request_line = lines.next().unwrap_or("").split_whitespace();
+    let (method, path) = match (request_line.next(), request_line.next()) {
+        (Some(method), Some(path)) => (method.to_string(), path.to_string()),
+        _ => return Err(Response::error(400, "invalid request line")),
+    };
+
+    let headers = lines
+        .take_while(|line| !line.is_empty())
+        .filter_map(|line| {
+            let index = line.find(':')?;
+            Some((
+                line[..index].trim().to_string(),
+                line[index + 1..].trim().to_string(),
+            ))
+        })
+        .collect();
+
+    let mut request = Request {
+        method,
+        path,
+        headers,
+        body: Vec::new(),
+    };
+
+    if request.method == "POST" {
+        if request.header("Transfer-Encoding").is_some() {
+            return Err(Response::error(411, "chunked requests are not supported"));
+        }
+        let length = match request.header("Content-Length") {
+            Some(length) => length
+                .parse::<usize>()
+                .map_err(|_| Response::error(400, "invalid Content-Length"))?,
+            None => return Err(Response::error(411, "Content-Length is required")),
+        };
+        if length > MAX_BODY_SIZE {
+            return Err(Response::error(413, "request body is too large"));
+        }
+
+        request.body = vec![0; length];
+        reader
+            .read_exact(&mut request.body)
+            .map_err(|_| Response::error(400, "failed to read request body"))?;
+    }
+
+    Ok(request)
+}
+
+#[derive(Serialize)]
+struct Health {
+    status: &'static str,
+    pipelines: Vec<String>,
+    active_requests: usize,
+}
+
+struct ServerState {
+    packages: PipelinePackages,
+    max_concurrent: usize,
+    active: AtomicUsize,
+    shutdown: ShutdownHandle,
+}
+
+impl ServerState {
+    fn handle(&self, request: Request) -> Response {
+        let path = request.path.split('?').next().unwrap_or("");
+
+        if path == "/health" {
+            if request.method != "GET" {
+                return Response::error(405, "use GET");
+            }
+            let health = Health {
+                status: "ok",
+                pipelines: self.packages.pipeline_names(),
+                active_requests: self.active.load(Ordering::SeqCst),
+            };
+            return Response::new(
+                200,
+                DataFormat::Json,
+                serde_json::to_vec(&health).unwrap_or_default(),
+            );
+        }
+
+        if !path.starts_with("/pipelines/") {
+            return Response::error(404, "not found");
+        }
+        let name = &path["/pipelines/".len()..];
+        if request.method != "POST" {
+            return Response::error(405, "use POST");
+        }
+
+        let (session, pipeline) = match self.packages.resolve(name) {
+            Ok(resolved) => resolved,
+            Err(e) => return Response::error(404, &e.to_string()),
+        };
+
+        let input_format = match request.header("Content-Type") {
+            Some(value) => match header_format(value) {
+                Some(format) => format,
+                None => {
+                    return Response::error(400, &format!("unsupported Content-Type {}", value))
+                }
+            },
+            None => detect_format(&request.body),
+        };
+        let output_format = request
+            .header("Accept")
+            .and_then(header_format)
+            .unwrap_or(input_format);
+
+        let mut session_request = SessionRequest::new(request.body, input_format);
+        session_request.pipeline = pipeline;
+        session_request.output_format = output_format;
+
+        match session.run_blocking(session_request) {
+            Ok(output) => Response::new(200, output_format, output),
+            Err(e) => {
+                error!("Running {} failed: {}", name, e);
+                Response::error(500, &e.to_string())
+            }
+        }
+    }
+}
+
+/// Counts a request as active until it is dropped, also when handling it panicked
+struct ActiveRequest(Arc<ServerState>);
+
+impl Drop for ActiveRequest {
+    fn drop(&mut self) {
+        self.0.active.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Stops a running server once the requests in flight are answered
+#[derive(Clone, Default)]
+pub struct ShutdownHandle(Arc<AtomicBool>);
+
+impl ShutdownHandle {
+    pub fn new() -> ShutdownHandle {
+        ShutdownHandle::default()
+    }
+
+    pub fn shutdown(&self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_shutdown(&self) -> bool {
+        self.0.load(Ordering::SeqCst)
+    }
+}
+
+/// HTTP server running the pipelines of one or more pipeline files, which are loaded once.
+///
+/// `POST /pipelines/{package}` runs the default pipeline of a package and
+/// `POST /pipelines/{package}/{pipeline}` a named one. The input format is taken from the
+/// `Content-Type` (`text/plain`, `application/json` or `application/x-capnp`) and detected if
+/// there is none, the output format from `Accept`, defaulting to the input format.
+/// `GET /health` lists the pipelines. Requests beyond the concurrency limit get a 503.
+pub struct Server {
+    listener: TcpListener,
+    state: Arc<ServerState>,
+}
+
+impl Server {
+    pub fn bind<A: ToSocketAddrs>(
+        address: A,
+        packages: PipelinePackages,
+        max_concurrent: usize,
+    ) -> io::Result<Server> {
+        let listener = TcpListener::bind(address)?;
+        listener.set_nonblocking(true)?;
+
+        Ok(Server {
+            listener,
+            state: Arc::new(ServerState {
+                packages,
+                max_concurrent,
+                active: AtomicUsize::new(0),
+                shutdown: ShutdownHandle::new(),
+            }),
+        })
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        self.state.shutdown.clone()
+    }
+
+    /// Answer requests until shut down, then wait for the requests in flight
+    pub fn run(self) -> io::Result<()> {
+        info!("Listening on {}", self.local_addr()?);
+
+        while !self.state.shutdown.is_shutdown() {
+            let (stream, peer) = match self.listener.accept() {
+                Ok(connection) => connection,
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    thread::sleep(ACCEPT_INTERVAL);
+                    continue;
+                }
+                Err(e) => {
+                    warn!("Failed to accept connection: {}", e);
+                    continue;
+                }
+            };
+
+            if self.state.active.fetch_add(1, Ordering::SeqCst) >= self.state.max_concurrent {
+                self.state.active.fetch_sub(1, Ordering::SeqCst);
+                warn!("Rejecting request from {}, too many requests", peer);
+                reject(stream);
+                continue;
+            }
+
+            // Accepted sockets inherit non-blocking mode on some platforms
+            let setup = stream
+                .set_nonblocking(false)
+                .and_then(|()| stream.set_read_timeout(Some(READ_TIMEOUT)));
+            if let Err(e) = setup {
+                self.state.active.fetch_sub(1, Ordering::SeqCst);
+                warn!("Failed to set up connection from {}: {}", peer, e);
+                continue;
+            }
+
+            let active = ActiveRequest(Arc::clone(&self.state));
+            thread::spawn(move || serve_connection(&active.0, stream));
+        }
+
+        info!("Shutting down");
+        while self.state.active.load(Ordering::SeqCst) > 0 {
+            thread::sleep(ACCEPT_INTERVAL);
+        }
+        Ok(())
+    }
+}
+
+/// Answer 503 on the accepting thread without reading the request, so rejected requests cost
+/// neither a thread nor a body buffer
+fn reject(stream: TcpStream) {
+    if let Err(e) = stream.set_nonblocking(true) {
+        warn!("Failed to reject request: {}", e);
+        return;
+    }
+    if let Err(e) = Response::error(503, "too many requests").write_to(&mut &stream) {
+        warn!("Failed to write response: {}", e);
+    }
+    let _ = stream.shutdown(Shutdown::Write);
+
+    // Closing a socket with unread data resets it, which can discard the response on the
+    // client's side. Drop what already arrived, up to the header limit, without waiting.
+    let mut buffer = [0; 4096];
+    let mut drained = 0;
+    while drained < MAX_HEADER_SIZE {
+        match (&stream).read(&mut buffer) {
+            Ok(0) | Err(_) => break,
+            Ok(read) => drained += read,
+        }
+    }
+}
+
+fn serve_connection(state: &ServerState, stream: TcpStream) {
+    let mut reader = BufReader::new(&stream);
+    let response = match read_request(&mut reader) {
+        Ok(request) => panic::catch_unwind(AssertUnwindSafe(|| state.handle(request)))
+            .unwrap_or_else(|_| Response::error(500, "running the pipeline panicked")),
+        Err(response) => response,
+    };
+
+    if let Err(e) = response.write_to(&mut &stream) {
+        warn!("Failed to write response: {}", e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_request() {
+        let data = b"POST /pipelines/se HTTP/1.1\r\nContent-Type: text/plain\r\nContent-Length: 5\r\n\r\nHello";
+        let request = read_request(&mut &data[..]).ok().unwrap();
+        assert_eq!(request.method, "POST");
+        assert_eq!(request.path, "/pipelines/se");
+        assert_eq!(request.header("content-type"), Some("text/plain"));
+        assert_eq!(request.body, b"Hello");
+
+        let data = b"POST /pipelines/se HTTP/1.1\r\n\r\n";
+        assert_eq!(read_request(&mut &data[..]).err().unwrap().status, 411);
+
+        let data = vec![b'a'; MAX_HEADER_SIZE * 2];
+        let mut reader = &data[..];
+        assert_eq!(read_request(&mut reader).err().unwrap().status, 400);
+        assert_eq!(reader.len(), MAX_HEADER_SIZE - 1);
+    }
+
+    #[test]
+    fn parse_content_types() {
+        assert_eq!(
+            header_format("text/plain; charset=utf-8"),
+            Some(DataFormat::Text)
+        );
+        assert_eq!(
+            header_format("text/html, application/json"),
+            Some(DataFormat::Json)
+        );
+        assert_eq!(header_format("image/png"), None);
+    }
+}
@@ -0,0 +1,350 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    error::Error,
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use futures::executor::block_on;
+use parking_lot::Mutex;
+
+use crate::{
+    convert::DataFormat,
+    executor::PipelineExecutor,
+    file::{FileLoadError, PipelineFile},
+    module::{AllocationType, ModuleAllocator, ModuleRegistry},
+    pipeline::{NodePath, PipelineCommand, PipelineData, PipelineObserver, PipelineType},
+    resources::{ResidencyPolicy, ResourceError},
+    run::{decode_output, encode_input, module_registry},
+};
+
+/// Input for a single run of a `PipelineSession`
+#[derive(Debug, Clone)]
+pub struct SessionRequest {
+    /// Pipeline of the file to run, the default one if not given
+    pub pipeline: Option<String>,
+    pub input: Vec<u8>,
+    pub input_format: DataFormat,
+    pub output_format: DataFormat,
+    /// Values for `${name}` placeholders in module names and parameters
+    pub variables: BTreeMap<String, String>,
+    /// Stops the run before the next command once set
+    pub cancel: Option<Arc<AtomicBool>>,
+}
+
+impl SessionRequest {
+    /// Run the default pipeline, with the output in the same format as the input
+    pub fn new(input: Vec<u8>, format: DataFormat) -> SessionRequest {
+        SessionRequest {
+            pipeline: None,
+            input,
+            input_format: format,
+            output_format: format,
+            variables: BTreeMap::new(),
+            cancel: None,
+        }
+    }
+}
+
+/// Owns the input of a run and remembers its outputs, which are freed when it is dropped. Module
+/// calls still in flight on an executor hold on to it, so that is once all of them returned.
+struct RunAllocations {
+    allocator: Arc<ModuleAllocator>,
+    input: Vec<u8>,
+    outputs: Mutex<Vec<usize>>,
+    cancel: Option<Arc<AtomicBool>>,
+    observer: Option<Arc<dyn PipelineObserver>>,
+}
+
+impl PipelineObserver for RunAllocations {
+    fn command_finished(
+        &self,
+        path: &NodePath,
+        command: &PipelineCommand,
+        input: &PipelineType,
+        output: &PipelineType,
+        duration: Duration,
+    ) {
+        if let Some(ref observer) = self.observer {
+            observer.command_finished(path, command, input, output, duration);
+        }
+        self.outputs
+            .lock()
+            .extend(output.iter().map(|data| data.data as usize));
+    }
+
+    fn cancelled(&self) -> bool {
+        self.cancel
+            .as_ref()
+            .map_or(false, |cancel| cancel.load(Ordering::SeqCst))
+            || self
+                .observer
+                .as_ref()
+                .map_or(false, |observer| observer.cancelled())
+    }
+}
+
+impl Drop for RunAllocations {
+    fn drop(&mut self) {
+        for ptr in self.outputs.get_mut().drain(..) {
+            self.allocator.free(ptr as *const u8);
+        }
+    }
+}
+
+/// The output of a single command, copied as the pipeline runs
+#[derive(Debug, Clone)]
+pub struct CommandOutput {
+    pub path: NodePath,
+    pub command: PipelineCommand,
+    pub output: Vec<u8>,
+    pub duration: Duration,
+}
+
+/// Observer keeping a copy of the output of every command, which stays valid after the run
+#[derive(Default)]
+pub struct CommandRecorder {
+    outputs: Mutex<Vec<CommandOutput>>,
+}
+
+impl CommandRecorder {
+    pub fn new() -> CommandRecorder {
+        CommandRecorder::default()
+    }
+
+    /// The outputs recorded so far, in the order the commands finished
+    pub fn take_outputs(&self) -> Vec<CommandOutput> {
+        std::mem::replace(&mut *self.outputs.lock(), Vec::new())
+    }
+}
+
+impl PipelineObserver for CommandRecorder {
+    fn command_finished(
+        &self,
+        path: &NodePath,
+        command: &PipelineCommand,
+        _input: &PipelineType,
+        output: &PipelineType,
+        duration: Duration,
+    ) {
+        self.outputs.lock().push(CommandOutput {
+            path: path.clone(),
+            command: command.clone(),
+            output: output
+                .iter()
+                .flat_map(|data| data.as_slice().iter().cloned())
+                .collect(),
+            duration,
+        });
+    }
+}
+
+/// A pipeline file with its modules loaded, for running many inputs without loading anything
+/// again. Runs can happen concurrently, the memory modules allocate for a run is released when
+/// it is done. Resources stay loaded between runs unless the file's registry was given another
+/// policy than the default one.
+pub struct PipelineSession {
+    file: PipelineFile,
+    registry: Arc<ModuleRegistry>,
+    allocator: Arc<ModuleAllocator>,
+    executor: Option<Arc<dyn PipelineExecutor>>,
+}
+
+impl PipelineSession {
+    pub fn new(
+        file: PipelineFile,
+        module_search_paths: &[PathBuf],
+    ) -> Result<PipelineSession, Box<dyn Error>> {
+        if file.resources.policy() == ResidencyPolicy::OnDemand {
+            file.resources.set_policy(ResidencyPolicy::KeepResident)?;
+        }
+
+        let allocator = Arc::new(ModuleAllocator::new(AllocationType::Memory));
+        let registry = module_registry(
+            Arc::clone(&allocator),
+            Arc::clone(&file.resources),
+            module_search_paths,
+            &file.manifest.modules,
+        )?;
+
+        Ok(PipelineSession {
+            file,
+            registry: Arc::new(registry),
+            allocator,
+            executor: None,
+        })
+    }
+
+    /// Keep the resources of the file loaded according to `policy` instead of `KeepResident`
+    pub fn with_policy(self, policy: ResidencyPolicy) -> Result<PipelineSession, ResourceError> {
+        self.file.resources.set_policy(policy)?;
+        Ok(self)
+    }
+
+    /// Call the modules of every run on `executor` instead of the thread polling the run
+    pub fn with_executor(mut self, executor: Arc<dyn PipelineExecutor>) -> PipelineSession {
+        self.executor = Some(executor);
+        self
+    }
+
+    pub fn file(&self) -> &PipelineFile {
+        &self.file
+    }
+
+    pub fn registry(&self) -> &Arc<ModuleRegistry> {
+        &self.registry
+    }
+
+    pub fn pipeline_names(&self) -> Vec<String> {
+        self.file.pipeline_names()
+    }
+
+    /// Names of the modules the manifest requires or any pipeline of the file uses
+    pub fn module_names(&self) -> Vec<String> {
+        let mut names = BTreeSet::new();
+        names.extend(
+            self.file
+                .manifest
+                .modules
+                .iter()
+                .map(|module| module.name.clone()),
+        );
+        for pipeline in self.file.pipelines.values() {
+            names.extend(
+                pipeline
+                    .commands()
+                    .into_iter()
+                    .map(|command| command.module.clone()),
+            );
+        }
+        names.into_iter().collect()
+    }
+
+    /// Run a pipeline of the file, returning its output in the requested format
+    pub async fn run(&self, request: SessionRequest) -> Result<Vec<u8>, Box<dyn Error>> {
+        self.run_with_observer(request, None).await
+    }
+
+    /// Like `run`, blocking the current thread until the pipeline is done
+    pub fn run_blocking(&self, request: SessionRequest) -> Result<Vec<u8>, Box<dyn Error>> {
+        block_on(self.run(request))
+    }
+
+    /// Run a pipeline of the file, telling `observer` about every command. The outputs it is
+    /// shown are released once the run is done.
+    pub async fn run_observed(
+        &self,
+        request: SessionRequest,
+        observer: Arc<dyn PipelineObserver>,
+    ) -> Result<Vec<u8>, Box<dyn Error>> {
+        self.run_with_observer(request, Some(observer)).await
+    }
+
+    /// Like `run_observed`, blocking the current thread until the pipeline is done
+    pub fn run_observed_blocking(
+        &self,
+        request: SessionRequest,
+        observer: Arc<dyn PipelineObserver>,
+    ) -> Result<Vec<u8>, Box<dyn Error>> {
+        block_on(self.run_observed(request, observer))
+    }
+
+    async fn run_with_observer(
+        &self,
+        request: SessionRequest,
+        observer: Option<Arc<dyn PipelineObserver>>,
+    ) -> Result<Vec<u8>, Box<dyn Error>> {
+        let pipeline = self
+            .file
+            .pipeline(request.pipeline.as_ref().map(|name| &**name))?
+            .with_variables(&request.variables);
+        pipeline.check_variables()?;
+
+        let input = match request.input_format {
+            DataFormat::Capnp => request.input,
+            format => encode_input(&self.registry, &pipeline, &request.input, format)?,
+        };
+
+        let allocations = Arc::new(RunAllocations {
+            allocator: Arc::clone(&self.allocator),
+            input,
+            outputs: Mutex::new(Vec::new()),
+            cancel: request.cancel,
+            observer,
+        });
+        let result = pipeline
+            .run_with(
+                Arc::clone(&self.registry),
+                Arc::new(vec![Arc::new(PipelineData {
+                    data: allocations.input.as_ptr(),
+                    size: allocations.input.len(),
+                })]),
+                Some(allocations.clone()),
+                self.executor.clone(),
+            )
+            .await;
+
+        // The outputs are copied here, they are freed with `allocations`
+        match result {
+            Ok(output) => match output.get(0) {
+                Some(data) => match request.output_format {
+                    DataFormat::Capnp => Ok(data.as_slice().to_vec()),
+                    format => decode_output(&self.registry, &pipeline, data.as_slice(), format)
+                        .map_err(|e| e.into()),
+                },
+                None => Err("pipeline produced no output".into()),
+            },
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+/// Pipeline files by name, as served by the HTTP server and the daemon. Their pipelines are
+/// named `{package}` for the default pipeline of a file and `{package}/{pipeline}` for each of
+/// its pipelines.
+pub struct PipelinePackages {
+    sessions: BTreeMap<String, PipelineSession>,
+}
+
+impl PipelinePackages {
+    pub fn new(sessions: BTreeMap<String, PipelineSession>) -> PipelinePackages {
+        PipelinePackages { sessions }
+    }
+
+    pub fn pipeline_names(&self) -> Vec<String> {
+        let mut names = Vec::new();
+        for (package, session) in &self.sessions {
+            if session.file().pipeline(None).is_ok() {
+                names.push(package.clone());
+            }
+            for pipeline in session.pipeline_names() {
+                names.push(format!("{}/{}", package, pipeline));
+            }
+        }
+        names
+    }
+
+    /// The session and name of the pipeline within it for a pipeline name
+    pub fn resolve(&self, name: &str) -> Result<(&PipelineSession, Option<String>), FileLoadError> {
+        let mut parts = name.splitn(2, '/');
+        let package = parts.next().unwrap_or("");
+        let pipeline = parts.next().map(str::to_string);
+
+        let session = self
+            .sessions
+            .get(package)
+            .ok_or_else(|| FileLoadError::UnknownPipeline {
+                name: name.to_string(),
+                available: self.pipeline_names(),
+            })?;
+        session
+            .file()
+            .pipeline(pipeline.as_ref().map(|name| &**name))?;
+
+        Ok((session, pipeline))
+    }
+}
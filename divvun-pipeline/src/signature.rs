@@ -0,0 +1,211 @@
+use std::{
+    convert::TryFrom,
+    error::Error,
+    fmt, fs, io,
+    path::{Path, PathBuf},
+};
+
+use ed25519_dalek::{Keypair, PublicKey, SecretKey, Signature, Signer, Verifier};
+use log::{error, info, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::hex::{decode_hex, encode_hex};
+
+/// Detached signature over the manifest, stored next to it in the archive. The manifest lists
+/// the hashes of all resources, so signing it covers the whole archive.
+pub static SIGNATURE_FILE_NAME: &'static str = "signature.json";
+
+#[derive(Debug)]
+pub enum SignatureError {
+    Io { path: PathBuf, error: io::Error },
+    InvalidKey(String),
+    InvalidSignatureFile(serde_json::Error),
+    InvalidSignature,
+    VerificationFailed { public_key: String },
+    UntrustedKey(String),
+    MissingSignature,
+}
+
+impl fmt::Display for SignatureError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SignatureError::Io {
+                ref path,
+                ref error,
+            } => write!(f, "failed to read {}: {}", path.display(), error),
+            SignatureError::InvalidKey(ref key) => {
+                write!(f, "{:?} is not a valid ed25519 key", key)
+            }
+            SignatureError::InvalidSignatureFile(ref e) => {
+                write!(f, "invalid {}: {}", SIGNATURE_FILE_NAME, e)
+            }
+            SignatureError::InvalidSignature => write!(f, "signature is malformed"),
+            SignatureError::VerificationFailed { ref public_key } => {
+                write!(f, "signature by {} does not match the manifest", public_key)
+            }
+            SignatureError::UntrustedKey(ref public_key) => {
+                write!(f, "signed by untrusted key {}", public_key)
+            }
+            SignatureError::MissingSignature => write!(f, "pipeline file is not signed"),
+        }
+    }
+}
+
+impl Error for SignatureError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            SignatureError::Io { ref error, .. } => Some(error),
+            SignatureError::InvalidSignatureFile(ref e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+/// Contents of the signature file, keys and signatures are hex encoded
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PipelineSignature {
+    pub public_key: String,
+    pub signature: String,
+}
+
+impl PipelineSignature {
+    pub fn from_slice(data: &[u8]) -> Result<PipelineSignature, SignatureError> {
+        serde_json::from_slice(data).map_err(SignatureError::InvalidSignatureFile)
+    }
+}
+
+/// Sign the bytes of a manifest exactly as they are stored in the archive
+pub fn sign_manifest(manifest: &[u8], keypair: &Keypair) -> PipelineSignature {
+    PipelineSignature {
+        public_key: encode_hex(keypair.public.as_bytes()),
+        signature: encode_hex(&keypair.sign(manifest).to_bytes()),
+    }
+}
+
+/// Read a hex encoded 32 byte ed25519 secret key
+pub fn read_secret_key(path: &Path) -> Result<Keypair, SignatureError> {
+    let text = fs::read_to_string(path).map_err(|error| SignatureError::Io {
+        path: path.to_path_buf(),
+        error,
+    })?;
+    let text = text.trim();
+
+    let secret = decode_hex(text)
+        .and_then(|bytes| SecretKey::from_bytes(&bytes).ok())
+        .ok_or_else(|| SignatureError::InvalidKey(path.display().to_string()))?;
+    let public = PublicKey::from(&secret);
+
+    Ok(Keypair { secret, public })
+}
+
+pub fn public_key_hex(keypair: &Keypair) -> String {
+    encode_hex(keypair.public.as_bytes())
+}
+
+/// The keys pipeline files have to be signed with
+#[derive(Debug, Clone, Default)]
+pub struct TrustedKeys {
+    keys: Vec<PublicKey>,
+    require_signature: bool,
+}
+
+impl TrustedKeys {
+    pub fn new() -> TrustedKeys {
+        TrustedKeys::default()
+    }
+
+    /// Reject unsigned pipeline files instead of loading them with a warning
+    pub fn require_signature(mut self, require_signature: bool) -> TrustedKeys {
+        self.require_signature = require_signature;
+        self
+    }
+
+    /// Add a hex encoded public key
+    pub fn add_key(&mut self, public_key: &str) -> Result<(), SignatureError> {
+        let key = decode_hex(public_key)
+            .and_then(|bytes| PublicKey::from_bytes(&bytes).ok())
+            .ok_or_else(|| SignatureError::InvalidKey(public_key.to_string()))?;
+        self.keys.push(key);
+        Ok(())
+    }
+
+    /// Add the keys of a file with one hex encoded public key per line. Empty lines and lines
+    /// starting with `#` are skipped.
+    pub fn add_key_file(&mut self, path: &Path) -> Result<(), SignatureError> {
+        let text = fs::read_to_string(path).map_err(|error| SignatureError::Io {
+            path: path.to_path_buf(),
+            error,
+        })?;
+
+        for line in text.lines().map(str::trim) {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            self.add_key(line)?;
+        }
+
+        Ok(())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.keys.is_empty()
+    }
+
+    /// Check the signature of a manifest, returning whether it was signed. Without trusted keys
+    /// signatures are only checked for consistency, since any key is accepted.
+    pub fn verify(
+        &self,
+        manifest: &[u8],
+        signature: Option<&PipelineSignature>,
+    ) -> Result<bool, SignatureError> {
+        let signature = match signature {
+            Some(signature) => signature,
+            None if self.require_signature => {
+                error!("Pipeline file is not signed");
+                return Err(SignatureError::MissingSignature);
+            }
+            None => {
+                if !self.keys.is_empty() {
+                    warn!("Pipeline file is not signed");
+                }
+                return Ok(false);
+            }
+        };
+
+        let public_key = decode_hex(&signature.public_key)
+            .and_then(|bytes| PublicKey::from_bytes(&bytes).ok())
+            .ok_or_else(|| SignatureError::InvalidKey(signature.public_key.clone()))?;
+        let bytes = decode_hex(&signature.signature).ok_or(SignatureError::InvalidSignature)?;
+        let ed_signature =
+            Signature::try_from(&bytes[..]).map_err(|_| SignatureError::InvalidSignature)?;
+
+        if public_key.verify(manifest, &ed_signature).is_err() {
+            error!("Signature by {} does not match", signature.public_key);
+            return Err(SignatureError::VerificationFailed {
+                public_key: signature.public_key.clone(),
+            });
+        }
+
+        if self.keys.is_empty() {
+            if self.require_signature {
+                return Err(SignatureError::UntrustedKey(signature.public_key.clone()));
+            }
+            // The signature only shows the manifest wasn't changed after signing, not by whom
+            warn!(
+                "No trusted keys configured, accepting signature by {} without checking the key",
+                signature.public_key
+            );
+            return Ok(true);
+        }
+        if !self.keys.contains(&public_key) {
+            error!(
+                "Pipeline file signed by untrusted key {}",
+                signature.public_key
+            );
+            return Err(SignatureError::UntrustedKey(signature.public_key.clone()));
+        }
+
+        info!("Signature by {} verified", signature.public_key);
+        Ok(true)
+    }
+}
@@ -0,0 +1,277 @@
+use std::{
+    error::Error,
+    fmt,
+    fs::{self, File},
+    io::{self, BufWriter, Write},
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::Arc,
+    time::Duration,
+};
+
+use log::error;
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    convert::schema_by_type_id,
+    hex::encode_hex,
+    module::{ModuleAllocator, ModuleRegistry},
+    pipeline::{NodePath, PipelineCommand, PipelineObserver, PipelineType},
+    run::command_types,
+};
+
+/// How the trace of a run is written
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum TraceFormat {
+    /// One JSON record per line in a single file
+    JsonLines,
+    /// A JSON record and the raw output of every command as separate files in a directory
+    Directory,
+}
+
+impl TraceFormat {
+    pub fn names() -> &'static [&'static str] {
+        &["jsonl", "dir"]
+    }
+}
+
+impl Default for TraceFormat {
+    fn default() -> TraceFormat {
+        TraceFormat::JsonLines
+    }
+}
+
+impl FromStr for TraceFormat {
+    type Err = TraceError;
+
+    fn from_str(name: &str) -> Result<TraceFormat, TraceError> {
+        match name {
+            "jsonl" => Ok(TraceFormat::JsonLines),
+            "dir" => Ok(TraceFormat::Directory),
+            _ => Err(TraceError::UnknownFormat(name.to_string())),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum TraceError {
+    UnknownFormat(String),
+    Io { path: PathBuf, error: io::Error },
+    Json(serde_json::Error),
+}
+
+impl fmt::Display for TraceError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TraceError::UnknownFormat(ref name) => write!(
+                f,
+                "unknown trace format {}, expected one of {}",
+                name,
+                TraceFormat::names().join(", ")
+            ),
+            TraceError::Io {
+                ref path,
+                ref error,
+            } => write!(f, "failed to write trace to {}: {}", path.display(), error),
+            TraceError::Json(ref e) => write!(f, "failed to serialize trace: {}", e),
+        }
+    }
+}
+
+impl Error for TraceError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            TraceError::Io { ref error, .. } => Some(error),
+            TraceError::Json(ref e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+/// What a single command did during a traced run
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TraceRecord {
+    /// Position in the order the commands finished
+    pub index: usize,
+    /// Position of the command in the pipeline, e.g. `1.0`
+    pub path: String,
+    pub module: String,
+    pub command: String,
+    #[serde(default)]
+    pub parameters: Vec<String>,
+    pub input_sizes: Vec<usize>,
+    pub output_size: usize,
+    /// The output as JSON, if the module lists the output type of the command
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub output: Option<serde_json::Value>,
+    /// The hex encoded output if it couldn't be decoded
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub output_hex: Option<String>,
+    pub duration_us: u64,
+    /// Growth of the module allocator's total since the previous record. Commands of parallel
+    /// nodes running at the same time share the allocator, so what one of them allocated may be
+    /// counted for another.
+    pub allocated_bytes: usize,
+    /// Memory held by the module allocator after the command
+    pub allocator_total_bytes: usize,
+}
+
+enum TraceWriter {
+    JsonLines(BufWriter<File>),
+    Directory,
+}
+
+struct TraceState {
+    writer: TraceWriter,
+    index: usize,
+    allocated: usize,
+    error: Option<TraceError>,
+}
+
+/// Writes a `TraceRecord` for every command of a run, passed to `Pipeline::run_observed`
+pub struct PipelineTracer {
+    path: PathBuf,
+    registry: Arc<ModuleRegistry>,
+    allocator: Arc<ModuleAllocator>,
+    state: Mutex<TraceState>,
+}
+
+impl PipelineTracer {
+    pub fn create(
+        path: &Path,
+        format: TraceFormat,
+        registry: Arc<ModuleRegistry>,
+        allocator: Arc<ModuleAllocator>,
+    ) -> Result<PipelineTracer, TraceError> {
+        let io_error = |error| TraceError::Io {
+            path: path.to_path_buf(),
+            error,
+        };
+
+        let writer = match format {
+            TraceFormat::JsonLines => {
+                TraceWriter::JsonLines(BufWriter::new(File::create(path).map_err(io_error)?))
+            }
+            TraceFormat::Directory => {
+                fs::create_dir_all(path).map_err(io_error)?;
+                TraceWriter::Directory
+            }
+        };
+
+        let allocated = allocator.total_size();
+        Ok(PipelineTracer {
+            path: path.to_path_buf(),
+            registry,
+            allocator,
+            state: Mutex::new(TraceState {
+                writer,
+                index: 0,
+                allocated,
+                error: None,
+            }),
+        })
+    }
+
+    /// Flush the trace, returning the first error that occurred while writing it
+    pub fn finish(&self) -> Result<(), TraceError> {
+        let mut state = self.state.lock();
+        if let Some(error) = state.error.take() {
+            return Err(error);
+        }
+
+        if let TraceWriter::JsonLines(ref mut writer) = state.writer {
+            writer.flush().map_err(|error| TraceError::Io {
+                path: self.path.clone(),
+                error,
+            })?;
+        }
+        Ok(())
+    }
+
+    fn decode_output(&self, command: &PipelineCommand, output: &[u8]) -> Option<serde_json::Value> {
+        let (_, output_type) = command_types(&self.registry, &command.module, &command.command)?;
+        schema_by_type_id(output_type)?.to_json(output).ok()
+    }
+
+    fn write(
+        &self,
+        state: &mut TraceState,
+        record: &TraceRecord,
+        output: &[u8],
+    ) -> Result<(), TraceError> {
+        match state.writer {
+            TraceWriter::JsonLines(ref mut writer) => {
+                serde_json::to_writer(&mut *writer, record).map_err(TraceError::Json)?;
+                // Flushed right away, so the records are there even if a later module panics
+                writeln!(writer)
+                    .and_then(|_| writer.flush())
+                    .map_err(|error| TraceError::Io {
+                        path: self.path.clone(),
+                        error,
+                    })
+            }
+            TraceWriter::Directory => {
+                let name = format!("{:04}-{}", record.index, record.path);
+                let json = serde_json::to_vec_pretty(record).map_err(TraceError::Json)?;
+                for (path, data) in &[
+                    (self.path.join(format!("{}.json", name)), &json[..]),
+                    (self.path.join(format!("{}.bin", name)), output),
+                ] {
+                    fs::write(path, data).map_err(|error| TraceError::Io {
+                        path: path.clone(),
+                        error,
+                    })?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl PipelineObserver for PipelineTracer {
+    fn command_finished(
+        &self,
+        path: &NodePath,
+        command: &PipelineCommand,
+        input: &PipelineType,
+        output: &PipelineType,
+        duration: Duration,
+    ) {
+        let data = output
+            .iter()
+            .flat_map(|data| data.as_slice().iter().cloned())
+            .collect::<Vec<u8>>();
+        let decoded = self.decode_output(command, &data);
+
+        let mut state = self.state.lock();
+        let allocator_total = self.allocator.total_size();
+        let record = TraceRecord {
+            index: state.index,
+            path: path.to_string(),
+            module: command.module.clone(),
+            command: command.command.clone(),
+            parameters: command.parameters.clone().unwrap_or_default(),
+            input_sizes: input.iter().map(|data| data.size).collect(),
+            output_size: data.len(),
+            output_hex: match decoded {
+                Some(_) => None,
+                None => Some(encode_hex(&data)),
+            },
+            output: decoded,
+            duration_us: duration.as_micros() as u64,
+            allocated_bytes: allocator_total.saturating_sub(state.allocated),
+            allocator_total_bytes: allocator_total,
+        };
+        state.index += 1;
+        state.allocated = allocator_total;
+
+        // Keep going with the run, the first error is reported by `finish`
+        if let Err(e) = self.write(&mut state, &record, &data) {
+            error!("{}", e);
+            if state.error.is_none() {
+                state.error = Some(e);
+            }
+        }
+    }
+}
@@ -0,0 +1,52 @@
+/*
+ * Runs the reverse-string pipeline through the C API.
+ *
+ * Usage: reverse <pipeline file or directory> <module search path>
+ */
+#include <stdio.h>
+#include <string.h>
+
+#include "divvun_pipeline.h"
+
+static int fail(const char *what) {
+    const char *message = dp_error_message();
+    fprintf(stderr, "%s failed: %s\n", what, message ? message : "no error message");
+    return 1;
+}
+
+int main(int argc, char **argv) {
+    if (argc != 3) {
+        fprintf(stderr, "usage: %s <pipeline> <module search path>\n", argv[0]);
+        return 2;
+    }
+
+    DpPipeline *pipeline = dp_pipeline_open(argv[1], argv[2], NULL);
+    if (pipeline == NULL) {
+        return fail("dp_pipeline_open");
+    }
+
+    const char *input = "Hello";
+    DpOutput *output = dp_pipeline_run(pipeline, NULL, (const uint8_t *)input, strlen(input),
+                                       DP_DATA_FORMAT_TEXT, DP_DATA_FORMAT_TEXT);
+    if (output == NULL) {
+        return fail("dp_pipeline_run");
+    }
+
+    size_t size = dp_output_size(output);
+    if (size != 5 || memcmp(dp_output_data(output), "olleH", size) != 0) {
+        fprintf(stderr, "unexpected output: %.*s\n", (int)size, (const char *)dp_output_data(output));
+        return 1;
+    }
+    printf("%.*s\n", (int)size, (const char *)dp_output_data(output));
+    dp_output_free(output);
+
+    output = dp_pipeline_run(pipeline, "missing", (const uint8_t *)input, strlen(input),
+                             DP_DATA_FORMAT_TEXT, DP_DATA_FORMAT_TEXT);
+    if (output != NULL || dp_error_message() == NULL) {
+        fprintf(stderr, "running a missing pipeline did not fail\n");
+        return 1;
+    }
+
+    dp_pipeline_close(pipeline);
+    return 0;
+}
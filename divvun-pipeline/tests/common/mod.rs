@@ -1,9 +1,18 @@
 use std::{
+    collections::BTreeMap,
+    io::Write,
     path::{Path, PathBuf},
     sync::Arc,
 };
 
-use divvun_pipeline::{module::*, resources::ResourceRegistry};
+use divvun_pipeline::{
+    file::load_pipeline_file,
+    manifest::MANIFEST_FILE_NAME,
+    module::*,
+    resources::ResourceRegistry,
+    session::{PipelinePackages, PipelineSession},
+};
+use serde_json::json;
 
 pub fn setup_test_registry(
     allocation_type: AllocationType,
@@ -34,3 +43,56 @@ pub fn get_project_root() -> PathBuf {
         .unwrap()
         .to_path_buf()
 }
+
+/// Write a .zpipe archive with the given entries and compression method into `dir`
+pub fn write_zpipe(
+    dir: &Path,
+    entries: &[(&str, &[u8])],
+    compression_method: zip::CompressionMethod,
+) -> PathBuf {
+    let path = dir.join("test.zpipe");
+    let file = std::fs::File::create(&path).unwrap();
+    let mut writer = zip::ZipWriter::new(file);
+    let options = zip::write::FileOptions::default().compression_method(compression_method);
+
+    for (name, data) in entries {
+        writer.start_file(*name, options).unwrap();
+        writer.write_all(data).unwrap();
+    }
+
+    writer.finish().unwrap();
+    path
+}
+
+/// Packages with a pipeline file named `reverse` written into `dir`, with a `default` pipeline
+/// reversing its input, a `twice` one reversing it twice and a `broken` one calling a module that
+/// doesn't exist
+pub fn reverse_packages(dir: &Path) -> PipelinePackages {
+    let _ = env_logger::builder().is_test(true).try_init();
+
+    let manifest = serde_json::to_vec(&json!({
+        "version": 1,
+        "pipelines": {
+            "default": { "run": { "module": "reverse_string", "command": "reverse", "parameters": null } },
+            "broken": { "run": { "module": "missing_module", "command": "run", "parameters": null } },
+            "twice": { "serial": [
+                { "run": { "module": "reverse_string", "command": "reverse", "parameters": null } },
+                { "run": { "module": "reverse_string", "command": "reverse", "parameters": null } }
+            ] }
+        }
+    }))
+    .unwrap();
+    let path = write_zpipe(
+        dir,
+        &[(MANIFEST_FILE_NAME, &manifest)],
+        zip::CompressionMethod::Stored,
+    );
+
+    let file = load_pipeline_file(&path).unwrap();
+    let mut sessions = BTreeMap::new();
+    sessions.insert(
+        "reverse".to_string(),
+        PipelineSession::new(file, &[get_test_module_search_path()]).unwrap(),
+    );
+    PipelinePackages::new(sessions)
+}
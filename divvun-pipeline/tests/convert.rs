@@ -0,0 +1,136 @@
+use std::io::Cursor;
+
+use divvun_pipeline::convert::{
+    decode, detect_format, encode, read_frame, schema_by_name, string_type_id, write_frame,
+    ConvertError, DataFormat,
+};
+use divvun_schema::{capnp_message, string_capnp::string};
+use serde_json::json;
+
+fn string_message(text: &str) -> Vec<u8> {
+    let msg = capnp_message!(string::Builder, builder => {
+        builder.set_string(text);
+    });
+    divvun_schema::util::message_to_vec(msg).unwrap()
+}
+
+#[test]
+fn convert_text_roundtrip() {
+    let message = encode(b"Hello world!", DataFormat::Text, string_type_id()).unwrap();
+    assert_eq!(message, string_message("Hello world!"));
+
+    let text = decode(&message, DataFormat::Text, string_type_id()).unwrap();
+    assert_eq!(text, b"Hello world!");
+}
+
+#[test]
+fn convert_json_roundtrip() {
+    let message = encode(br#"{"string":"Hello"}"#, DataFormat::Json, string_type_id()).unwrap();
+    assert_eq!(message, string_message("Hello"));
+
+    let json = decode(&message, DataFormat::Json, string_type_id()).unwrap();
+    assert_eq!(json, br#"{"string":"Hello"}"#.to_vec());
+}
+
+#[test]
+fn convert_capnp_passthrough() {
+    let message = string_message("Hello");
+    assert_eq!(encode(&message, DataFormat::Capnp, 0).unwrap(), message);
+    assert_eq!(decode(&message, DataFormat::Capnp, 0).unwrap(), message);
+}
+
+#[test]
+fn convert_detect_format() {
+    assert_eq!(detect_format(&string_message("Hello")), DataFormat::Capnp);
+    assert_eq!(detect_format(b"Hello world!\n"), DataFormat::Text);
+    assert_eq!(detect_format(b""), DataFormat::Text);
+}
+
+#[test]
+fn convert_unsupported() {
+    match encode(b"Hello", DataFormat::Text, 0x1234) {
+        Err(ConvertError::UnsupportedType { type_id, .. }) => assert_eq!(type_id, 0x1234),
+        other => panic!("unexpected result: {:?}", other),
+    }
+    assert!(encode(&[0xff, 0xfe], DataFormat::Text, string_type_id()).is_err());
+    assert!("xml".parse::<DataFormat>().is_err());
+}
+
+#[test]
+fn convert_pipeline_error_json() {
+    let schema = schema_by_name("pipeline_error").unwrap();
+    let error = json!({
+        "kind": "moduleError",
+        "message": "outer",
+        "source": { "kind": "invalidInput", "message": "inner" }
+    });
+
+    let message = schema.from_json(error.clone()).unwrap();
+    assert_eq!(schema.to_json(&message).unwrap(), error);
+
+    let unknown = schema.from_json(json!({ "kind": "fatal", "message": "" }));
+    match unknown {
+        Err(ConvertError::InvalidValue(_)) => {}
+        other => panic!("unexpected result: {:?}", other),
+    }
+}
+
+#[test]
+fn convert_module_metadata_json() {
+    let schema = schema_by_name("module_metadata").unwrap();
+    let metadata = json!({
+        "moduleName": "reverse_string",
+        "moduleVersion": "0.0.1",
+        "commands": [{ "name": "reverse", "inputs": [string_type_id()], "output": string_type_id() }]
+    });
+
+    let data = serde_json::to_vec(&metadata).unwrap();
+    let message = encode(&data, DataFormat::Json, schema.type_id).unwrap();
+    let json = decode(&message, DataFormat::Json, schema.type_id).unwrap();
+    assert_eq!(
+        serde_json::from_slice::<serde_json::Value>(&json).unwrap(),
+        metadata
+    );
+
+    // Only strings have a plain text form
+    assert!(encode(b"reverse", DataFormat::Text, schema.type_id).is_err());
+    let text = decode(&message, DataFormat::Text, schema.type_id).unwrap();
+    assert_eq!(text, json);
+}
+
+#[test]
+fn convert_frames() {
+    let error_type = schema_by_name("pipeline_error").unwrap().type_id;
+    let error = encode(
+        br#"{"kind":"unknownCommand","message":"nope"}"#,
+        DataFormat::Json,
+        error_type,
+    )
+    .unwrap();
+
+    let mut stream = Vec::new();
+    write_frame(&mut stream, string_type_id(), &string_message("one")).unwrap();
+    write_frame(&mut stream, error_type, &error).unwrap();
+    write_frame(&mut stream, string_type_id(), &string_message("")).unwrap();
+
+    let mut cursor = Cursor::new(&stream);
+    let mut frames = Vec::new();
+    while let Some(frame) = read_frame(&mut cursor).unwrap() {
+        frames.push(frame);
+    }
+
+    assert_eq!(
+        frames,
+        vec![
+            (string_type_id(), string_message("one")),
+            (error_type, error),
+            (string_type_id(), string_message("")),
+        ]
+    );
+
+    // A stream cut off within a message
+    let mut cursor = Cursor::new(&stream[..stream.len() - 3]);
+    read_frame(&mut cursor).unwrap();
+    read_frame(&mut cursor).unwrap();
+    assert!(read_frame(&mut cursor).is_err());
+}
@@ -0,0 +1,190 @@
+use std::{
+    collections::BTreeMap,
+    io::{self, Write},
+    sync::Arc,
+};
+
+use divvun_pipeline::daemon::{
+    read_message, write_message, Daemon, INVALID_PARAMS, METHOD_NOT_FOUND, PARSE_ERROR,
+    TOO_MANY_RUNS,
+};
+use parking_lot::Mutex;
+use serde_json::{json, Value};
+
+mod common;
+
+/// Collects what the daemon writes, which happens on the threads of the runs
+#[derive(Clone, Default)]
+struct SharedBuffer(Arc<Mutex<Vec<u8>>>);
+
+impl Write for SharedBuffer {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        self.0.lock().extend_from_slice(data);
+        Ok(data.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+fn daemon() -> (Daemon, tempfile::TempDir) {
+    let td = tempfile::tempdir().unwrap();
+    (Daemon::new(common::reverse_packages(td.path()), 8), td)
+}
+
+/// Send the messages to a daemon and return its responses by id
+fn exchange(daemon: &Daemon, messages: &[Vec<u8>]) -> BTreeMap<String, Value> {
+    let mut input = Vec::new();
+    for message in messages {
+        write_message(&mut input, message).unwrap();
+    }
+
+    let output = SharedBuffer::default();
+    daemon.serve(&input[..], output.clone()).unwrap();
+
+    let data = output.0.lock();
+    let mut reader = &data[..];
+    let mut responses = BTreeMap::new();
+    while let Some(message) = read_message(&mut reader).unwrap() {
+        let response: Value = serde_json::from_slice(&message).unwrap();
+        assert_eq!(response["jsonrpc"], "2.0");
+        responses.insert(response["id"].to_string(), response);
+    }
+    responses
+}
+
+fn request(id: u64, method: &str, params: Value) -> Vec<u8> {
+    serde_json::to_vec(&json!({ "jsonrpc": "2.0", "id": id, "method": method, "params": params }))
+        .unwrap()
+}
+
+#[test]
+fn daemon_requests() {
+    let (daemon, _td) = daemon();
+
+    let responses = exchange(
+        &daemon,
+        &[
+            request(1, "list_pipelines", Value::Null),
+            request(2, "run", json!({ "pipeline": "reverse", "input": "Hello" })),
+            request(
+                3,
+                "run",
+                json!({ "pipeline": "reverse/twice", "input": "Hello" }),
+            ),
+            request(
+                4,
+                "run",
+                json!({ "pipeline": "reverse", "input": { "string": "Hello" } }),
+            ),
+            request(
+                5,
+                "run",
+                json!({ "pipeline": "reverse", "input": "Hello", "output_format": "json" }),
+            ),
+            request(6, "cancel", json!({ "id": 42 })),
+        ],
+    );
+
+    assert_eq!(
+        responses["1"]["result"],
+        json!([
+            "reverse",
+            "reverse/broken",
+            "reverse/default",
+            "reverse/twice"
+        ])
+    );
+    assert_eq!(responses["2"]["result"], json!({ "output": "olleH" }));
+    assert_eq!(responses["3"]["result"], json!({ "output": "Hello" }));
+    assert_eq!(
+        responses["4"]["result"],
+        json!({ "output": { "string": "olleH" } })
+    );
+    assert_eq!(
+        responses["5"]["result"],
+        json!({ "output": { "string": "olleH" } })
+    );
+    assert_eq!(responses["6"]["result"], json!({ "cancelled": false }));
+}
+
+#[test]
+fn daemon_errors() {
+    let (daemon, _td) = daemon();
+
+    let responses = exchange(
+        &daemon,
+        &[
+            request(1, "run", json!({ "pipeline": "missing", "input": "Hello" })),
+            request(
+                2,
+                "run",
+                json!({ "pipeline": "reverse", "input": 42, "format": "text" }),
+            ),
+            request(3, "frobnicate", Value::Null),
+            br#"{"jsonrpc":"2.0","method":"list_pipelines"}"#.to_vec(),
+            br#"{"jsonrpc":"2.0","method":"run","params":{"pipeline":"reverse","input":"Hello"}}"#
+                .to_vec(),
+            request(4, "cancel", json!({ "id": null })),
+            b"{not json".to_vec(),
+        ],
+    );
+
+    assert_eq!(responses.len(), 5);
+    // Notifications aren't answered and can't be cancelled
+    assert_eq!(responses["4"]["result"], json!({ "cancelled": false }));
+    assert_eq!(responses["1"]["error"]["code"], INVALID_PARAMS);
+    assert_eq!(responses["2"]["error"]["code"], INVALID_PARAMS);
+    assert_eq!(responses["3"]["error"]["code"], METHOD_NOT_FOUND);
+    assert_eq!(responses["null"]["error"]["code"], PARSE_ERROR);
+}
+
+#[test]
+fn daemon_run_limit() {
+    let td = tempfile::tempdir().unwrap();
+    let daemon = Daemon::new(common::reverse_packages(td.path()), 0);
+
+    let responses = exchange(
+        &daemon,
+        &[
+            request(1, "run", json!({ "pipeline": "reverse", "input": "Hello" })),
+            request(2, "list_pipelines", Value::Null),
+        ],
+    );
+
+    assert_eq!(responses["1"]["error"]["code"], TOO_MANY_RUNS);
+    assert!(responses["2"]["result"].is_array());
+}
+
+#[cfg(unix)]
+#[test]
+fn daemon_unix_shutdown() {
+    use divvun_pipeline::serve::ShutdownHandle;
+    use std::{os::unix::net::UnixStream, thread, time::Duration};
+
+    let (daemon, td) = daemon();
+    let path = td.path().join("daemon.sock");
+    let handle = ShutdownHandle::new();
+    let server = {
+        let (path, handle) = (path.clone(), handle.clone());
+        thread::spawn(move || daemon.serve_unix(&path, handle))
+    };
+
+    let mut stream = loop {
+        match UnixStream::connect(&path) {
+            Ok(stream) => break stream,
+            Err(_) => thread::sleep(Duration::from_millis(10)),
+        }
+    };
+    write_message(&mut stream, &request(1, "list_pipelines", Value::Null)).unwrap();
+    let response: Value =
+        serde_json::from_slice(&read_message(&mut stream).unwrap().unwrap()).unwrap();
+    assert_eq!(response["id"], 1);
+
+    // The idle connection is still open, which mustn't keep the daemon from stopping
+    handle.shutdown();
+    server.join().unwrap().unwrap();
+    assert!(!path.exists());
+    assert!(read_message(&mut stream).unwrap().is_none());
+}
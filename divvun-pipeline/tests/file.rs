@@ -0,0 +1,561 @@
+use divvun_pipeline::{
+    file::{load_pipeline_bytes, load_pipeline_dir, load_pipeline_file, FileLoadError},
+    manifest::{
+        Manifest, ModuleRequirement, DEFAULT_PIPELINE_NAME, MANIFEST_FILE_NAME, MANIFEST_VERSION,
+    },
+    resources::ResourceMetadata,
+};
+use zip::CompressionMethod;
+
+mod common;
+
+const PIPELINE_JSON: &[u8] = br#"[
+    { "module": "reverse_string", "command": "reverse_resource", "parameters": ["yummy_resource"] }
+]"#;
+
+fn manifest_json(resources: Vec<ResourceMetadata>) -> Vec<u8> {
+    serde_json::to_vec(&Manifest {
+        resources,
+        ..Default::default()
+    })
+    .unwrap()
+}
+
+#[test]
+fn zpipe_manifest_verified() {
+    let td = tempfile::tempdir().unwrap();
+    let manifest = manifest_json(vec![ResourceMetadata::new("yummy_resource", b"yummy")]);
+    let path = common::write_zpipe(
+        td.path(),
+        &[
+            (MANIFEST_FILE_NAME, &manifest),
+            ("pipeline.json", PIPELINE_JSON),
+            ("yummy_resource", b"yummy"),
+        ],
+        CompressionMethod::Stored,
+    );
+
+    let file = load_pipeline_file(&path).unwrap();
+    let resources = file.resources;
+    let handle = resources.get("yummy_resource").unwrap();
+    assert_eq!(handle.size(), Some(5));
+    assert_eq!(
+        resources.metadata("yummy_resource").unwrap().sha256,
+        divvun_pipeline::resources::sha256_hex(b"yummy")
+    );
+}
+
+#[test]
+fn zpipe_stored_resource_verified_lazily() {
+    let td = tempfile::tempdir().unwrap();
+    let manifest = manifest_json(vec![ResourceMetadata::new("yummy_resource", b"yummy")]);
+    let path = common::write_zpipe(
+        td.path(),
+        &[
+            (MANIFEST_FILE_NAME, &manifest),
+            ("pipeline.json", PIPELINE_JSON),
+            ("yummy_resource", b"yucky"),
+        ],
+        CompressionMethod::Stored,
+    );
+
+    let file = load_pipeline_file(&path).unwrap();
+    assert!(file.resources.get("yummy_resource").is_err());
+}
+
+#[test]
+fn zpipe_compressed_resource_corrupt() {
+    let td = tempfile::tempdir().unwrap();
+    let manifest = manifest_json(vec![ResourceMetadata::new("yummy_resource", b"yummy")]);
+    let path = common::write_zpipe(
+        td.path(),
+        &[
+            (MANIFEST_FILE_NAME, &manifest),
+            ("pipeline.json", PIPELINE_JSON),
+            ("yummy_resource", b"yucky"),
+        ],
+        CompressionMethod::Deflated,
+    );
+
+    match load_pipeline_file(&path) {
+        Err(FileLoadError::CorruptResource { name, .. }) => assert_eq!(name, "yummy_resource"),
+        _ => panic!("expected corrupt resource"),
+    }
+}
+
+#[test]
+fn zpipe_resource_missing() {
+    let td = tempfile::tempdir().unwrap();
+    let manifest = manifest_json(vec![ResourceMetadata::new("yummy_resource", b"yummy")]);
+    let path = common::write_zpipe(
+        td.path(),
+        &[
+            (MANIFEST_FILE_NAME, &manifest),
+            ("pipeline.json", PIPELINE_JSON),
+        ],
+        CompressionMethod::Stored,
+    );
+
+    match load_pipeline_file(&path) {
+        Err(FileLoadError::MissingResource(name)) => assert_eq!(name, "yummy_resource"),
+        _ => panic!("expected missing resource"),
+    }
+}
+
+#[test]
+fn zpipe_manifest_with_pipeline() {
+    let td = tempfile::tempdir().unwrap();
+    let manifest = serde_json::to_vec(&Manifest {
+        version: MANIFEST_VERSION,
+        name: Some("reverse".into()),
+        language: Some("en".into()),
+        modules: vec![ModuleRequirement {
+            name: "reverse_string".into(),
+            min_version: Some("0.0.1".into()),
+        }],
+        pipeline: Some(serde_json::from_slice(PIPELINE_JSON).unwrap()),
+        resources: vec![ResourceMetadata::new("yummy_resource", b"yummy")],
+        ..Default::default()
+    })
+    .unwrap();
+    let path = common::write_zpipe(
+        td.path(),
+        &[
+            (MANIFEST_FILE_NAME, &manifest),
+            ("yummy_resource", b"yummy"),
+        ],
+        CompressionMethod::Stored,
+    );
+
+    let file = load_pipeline_file(&path).unwrap();
+    assert_eq!(file.manifest.name.as_ref().unwrap(), "reverse");
+    assert_eq!(file.manifest.modules[0].name, "reverse_string");
+    assert_eq!(file.pipeline(None).unwrap().commands().len(), 1);
+}
+
+#[test]
+fn zpipe_manifest_version_unsupported() {
+    let td = tempfile::tempdir().unwrap();
+    let manifest = serde_json::to_vec(&Manifest {
+        version: MANIFEST_VERSION + 1,
+        ..Default::default()
+    })
+    .unwrap();
+    let path = common::write_zpipe(
+        td.path(),
+        &[
+            (MANIFEST_FILE_NAME, &manifest),
+            ("pipeline.json", PIPELINE_JSON),
+        ],
+        CompressionMethod::Stored,
+    );
+
+    match load_pipeline_file(&path) {
+        Err(FileLoadError::UnsupportedManifestVersion { found, supported }) => {
+            assert_eq!(found, MANIFEST_VERSION + 1);
+            assert_eq!(supported, MANIFEST_VERSION);
+        }
+        _ => panic!("expected unsupported manifest version"),
+    }
+}
+
+#[test]
+fn zpipe_legacy_prefers_pipeline_json() {
+    let td = tempfile::tempdir().unwrap();
+    let path = common::write_zpipe(
+        td.path(),
+        &[
+            ("other.json", b"not a pipeline"),
+            ("pipeline.json", PIPELINE_JSON),
+            ("yummy_resource", b"yummy"),
+        ],
+        CompressionMethod::Stored,
+    );
+
+    let file = load_pipeline_file(&path).unwrap();
+    assert_eq!(file.pipeline(None).unwrap().commands().len(), 1);
+}
+
+#[test]
+fn zpipe_legacy_ambiguous() {
+    let td = tempfile::tempdir().unwrap();
+    let path = common::write_zpipe(
+        td.path(),
+        &[("a.json", PIPELINE_JSON), ("b.json", PIPELINE_JSON)],
+        CompressionMethod::Stored,
+    );
+
+    match load_pipeline_file(&path) {
+        Err(FileLoadError::AmbiguousPipeline(mut names)) => {
+            names.sort();
+            assert_eq!(names, vec!["a.json", "b.json"]);
+        }
+        _ => panic!("expected ambiguous pipeline"),
+    }
+}
+
+#[test]
+fn zpipe_named_pipelines() {
+    let td = tempfile::tempdir().unwrap();
+    let pipeline = serde_json::from_slice(PIPELINE_JSON).unwrap();
+    let mut manifest = Manifest {
+        version: MANIFEST_VERSION,
+        ..Default::default()
+    };
+    manifest.pipelines.insert(
+        "reverse".into(),
+        serde_json::from_slice(PIPELINE_JSON).unwrap(),
+    );
+    manifest.pipelines.insert(
+        "reverse-twice".into(),
+        serde_json::from_str(
+            r#"[
+                { "module": "reverse_string", "command": "reverse", "parameters": null },
+                { "module": "reverse_string", "command": "reverse", "parameters": null }
+            ]"#,
+        )
+        .unwrap(),
+    );
+    let manifest = serde_json::to_vec(&manifest).unwrap();
+    let path = common::write_zpipe(
+        td.path(),
+        &[
+            (MANIFEST_FILE_NAME, &manifest),
+            ("yummy_resource", b"yummy"),
+        ],
+        CompressionMethod::Stored,
+    );
+
+    let mut file = load_pipeline_file(&path).unwrap();
+    assert_eq!(file.pipeline_names(), vec!["reverse", "reverse-twice"]);
+    assert_eq!(file.pipeline(Some("reverse")).unwrap().commands().len(), 1);
+    assert_eq!(
+        file.pipeline(Some("reverse-twice"))
+            .unwrap()
+            .commands()
+            .len(),
+        2
+    );
+    match file.pipeline(None) {
+        Err(FileLoadError::AmbiguousPipeline(names)) => assert_eq!(names.len(), 2),
+        _ => panic!("expected ambiguous pipeline"),
+    }
+    match file.pipeline(Some("spell")) {
+        Err(FileLoadError::UnknownPipeline { name, available }) => {
+            assert_eq!(name, "spell");
+            assert_eq!(available.len(), 2);
+        }
+        _ => panic!("expected unknown pipeline"),
+    }
+
+    file.pipelines.insert(
+        DEFAULT_PIPELINE_NAME.into(),
+        divvun_pipeline::pipeline::Pipeline { root: pipeline },
+    );
+    assert_eq!(file.pipeline(None).unwrap().commands().len(), 1);
+}
+
+#[test]
+fn zpipe_from_bytes() {
+    let td = tempfile::tempdir().unwrap();
+    let manifest = manifest_json(vec![
+        ResourceMetadata::new("yummy_resource", b"yummy"),
+        ResourceMetadata::new("yucky_resource", b"yummy"),
+    ]);
+    let path = common::write_zpipe(
+        td.path(),
+        &[
+            (MANIFEST_FILE_NAME, &manifest),
+            ("pipeline.json", PIPELINE_JSON),
+            ("yummy_resource", b"yummy"),
+            ("yucky_resource", b"yucky"),
+        ],
+        CompressionMethod::Stored,
+    );
+    let bytes = std::fs::read(&path).unwrap();
+    drop(td);
+
+    let file = load_pipeline_bytes(&bytes[..]).unwrap();
+    assert!(file.temp_dir.is_none());
+    let handle = file.resources.get("yummy_resource").unwrap();
+    let data = unsafe { std::slice::from_raw_parts(handle.as_ptr().unwrap(), 5) };
+    assert_eq!(data, b"yummy");
+    assert!(file.resources.get("yucky_resource").is_err());
+}
+
+#[test]
+fn zpipe_from_bytes_compressed() {
+    let td = tempfile::tempdir().unwrap();
+    let path = common::write_zpipe(
+        td.path(),
+        &[
+            ("pipeline.json", PIPELINE_JSON),
+            ("yummy_resource", b"yummy"),
+        ],
+        CompressionMethod::Deflated,
+    );
+
+    let file = load_pipeline_bytes(std::fs::read(&path).unwrap()).unwrap();
+    assert!(file.temp_dir.is_none());
+    assert_eq!(
+        file.resources.get("yummy_resource").unwrap().size(),
+        Some(5)
+    );
+}
+
+#[test]
+fn zpipe_from_bytes_invalid() {
+    match load_pipeline_bytes(&b"not a zip"[..]) {
+        Err(FileLoadError::InvalidArchive(_)) => {}
+        _ => panic!("expected invalid archive"),
+    }
+}
+
+#[test]
+fn pipeline_from_dir() {
+    let td = tempfile::tempdir().unwrap();
+    let manifest = manifest_json(vec![
+        ResourceMetadata::new("yummy_resource", b"yummy"),
+        ResourceMetadata::new("yucky_resource", b"yummy"),
+    ]);
+    std::fs::write(td.path().join(MANIFEST_FILE_NAME), &manifest).unwrap();
+    std::fs::write(td.path().join("pipeline.json"), PIPELINE_JSON).unwrap();
+    std::fs::write(td.path().join("yummy_resource"), b"yummy").unwrap();
+    std::fs::write(td.path().join("yucky_resource"), b"yucky").unwrap();
+
+    let file = load_pipeline_dir(td.path()).unwrap();
+    assert_eq!(file.pipeline(None).unwrap().commands().len(), 1);
+    assert_eq!(
+        file.resources.get("yummy_resource").unwrap().size(),
+        Some(5)
+    );
+    assert!(file.resources.get("yucky_resource").is_err());
+
+    std::fs::remove_file(td.path().join("yummy_resource")).unwrap();
+    match load_pipeline_dir(td.path()) {
+        Err(FileLoadError::MissingResource(name)) => assert_eq!(name, "yummy_resource"),
+        _ => panic!("expected missing resource"),
+    }
+}
+
+#[test]
+fn pipeline_from_dir_not_a_directory() {
+    let td = tempfile::tempdir().unwrap();
+    let path = td.path().join("pipeline.json");
+    std::fs::write(&path, PIPELINE_JSON).unwrap();
+
+    match load_pipeline_dir(&path) {
+        Err(FileLoadError::NotADirectory(_)) => {}
+        _ => panic!("expected not a directory"),
+    }
+}
+
+#[test]
+fn zpipe_subdirectories() {
+    let td = tempfile::tempdir().unwrap();
+    let manifest = manifest_json(vec![
+        ResourceMetadata::new("grammar/disambiguator.bin", b"yummy"),
+        ResourceMetadata::new("speller/errmodel.hfst", b"yucky"),
+    ]);
+    let path = common::write_zpipe(
+        td.path(),
+        &[
+            (MANIFEST_FILE_NAME, &manifest),
+            ("pipeline.json", PIPELINE_JSON),
+            ("grammar/", b""),
+            ("grammar/disambiguator.bin", b"yummy"),
+            ("speller/./errmodel.hfst", b"yucky"),
+            ("speller/settings.json", b"{}"),
+        ],
+        CompressionMethod::Deflated,
+    );
+
+    let file = load_pipeline_file(&path).unwrap();
+    assert_eq!(file.pipeline(None).unwrap().commands().len(), 1);
+    assert_eq!(
+        file.resources
+            .get("grammar/disambiguator.bin")
+            .unwrap()
+            .size(),
+        Some(5)
+    );
+    assert!(file.resources.get("speller/errmodel.hfst").is_ok());
+    assert!(file.resources.contains("speller/settings.json"));
+}
+
+#[test]
+fn zpipe_path_traversal() {
+    for name in &[
+        "../yummy_resource",
+        "grammar/../../yummy_resource",
+        "/etc/passwd",
+        "C:/yummy",
+    ] {
+        let td = tempfile::tempdir().unwrap();
+        let path = common::write_zpipe(
+            td.path(),
+            &[("pipeline.json", PIPELINE_JSON), (name, b"yummy")],
+            CompressionMethod::Stored,
+        );
+
+        match load_pipeline_file(&path) {
+            Err(FileLoadError::PathTraversal(_)) => {}
+            _ => panic!("expected {} to be rejected", name),
+        }
+    }
+}
+
+#[test]
+fn pipeline_from_dir_subdirectories() {
+    let td = tempfile::tempdir().unwrap();
+    std::fs::create_dir_all(td.path().join("grammar/rules")).unwrap();
+    std::fs::write(td.path().join("pipeline.json"), PIPELINE_JSON).unwrap();
+    std::fs::write(td.path().join("grammar/rules/disambiguator.bin"), b"yummy").unwrap();
+    std::fs::write(td.path().join("grammar/settings.json"), b"{}").unwrap();
+
+    let file = load_pipeline_dir(td.path()).unwrap();
+    assert_eq!(file.pipeline(None).unwrap().commands().len(), 1);
+    assert!(file.resources.contains("grammar/rules/disambiguator.bin"));
+    assert!(file.resources.contains("grammar/settings.json"));
+}
+
+/// Small deterministic generator, so failures can be reproduced
+struct Lcg(u64);
+
+impl Lcg {
+    fn next(&mut self) -> u64 {
+        self.0 = self
+            .0
+            .wrapping_mul(6364136223846793005)
+            .wrapping_add(1442695040888963407);
+        self.0 >> 33
+    }
+}
+
+fn fuzz_archive(compression_method: CompressionMethod) -> Vec<u8> {
+    let td = tempfile::tempdir().unwrap();
+    let manifest = manifest_json(vec![ResourceMetadata::new("yummy_resource", b"yummy")]);
+    let path = common::write_zpipe(
+        td.path(),
+        &[
+            (MANIFEST_FILE_NAME, &manifest),
+            ("pipeline.json", PIPELINE_JSON),
+            ("grammar/yummy", b"yummy yummy yummy"),
+            ("yummy_resource", b"yummy"),
+        ],
+        compression_method,
+    );
+    std::fs::read(&path).unwrap()
+}
+
+/// Loading must fail gracefully, and whatever loads must be safe to use
+fn load_corrupted(bytes: Vec<u8>) {
+    let td = tempfile::tempdir().unwrap();
+    let path = td.path().join("corrupt.zpipe");
+    std::fs::write(&path, &bytes).unwrap();
+
+    for result in vec![load_pipeline_bytes(bytes), load_pipeline_file(&path)] {
+        match result {
+            Ok(file) => {
+                for name in &["yummy_resource", "grammar/yummy"] {
+                    if let Ok(handle) = file.resources.get(name) {
+                        let size = handle.size().unwrap();
+                        let data =
+                            unsafe { std::slice::from_raw_parts(handle.as_ptr().unwrap(), size) };
+                        // Touch every byte, a mapping past the end of the file would crash here
+                        let sum: usize = data.iter().map(|b| *b as usize).sum();
+                        assert!(sum <= 255 * size);
+                    }
+                }
+            }
+            Err(e) => {
+                // Every error has to describe itself
+                assert!(!e.to_string().is_empty());
+            }
+        }
+    }
+}
+
+#[test]
+fn zpipe_truncated() {
+    for compression_method in vec![CompressionMethod::Stored, CompressionMethod::Deflated] {
+        let bytes = fuzz_archive(compression_method);
+        for len in 0..bytes.len() {
+            load_corrupted(bytes[..len].to_vec());
+        }
+    }
+}
+
+#[test]
+fn zpipe_corrupted_bytes() {
+    let mut rng = Lcg(0x5eed);
+    for compression_method in vec![CompressionMethod::Stored, CompressionMethod::Deflated] {
+        let bytes = fuzz_archive(compression_method);
+        for _ in 0..500 {
+            let mut corrupted = bytes.clone();
+            for _ in 0..1 + rng.next() % 4 {
+                let index = rng.next() as usize % corrupted.len();
+                corrupted[index] = rng.next() as u8;
+            }
+            load_corrupted(corrupted);
+        }
+    }
+}
+
+#[test]
+fn zpipe_invalid_pipeline_names_entry() {
+    let td = tempfile::tempdir().unwrap();
+    let path = common::write_zpipe(
+        td.path(),
+        &[("pipeline.json", b"[{ \"module\": ")],
+        CompressionMethod::Stored,
+    );
+
+    match load_pipeline_file(&path) {
+        Err(e @ FileLoadError::InvalidPipeline { .. }) => {
+            assert!(e.to_string().contains("pipeline.json"));
+            assert!(std::error::Error::source(&e).is_some());
+        }
+        _ => panic!("expected invalid pipeline"),
+    }
+}
+
+#[test]
+fn zpipe_legacy_yaml_resource() {
+    let td = tempfile::tempdir().unwrap();
+    let path = common::write_zpipe(
+        td.path(),
+        &[
+            ("pipeline.json", PIPELINE_JSON),
+            ("settings.yaml", b"speed: fast\n"),
+            ("yummy_resource", b"yummy"),
+        ],
+        CompressionMethod::Stored,
+    );
+
+    let file = load_pipeline_file(&path).unwrap();
+    assert_eq!(file.pipeline(None).unwrap().commands().len(), 1);
+    assert_eq!(
+        file.resources.get("settings.yaml").unwrap().size(),
+        Some(12)
+    );
+}
+
+#[test]
+fn zpipe_legacy_yaml_pipeline() {
+    let td = tempfile::tempdir().unwrap();
+    let path = common::write_zpipe(
+        td.path(),
+        &[
+            (
+                "pipeline.yaml",
+                b"serial:\n  - run: { module: reverse_string, command: reverse }\n",
+            ),
+            ("yummy_resource", b"yummy"),
+        ],
+        CompressionMethod::Stored,
+    );
+
+    let file = load_pipeline_file(&path).unwrap();
+    assert_eq!(file.pipeline(None).unwrap().commands().len(), 1);
+}
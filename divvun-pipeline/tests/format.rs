@@ -0,0 +1,134 @@
+use divvun_pipeline::{
+    format::{parse_pipeline, pipeline_schema, PipelineFormat},
+    pipeline::{Pipeline, PipelineNode, PipelineNodeSerial},
+};
+
+const UNTAGGED_JSON: &str = r#"[
+    { "module": "tokenize", "command": "tokenize", "parameters": null },
+    [
+        { "module": "reverse_string", "command": "reverse", "parameters": null },
+        [
+            { "module": "reverse_string", "command": "reverse", "parameters": null },
+            { "module": "reverse_string", "command": "uppercase", "parameters": ["yummy"] }
+        ]
+    ]
+]"#;
+
+const TAGGED_JSON: &str = r#"{ "serial": [
+    { "run": { "module": "tokenize", "command": "tokenize" } },
+    { "parallel": [
+        { "run": { "module": "reverse_string", "command": "reverse" } },
+        { "serial": [
+            { "run": { "module": "reverse_string", "command": "reverse" } },
+            { "run": { "module": "reverse_string", "command": "uppercase", "parameters": ["yummy"] } }
+        ] }
+    ] }
+] }"#;
+
+const TAGGED_YAML: &str = r#"
+serial:
+  - run: { module: tokenize, command: tokenize }
+  - parallel:
+      - run: { module: reverse_string, command: reverse }
+      - serial:
+          - run: { module: reverse_string, command: reverse }
+          - run: { module: reverse_string, command: uppercase, parameters: [yummy] }
+"#;
+
+const TAGGED_TOML: &str = r#"
+[[serial]]
+run = { module = "tokenize", command = "tokenize" }
+
+[[serial]]
+[[serial.parallel]]
+run = { module = "reverse_string", command = "reverse" }
+
+[[serial.parallel]]
+serial = [
+    { run = { module = "reverse_string", command = "reverse" } },
+    { run = { module = "reverse_string", command = "uppercase", parameters = ["yummy"] } },
+]
+"#;
+
+fn untagged(root: &PipelineNodeSerial) -> serde_json::Value {
+    serde_json::to_value(root).unwrap()
+}
+
+fn commands(root: PipelineNodeSerial) -> Vec<String> {
+    Pipeline { root }
+        .commands()
+        .into_iter()
+        .map(|command| format!("{}.{}", command.module, command.command))
+        .collect()
+}
+
+#[test]
+fn format_tagged_matches_untagged() {
+    let expected = parse_pipeline(UNTAGGED_JSON, PipelineFormat::Json).unwrap();
+
+    for (text, format) in vec![
+        (TAGGED_JSON, PipelineFormat::Json),
+        (TAGGED_YAML, PipelineFormat::Yaml),
+        (TAGGED_TOML, PipelineFormat::Toml),
+    ] {
+        let root = parse_pipeline(text, format).unwrap();
+        assert_eq!(
+            commands(root.clone()),
+            commands(expected.clone()),
+            "{:?}",
+            format
+        );
+        assert_eq!(
+            serde_json::to_value(PipelineNode::from(&root)).unwrap(),
+            serde_json::to_value(PipelineNode::from(&expected)).unwrap(),
+            "{:?}",
+            format
+        );
+    }
+}
+
+#[test]
+fn format_untagged_roundtrip() {
+    let root = parse_pipeline(UNTAGGED_JSON, PipelineFormat::Json).unwrap();
+    let tagged = serde_json::to_string(&PipelineNode::from(&root)).unwrap();
+    let reparsed = parse_pipeline(&tagged, PipelineFormat::Json).unwrap();
+    assert_eq!(untagged(&root), untagged(&reparsed));
+
+    let yaml = parse_pipeline(
+        "- { module: reverse_string, command: reverse, parameters: ~ }",
+        PipelineFormat::Yaml,
+    )
+    .unwrap();
+    assert_eq!(commands(yaml), vec!["reverse_string.reverse"]);
+}
+
+#[test]
+fn format_invalid() {
+    assert!(parse_pipeline(r#"{ "sequence": [] }"#, PipelineFormat::Json).is_err());
+    assert!(parse_pipeline("serial = 1", PipelineFormat::Toml).is_err());
+    assert!(parse_pipeline("- run: {}", PipelineFormat::Yaml).is_err());
+}
+
+#[test]
+fn format_from_path() {
+    use std::path::Path;
+
+    assert_eq!(
+        PipelineFormat::from_path(Path::new("pipeline.yml")),
+        Some(PipelineFormat::Yaml)
+    );
+    assert_eq!(
+        PipelineFormat::from_path(Path::new("grammar.toml")),
+        Some(PipelineFormat::Toml)
+    );
+    assert_eq!(PipelineFormat::from_path(Path::new("yummy_resource")), None);
+}
+
+#[test]
+fn format_schema() {
+    let schema = serde_json::to_value(pipeline_schema()).unwrap();
+    let text = schema.to_string();
+    for tag in &["serial", "parallel", "run", "module", "command"] {
+        assert!(text.contains(tag), "schema is missing {}", tag);
+    }
+}
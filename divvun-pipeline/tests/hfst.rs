@@ -47,3 +47,51 @@ fn hfst_module() {
 
     assert_eq!(text.get_string().unwrap(), "\"<Hello>\"\n\t\"heallat\" Ex/V Ex/IV Der/PassS V IV Ind Prs ConNeg <W:0.0>\n\t\"heallat\" Ex/V Ex/IV Der/PassS V IV Ind Prs Sg3 <W:0.0>\n\t\"heallat\" V IV Imprt ConNegII <W:0.0>\n: \n\"<world>\"\n\t\"world\" ?\n: \n\"<what>\"\n\t\"what\" ?\n: \n\"<is>\"\n\t\"is\" ?\n: \n\"<going>\"\n\t\"going\" ?\n: \n\"<on>\"\n\t\"on\" Adv <W:0.0>\n\"<,>\"\n\t\",\" CLB <W:0.0>\n: \n\"<please>\"\n\t\"please\" ?\n: \n\"<correct>\"\n\t\"correct\" ?\n: \n\"<me>\"\n\t\"me\" ?\n");
 }
+
+#[test]
+// #[ignore]
+fn hfst_module_parallel_shared_pmatch() {
+    let (registry, _allocator, resources) = common::setup_test_registry(AllocationType::Memory);
+    let registry = Arc::new(registry);
+    let mut res_path = common::get_project_root();
+    res_path.push("se_zcheck/tokeniser-gramcheck-gt-desc.pmhfst");
+    resources.add_resource(
+        "pmatch_file",
+        LoadableResource::from(Resource::new_file(&res_path)),
+    );
+
+    let pipeline = Pipeline {
+        root: serde_json::from_str(
+            r#"[
+                [
+                    { "module": "hfst", "command": "tokenize", "parameters": ["pmatch_file"] },
+                    { "module": "hfst", "command": "tokenize", "parameters": ["pmatch_file"] }
+                ]
+            ]"#,
+        )
+        .unwrap(),
+    };
+
+    let text = util::message_to_vec(divvun_schema::capnp_message!(string::Builder, builder => {
+        builder.set_string("Hello world");
+    }))
+    .unwrap();
+
+    let output = async_std::task::block_on(pipeline.run(
+        registry,
+        Arc::new(vec![Arc::new(PipelineData {
+            data: text.as_ptr(),
+            size: text.len(),
+        })]),
+    ))
+    .unwrap();
+
+    assert_eq!(output.len(), 2);
+    let first = util::read_message::<string::Owned>(output[0].data, output[0].size).unwrap();
+    let second = util::read_message::<string::Owned>(output[1].data, output[1].size).unwrap();
+    assert_eq!(
+        first.get().unwrap().get_string().unwrap(),
+        second.get().unwrap().get_string().unwrap()
+    );
+    assert_eq!(resources.loaded_resources_count(), 0);
+}
@@ -0,0 +1,335 @@
+use std::{collections::BTreeMap, fs, path::Path};
+
+use divvun_pipeline::{
+    file::{load_pipeline_file, FileLoadError, PipelineFile},
+    manifest::{ModuleRequirement, MANIFEST_FILE_NAME},
+    pipeline::{find_placeholder, substitute_placeholders, PipelineNodeSerial},
+};
+use serde_json::json;
+use zip::CompressionMethod;
+
+mod common;
+
+fn write_manifest_zpipe(dir: &Path, manifest: serde_json::Value, resources: &[(&str, &[u8])]) {
+    fs::create_dir_all(dir).unwrap();
+    let manifest = serde_json::to_vec(&manifest).unwrap();
+    let mut entries = vec![(MANIFEST_FILE_NAME, &manifest[..])];
+    entries.extend_from_slice(resources);
+    common::write_zpipe(dir, &entries, CompressionMethod::Stored);
+}
+
+fn parameters(file: &PipelineFile, name: &str) -> Vec<Vec<String>> {
+    file.pipeline(Some(name))
+        .unwrap()
+        .commands()
+        .into_iter()
+        .map(|command| command.parameters.clone().unwrap_or_default())
+        .collect()
+}
+
+#[test]
+fn include_from_same_archive() {
+    let td = tempfile::tempdir().unwrap();
+    write_manifest_zpipe(
+        td.path(),
+        json!({
+            "version": 1,
+            "pipelines": {
+                "analyse": { "serial": [
+                    { "run": { "module": "hfst", "command": "analyse", "parameters": ["${analyser}"] } },
+                    { "run": { "module": "cg3", "command": "disambiguate", "parameters": ["${grammar}"] } }
+                ] },
+                "default": { "serial": [
+                    { "run": { "module": "tokenize", "command": "tokenize", "parameters": null } },
+                    { "include": {
+                        "pipeline": "analyse",
+                        "parameters": { "analyser": "se/analyser.hfstol", "grammar": "se/disambiguator.bin" }
+                    } }
+                ] }
+            }
+        }),
+        &[],
+    );
+
+    let file = load_pipeline_file(&td.path().join("test.zpipe")).unwrap();
+    assert_eq!(
+        parameters(&file, "default"),
+        vec![
+            vec![],
+            vec!["se/analyser.hfstol".to_string()],
+            vec!["se/disambiguator.bin".to_string()],
+        ]
+    );
+    // The included pipeline itself is left untouched
+    assert_eq!(
+        parameters(&file, "analyse"),
+        vec![
+            vec!["${analyser}".to_string()],
+            vec!["${grammar}".to_string()]
+        ]
+    );
+}
+
+#[test]
+fn include_nested_parameters() {
+    let td = tempfile::tempdir().unwrap();
+    write_manifest_zpipe(
+        td.path(),
+        json!({
+            "version": 1,
+            "pipelines": {
+                "inner": { "run": { "module": "hfst", "command": "analyse", "parameters": ["${path}"] } },
+                "middle": { "include": { "pipeline": "inner", "parameters": { "path": "${lang}/analyser.hfstol" } } },
+                "default": { "parallel": [
+                    { "include": { "pipeline": "middle", "parameters": { "lang": "sma" } } },
+                    { "include": { "pipeline": "middle", "parameters": { "lang": "sme" } } }
+                ] }
+            }
+        }),
+        &[],
+    );
+
+    let file = load_pipeline_file(&td.path().join("test.zpipe")).unwrap();
+    assert_eq!(
+        parameters(&file, "default"),
+        vec![
+            vec!["sma/analyser.hfstol".to_string()],
+            vec!["sme/analyser.hfstol".to_string()],
+        ]
+    );
+}
+
+#[test]
+fn include_from_other_file() {
+    let td = tempfile::tempdir().unwrap();
+    write_manifest_zpipe(
+        &td.path().join("shared"),
+        json!({
+            "version": 1,
+            "pipelines": {
+                "reverse": { "run": { "module": "reverse_string", "command": "reverse_resource", "parameters": ["shared_resource"] } }
+            }
+        }),
+        &[("shared_resource", b"shared")],
+    );
+    write_manifest_zpipe(
+        &td.path().join("main"),
+        json!({
+            "version": 1,
+            "pipeline": { "serial": [
+                { "include": { "pipeline": "reverse", "file": "../shared/test.zpipe" } }
+            ] }
+        }),
+        &[],
+    );
+
+    let file = load_pipeline_file(&td.path().join("main/test.zpipe")).unwrap();
+    assert_eq!(
+        parameters(&file, "default"),
+        vec![vec!["shared_resource".to_string()]]
+    );
+    assert_eq!(file.included.len(), 1);
+    assert_eq!(
+        file.resources.get("shared_resource").unwrap().size(),
+        Some(6)
+    );
+}
+
+#[test]
+fn include_resource_conflict() {
+    let td = tempfile::tempdir().unwrap();
+    write_manifest_zpipe(
+        &td.path().join("shared"),
+        json!({
+            "version": 1,
+            "pipelines": {
+                "reverse": { "run": { "module": "reverse_string", "command": "reverse_resource", "parameters": ["data"] } }
+            }
+        }),
+        &[("data", b"shared")],
+    );
+    write_manifest_zpipe(
+        &td.path().join("main"),
+        json!({
+            "version": 1,
+            "pipeline": { "include": { "pipeline": "reverse", "file": "../shared/test.zpipe" } }
+        }),
+        &[("data", b"main")],
+    );
+
+    match load_pipeline_file(&td.path().join("main/test.zpipe")) {
+        Err(FileLoadError::IncludedResources { path, .. }) => {
+            assert!(path.ends_with("shared/test.zpipe"))
+        }
+        Err(e) => panic!("unexpected error: {}", e),
+        Ok(_) => panic!("conflicting resource was accepted"),
+    }
+}
+
+#[test]
+fn include_module_requirements() {
+    let td = tempfile::tempdir().unwrap();
+    write_manifest_zpipe(
+        &td.path().join("shared"),
+        json!({
+            "version": 1,
+            "modules": [{ "name": "reverse_string", "min_version": "1.2" }, { "name": "hfst" }],
+            "pipelines": {
+                "reverse": { "run": { "module": "reverse_string", "command": "reverse" } }
+            }
+        }),
+        &[],
+    );
+    write_manifest_zpipe(
+        &td.path().join("main"),
+        json!({
+            "version": 1,
+            "modules": [{ "name": "reverse_string", "min_version": "1.0" }],
+            "pipeline": { "include": { "pipeline": "reverse", "file": "../shared/test.zpipe" } }
+        }),
+        &[],
+    );
+
+    let file = load_pipeline_file(&td.path().join("main/test.zpipe")).unwrap();
+    assert_eq!(
+        file.manifest.modules,
+        vec![
+            ModuleRequirement {
+                name: "reverse_string".to_string(),
+                min_version: Some("1.2".to_string()),
+            },
+            ModuleRequirement {
+                name: "hfst".to_string(),
+                min_version: None,
+            },
+        ]
+    );
+}
+
+#[test]
+fn include_unknown_pipeline() {
+    let td = tempfile::tempdir().unwrap();
+    write_manifest_zpipe(
+        td.path(),
+        json!({
+            "version": 1,
+            "pipeline": { "include": { "pipeline": "missing" } }
+        }),
+        &[],
+    );
+
+    match load_pipeline_file(&td.path().join("test.zpipe")) {
+        Err(FileLoadError::UnknownInclude {
+            name,
+            included_from,
+        }) => {
+            assert!(name.ends_with("missing"));
+            assert!(included_from.ends_with("default"));
+        }
+        Err(e) => panic!("unexpected error: {}", e),
+        Ok(_) => panic!("unknown include was accepted"),
+    }
+}
+
+#[test]
+fn include_missing_file() {
+    let td = tempfile::tempdir().unwrap();
+    write_manifest_zpipe(
+        td.path(),
+        json!({
+            "version": 1,
+            "pipeline": { "include": { "pipeline": "default", "file": "missing.zpipe" } }
+        }),
+        &[],
+    );
+
+    match load_pipeline_file(&td.path().join("test.zpipe")) {
+        Err(FileLoadError::IncludedFile { .. }) => {}
+        Err(e) => panic!("unexpected error: {}", e),
+        Ok(_) => panic!("missing include file was accepted"),
+    }
+}
+
+#[test]
+fn include_cycle() {
+    let td = tempfile::tempdir().unwrap();
+    write_manifest_zpipe(
+        td.path(),
+        json!({
+            "version": 1,
+            "pipelines": {
+                "a": { "serial": [{ "include": { "pipeline": "b" } }] },
+                "b": { "parallel": [{ "include": { "pipeline": "a" } }] }
+            }
+        }),
+        &[],
+    );
+
+    match load_pipeline_file(&td.path().join("test.zpipe")) {
+        Err(FileLoadError::IncludeCycle(names)) => {
+            assert_eq!(names.len(), 3);
+            assert!(names[0].ends_with(":a"));
+            assert!(names[1].ends_with(":b"));
+            assert!(names[2].ends_with(":a"));
+        }
+        Err(e) => panic!("unexpected error: {}", e),
+        Ok(_) => panic!("include cycle was accepted"),
+    }
+}
+
+#[test]
+fn include_cycle_across_files() {
+    let td = tempfile::tempdir().unwrap();
+    write_manifest_zpipe(
+        &td.path().join("one"),
+        json!({
+            "version": 1,
+            "pipeline": { "include": { "pipeline": "default", "file": "../two/test.zpipe" } }
+        }),
+        &[],
+    );
+    write_manifest_zpipe(
+        &td.path().join("two"),
+        json!({
+            "version": 1,
+            "pipeline": { "include": { "pipeline": "default", "file": "../one/test.zpipe" } }
+        }),
+        &[],
+    );
+
+    match load_pipeline_file(&td.path().join("one/test.zpipe")) {
+        Err(FileLoadError::IncludeCycle(names)) => assert_eq!(names.len(), 3),
+        Err(e) => panic!("unexpected error: {}", e),
+        Ok(_) => panic!("include cycle was accepted"),
+    }
+}
+
+#[test]
+fn include_is_written_tagged() {
+    let root: PipelineNodeSerial = serde_json::from_value(json!([
+        { "include": { "pipeline": "analyse", "parameters": { "lang": "se" } } }
+    ]))
+    .unwrap();
+
+    assert_eq!(
+        serde_json::to_value(&root).unwrap(),
+        json!([{ "include": { "pipeline": "analyse", "parameters": { "lang": "se" } } }])
+    );
+}
+
+#[test]
+fn placeholders_substituted() {
+    let mut values = BTreeMap::new();
+    values.insert("lang".to_string(), "sme".to_string());
+
+    assert_eq!(
+        substitute_placeholders("${lang}/${lang}.hfstol", &values),
+        "sme/sme.hfstol"
+    );
+    assert_eq!(
+        substitute_placeholders("${other}/${lang", &values),
+        "${other}/${lang"
+    );
+    assert_eq!(find_placeholder("${other}/${lang"), Some("other"));
+    assert_eq!(find_placeholder("sme/${lang"), None);
+}
@@ -1,3 +1,5 @@
+use std::path::Path;
+
 use divvun_pipeline::{
     module::*,
     resources::{LoadableResource, Resource},
@@ -100,3 +102,93 @@ fn load_run_input_reverse_resource() {
 
     assert_eq!(text.get_string().unwrap(), "olleH");
 }
+
+#[test]
+fn load_run_input_reverse_resource_missing_file() {
+    let (registry, _allocator, resources) = common::setup_test_registry(AllocationType::Memory);
+
+    resources.add_resource(
+        "missing",
+        LoadableResource::from(Resource::new_file(Path::new("does/not/exist.bin"))),
+    );
+
+    let module = registry.get_module("reverse_string").unwrap();
+
+    let parameters = vec!["missing".to_string()];
+    let result = module.call_run("reverse_resource", Some(&parameters), vec![], vec![]);
+
+    let error = result.unwrap_err();
+    let error = error.downcast_ref::<ModuleRunError>().unwrap();
+    let message = error.pipeline_error().unwrap().get().unwrap();
+    assert!(message.get_message().unwrap().contains("missing"));
+    assert_eq!(resources.loaded_resources_count(), 0);
+}
+
+#[test]
+fn load_run_input_reverse_resource_concurrent() {
+    let (registry, _allocator, resources) = common::setup_test_registry(AllocationType::Memory);
+
+    let my_data = "Hello".as_bytes();
+    resources.add_resource(
+        "lol",
+        LoadableResource::from(Resource::Bytes(my_data.to_owned())),
+    );
+
+    let module = registry.get_module("reverse_string").unwrap();
+
+    let threads = (0..8)
+        .map(|_| {
+            let module = module.clone();
+            std::thread::spawn(move || {
+                for _ in 0..20 {
+                    let parameters = vec!["lol".to_string()];
+                    let result =
+                        module.call_run("reverse_resource", Some(&parameters), vec![], vec![]);
+                    assert!(result.is_ok());
+                }
+            })
+        })
+        .collect::<Vec<_>>();
+
+    for thread in threads {
+        thread.join().unwrap();
+    }
+
+    assert_eq!(resources.loaded_resources_count(), 0);
+}
+
+#[test]
+fn validate_pipeline_against_modules() {
+    use divvun_pipeline::{
+        pack::{validate_pipeline, PackError},
+        pipeline::Pipeline,
+    };
+
+    let (registry, ..) = common::setup_test_registry(AllocationType::Memory);
+
+    let pipeline = |json: &str| Pipeline {
+        root: serde_json::from_str(json).unwrap(),
+    };
+
+    validate_pipeline(
+        &pipeline(r#"[{ "module": "reverse_string", "command": "reverse", "parameters": null }]"#),
+        &registry,
+    )
+    .unwrap();
+
+    match validate_pipeline(
+        &pipeline(r#"[{ "module": "reverse_string", "command": "nope", "parameters": null }]"#),
+        &registry,
+    ) {
+        Err(PackError::UnknownCommand { command, .. }) => assert_eq!(command, "nope"),
+        _ => panic!("expected unknown command"),
+    }
+
+    match validate_pipeline(
+        &pipeline(r#"[{ "module": "missing", "command": "reverse", "parameters": null }]"#),
+        &registry,
+    ) {
+        Err(PackError::MissingModule { name, .. }) => assert_eq!(name, "missing"),
+        _ => panic!("expected missing module"),
+    }
+}
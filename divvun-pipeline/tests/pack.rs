@@ -0,0 +1,223 @@
+use std::fs;
+
+use divvun_pipeline::{
+    file::load_pipeline_file,
+    manifest::{Manifest, ModuleRequirement},
+    pack::{inspect_pipeline_file, unpack_pipeline_file, PackError, PipelinePacker},
+    resources::ResourceMetadata,
+};
+
+const PIPELINE_JSON: &str = r#"[
+    { "module": "reverse_string", "command": "reverse_resource", "parameters": ["yummy_resource"] }
+]"#;
+
+fn packer() -> PipelinePacker {
+    PipelinePacker::new(Manifest {
+        name: Some("reverse".into()),
+        pipeline: Some(serde_json::from_str(PIPELINE_JSON).unwrap()),
+        ..Default::default()
+    })
+}
+
+#[test]
+fn pack_load_roundtrip() {
+    let td = tempfile::tempdir().unwrap();
+    fs::write(td.path().join("yummy"), b"yummy").unwrap();
+    fs::write(td.path().join("yucky"), b"yucky").unwrap();
+
+    let mut packer = packer();
+    packer
+        .add_resource("yummy_resource", &td.path().join("yummy"))
+        .unwrap();
+    packer
+        .add_resource("yucky_resource", &td.path().join("yucky"))
+        .unwrap();
+
+    let path = td.path().join("test.zpipe");
+    let manifest = packer.write(&path).unwrap();
+    assert_eq!(manifest.resources.len(), 2);
+    // Only the resource the pipeline passes to a command gets its module
+    assert_eq!(
+        manifest.resource("yummy_resource").unwrap().module,
+        Some("reverse_string".to_string())
+    );
+    assert_eq!(manifest.resource("yucky_resource").unwrap().module, None);
+
+    let info = inspect_pipeline_file(&path).unwrap();
+    assert_eq!(info.manifest.unwrap().name.unwrap(), "reverse");
+    for entry in info
+        .entries
+        .iter()
+        .filter(|e| e.name.ends_with("_resource"))
+    {
+        assert!(entry.is_aligned(), "{} is not aligned", entry.name);
+    }
+
+    let file = load_pipeline_file(&path).unwrap();
+    assert_eq!(file.pipeline(None).unwrap().commands().len(), 1);
+    let handle = file.resources.get("yummy_resource").unwrap();
+    let data = unsafe { std::slice::from_raw_parts(handle.as_ptr().unwrap(), 5) };
+    assert_eq!(data, b"yummy");
+}
+
+#[test]
+fn pack_keeps_manifest_metadata() {
+    let td = tempfile::tempdir().unwrap();
+    fs::write(td.path().join("yummy"), b"yummy").unwrap();
+
+    let mut packer = PipelinePacker::new(Manifest {
+        pipeline: Some(serde_json::from_str(PIPELINE_JSON).unwrap()),
+        modules: vec![ModuleRequirement {
+            name: "reverse_string".into(),
+            min_version: Some("0.2".into()),
+        }],
+        resources: vec![ResourceMetadata {
+            name: "yummy_resource".into(),
+            sha256: "stale".into(),
+            size: 0,
+            media_type: Some("text/plain".into()),
+            module: Some("reverse_string".into()),
+        }],
+        ..Default::default()
+    });
+    packer
+        .add_resource("yummy_resource", &td.path().join("yummy"))
+        .unwrap();
+
+    let manifest = packer.write(&td.path().join("test.zpipe")).unwrap();
+    let resource = manifest.resource("yummy_resource").unwrap();
+    assert_eq!(resource.size, 5);
+    assert_eq!(resource.media_type.as_ref().unwrap(), "text/plain");
+    assert_eq!(resource.module.as_ref().unwrap(), "reverse_string");
+    assert_eq!(
+        manifest.modules,
+        vec![ModuleRequirement {
+            name: "reverse_string".into(),
+            min_version: Some("0.2".into()),
+        }]
+    );
+}
+
+#[test]
+fn pack_lists_modules() {
+    let td = tempfile::tempdir().unwrap();
+    let mut pipelines = std::collections::BTreeMap::new();
+    pipelines.insert(
+        "variable".to_string(),
+        serde_json::from_str(r#"[{ "module": "${reverser}", "command": "reverse" }]"#).unwrap(),
+    );
+    let packer = PipelinePacker::new(Manifest {
+        pipeline: Some(
+            serde_json::from_str(
+                r#"[
+                    { "module": "reverse_string", "command": "reverse" },
+                    { "module": "concat_strings", "command": "concat" },
+                    { "module": "reverse_string", "command": "reverse" }
+                ]"#,
+            )
+            .unwrap(),
+        ),
+        pipelines,
+        ..Default::default()
+    });
+
+    let manifest = packer.write(&td.path().join("test.zpipe")).unwrap();
+    let names: Vec<&str> = manifest
+        .modules
+        .iter()
+        .map(|module| module.name.as_str())
+        .collect();
+    assert_eq!(names, vec!["reverse_string", "concat_strings"]);
+}
+
+#[test]
+fn pack_unpack() {
+    let td = tempfile::tempdir().unwrap();
+    fs::write(td.path().join("yummy"), b"yummy").unwrap();
+
+    let mut packer = packer();
+    packer
+        .add_resource("yummy_resource", &td.path().join("yummy"))
+        .unwrap();
+    let path = td.path().join("test.zpipe");
+    packer.write(&path).unwrap();
+
+    let target = td.path().join("unpacked");
+    let mut written = unpack_pipeline_file(&path, &target).unwrap();
+    written.sort();
+    assert_eq!(
+        written,
+        vec![target.join("manifest.json"), target.join("yummy_resource")]
+    );
+    assert_eq!(fs::read(target.join("yummy_resource")).unwrap(), b"yummy");
+}
+
+#[test]
+fn pack_invalid_resources() {
+    let td = tempfile::tempdir().unwrap();
+    let mut packer = packer();
+
+    match packer.add_resource("../yummy", &td.path().join("yummy")) {
+        Err(PackError::InvalidResourceName(_)) => {}
+        _ => panic!("expected invalid resource name"),
+    }
+    match packer.add_resource("manifest.json", &td.path().join("yummy")) {
+        Err(PackError::InvalidResourceName(_)) => {}
+        _ => panic!("expected invalid resource name"),
+    }
+
+    packer
+        .add_resource("yummy", &td.path().join("yummy"))
+        .unwrap();
+    match packer.add_resource("yummy", &td.path().join("yummy")) {
+        Err(PackError::DuplicateResource(_)) => {}
+        _ => panic!("expected duplicate resource"),
+    }
+}
+
+#[test]
+fn pack_without_pipeline() {
+    let td = tempfile::tempdir().unwrap();
+    let packer = PipelinePacker::new(Manifest::default());
+
+    match packer.write(&td.path().join("test.zpipe")) {
+        Err(PackError::NoPipeline) => {}
+        _ => panic!("expected missing pipeline"),
+    }
+}
+
+#[test]
+fn pack_subdirectories() {
+    let td = tempfile::tempdir().unwrap();
+    let resources = td.path().join("resources");
+    fs::create_dir_all(resources.join("grammar")).unwrap();
+    fs::write(resources.join("grammar/disambiguator.bin"), b"yummy").unwrap();
+    fs::write(resources.join("yummy"), b"yummy").unwrap();
+
+    let mut packer = packer();
+    packer.add_resource_dir("lang", &resources).unwrap();
+    match packer.add_resource("lang/../yummy", &resources.join("yummy")) {
+        Err(PackError::InvalidResourceName(_)) => {}
+        _ => panic!("expected invalid resource name"),
+    }
+
+    let path = td.path().join("test.zpipe");
+    let manifest = packer.write(&path).unwrap();
+    let mut names = manifest
+        .resources
+        .iter()
+        .map(|resource| resource.name.clone())
+        .collect::<Vec<_>>();
+    names.sort();
+    assert_eq!(names, vec!["lang/grammar/disambiguator.bin", "lang/yummy"]);
+
+    let file = load_pipeline_file(&path).unwrap();
+    assert!(file.resources.get("lang/grammar/disambiguator.bin").is_ok());
+
+    let target = td.path().join("unpacked");
+    unpack_pipeline_file(&path, &target).unwrap();
+    assert_eq!(
+        fs::read(target.join("lang/grammar/disambiguator.bin")).unwrap(),
+        b"yummy"
+    );
+}
@@ -1,8 +1,28 @@
 #![feature(async_await)]
 
-use divvun_pipeline::{file::load_pipeline_file, run::PipelineRunConfigurationBuilder};
+use divvun_pipeline::{
+    convert::DataFormat,
+    executor::{BlockingTask, PipelineExecutor, ThreadExecutor},
+    file::{load_pipeline_dir, load_pipeline_file},
+    module::AllocationType,
+    pipeline::{NodePath, Pipeline, PipelineCommand, PipelineData, PipelineObserver, PipelineType},
+    resources::{LoadableResource, ResidencyPolicy, Resource, ResourceRegistry},
+    run::PipelineRunConfigurationBuilder,
+    session::{PipelineSession, SessionRequest},
+};
 use divvun_schema::{capnp_message, string_capnp::string};
-use std::{env, fs, path::PathBuf};
+use parking_lot::Mutex;
+use std::{
+    collections::BTreeMap,
+    env, fs,
+    io::Read,
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
 
 mod common;
 
@@ -19,18 +39,442 @@ async fn pipeline_run_with_zpipe() {
 
     let mut pipeline_file = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
     pipeline_file.push("tests/pipeline.zpipe");
-    let (pipeline, registry, _td) = load_pipeline_file(&pipeline_file).unwrap();
+    let file = load_pipeline_file(&pipeline_file).unwrap();
     let runner = PipelineRunConfigurationBuilder::default()
-        .pipeline(pipeline)
-        .resources(registry)
+        .pipeline(file.pipeline(None).unwrap().clone())
+        .resources(file.resources)
         .input(msg_vec)
         .module_search_path(common::get_test_module_search_path())
         .build()
         .unwrap();
-    let output = runner.run().await;
+    let output = runner.run().await.unwrap();
 
     assert_eq!(
         "EREH ENOD SNOITATUPMOC GIB AHello world!\n😋\n!ymmuy",
         output
     );
 }
+
+#[test]
+fn pipeline_run_parallel_shared_resource() {
+    let (registry, _allocator, resources) = common::setup_test_registry(AllocationType::Memory);
+    let registry = Arc::new(registry);
+
+    resources.add_resource(
+        "yummy_resource",
+        LoadableResource::from(Resource::Bytes("yummy".as_bytes().to_owned())),
+    );
+
+    let pipeline = Pipeline {
+        root: serde_json::from_str(
+            r#"[
+                [
+                    { "module": "reverse_string", "command": "reverse_resource", "parameters": ["yummy_resource"] },
+                    { "module": "reverse_string", "command": "reverse_resource", "parameters": ["yummy_resource"] },
+                    { "module": "reverse_string", "command": "reverse_resource", "parameters": ["yummy_resource"] }
+                ],
+                { "module": "concat_strings", "command": "concat" }
+            ]"#,
+        )
+        .unwrap(),
+    };
+
+    let msg = capnp_message!(string::Builder, builder => {
+        builder.set_string("unused");
+    });
+    let msg_vec = divvun_schema::util::message_to_vec(msg).unwrap();
+
+    let output = async_std::task::block_on(pipeline.run(
+        registry,
+        Arc::new(vec![Arc::new(PipelineData {
+            data: msg_vec.as_ptr(),
+            size: msg_vec.len(),
+        })]),
+    ))
+    .unwrap();
+
+    let message =
+        divvun_schema::util::read_message::<string::Owned>(output[0].data, output[0].size).unwrap();
+    assert_eq!(
+        message.get().unwrap().get_string().unwrap(),
+        "ymmuyymmuyymmuy"
+    );
+    assert_eq!(resources.loaded_resources_count(), 0);
+}
+
+#[test]
+fn pipeline_warm_resources() {
+    let td = tempfile::tempdir().unwrap();
+    let resource_path = td.path().join("yummy_resource");
+    fs::write(&resource_path, "yummy").unwrap();
+
+    let resources = ResourceRegistry::with_policy(ResidencyPolicy::KeepResident);
+    resources.add_resource(
+        "yummy_resource",
+        LoadableResource::from(Resource::new_file(&resource_path)),
+    );
+
+    let pipeline = Pipeline {
+        root: serde_json::from_str(
+            r#"[
+                { "module": "reverse_string", "command": "reverse" },
+                { "module": "reverse_string", "command": "reverse_resource", "parameters": ["yummy_resource"] }
+            ]"#,
+        )
+        .unwrap(),
+    };
+
+    assert_eq!(resources.resident_size(), 0);
+    pipeline.warm_resources(&resources).unwrap();
+    assert_eq!(resources.resident_size(), 5);
+    assert_eq!(resources.loaded_resources_count(), 0);
+}
+
+#[test]
+fn pipeline_session_keeps_resources() {
+    let mut dir = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+    dir.push("tests/unzipped");
+    let file = load_pipeline_dir(&dir).unwrap();
+    let resources = Arc::clone(&file.resources);
+    let session = PipelineSession::new(file, &[common::get_test_module_search_path()]).unwrap();
+    assert_eq!(resources.policy(), ResidencyPolicy::KeepResident);
+
+    for _ in 0..2 {
+        let request = SessionRequest::new(b"Hello world!".to_vec(), DataFormat::Text);
+        let output = session.run_blocking(request).unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "EREH ENOD SNOITATUPMOC GIB AHello world!\n😋\n!ymmuy"
+        );
+        assert_eq!(resources.claimed_resources_count(), 0);
+        assert_eq!(resources.resident_size(), 12);
+    }
+
+    // Unloading the modules leaves the resources loaded for other holders of the registry
+    drop(session);
+    assert_eq!(resources.resident_size(), 12);
+}
+
+#[test]
+fn pipeline_session_policy() {
+    let mut dir = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+    dir.push("tests/unzipped");
+    let file = load_pipeline_dir(&dir).unwrap();
+    let resources = Arc::clone(&file.resources);
+    let session = PipelineSession::new(file, &[common::get_test_module_search_path()])
+        .unwrap()
+        .with_policy(ResidencyPolicy::OnDemand)
+        .unwrap();
+
+    let request = SessionRequest::new(b"Hello world!".to_vec(), DataFormat::Text);
+    session.run_blocking(request).unwrap();
+    assert_eq!(resources.policy(), ResidencyPolicy::OnDemand);
+    assert_eq!(resources.resident_size(), 0);
+}
+
+#[test]
+fn pipeline_with_variables() {
+    let pipeline = Pipeline {
+        root: serde_json::from_str(
+            r#"[
+                { "module": "${reverser}", "command": "reverse" },
+                { "module": "${reverser}", "command": "reverse_resource", "parameters": ["${grammar}", "${unset}"] }
+            ]"#,
+        )
+        .unwrap(),
+    };
+
+    let mut variables = BTreeMap::new();
+    variables.insert("reverser".to_string(), "reverse_string".to_string());
+    variables.insert("grammar".to_string(), "yummy_resource".to_string());
+
+    let pipeline = pipeline.with_variables(&variables);
+    let commands = pipeline.commands();
+    assert!(commands
+        .iter()
+        .all(|command| command.module == "reverse_string"));
+    assert_eq!(
+        commands[1].parameters,
+        Some(vec!["yummy_resource".to_string(), "${unset}".to_string()])
+    );
+    assert!(pipeline.check_variables().is_ok());
+}
+
+#[test]
+fn pipeline_run_unset_variable() {
+    let pipeline = Pipeline {
+        root: serde_json::from_str(r#"[{ "module": "${reverser}", "command": "reverse" }]"#)
+            .unwrap(),
+    };
+
+    let runner = PipelineRunConfigurationBuilder::default()
+        .pipeline(pipeline)
+        .resources(Arc::new(ResourceRegistry::new()))
+        .input(b"Hello".to_vec())
+        .input_format(DataFormat::Text)
+        .output_format(DataFormat::Text)
+        .module_search_path(common::get_test_module_search_path())
+        .build()
+        .unwrap();
+    let error = runner.run_blocking().err().unwrap();
+    assert_eq!(
+        error.to_string(),
+        "unset variable reverser in module name ${reverser}"
+    );
+}
+
+#[test]
+fn pipeline_run_with_variables() {
+    let _ = env_logger::builder().is_test(true).try_init();
+
+    let resources = Arc::new(ResourceRegistry::new());
+    resources.add_resource(
+        "yucky_resource",
+        LoadableResource::from(Resource::Bytes("yucky".as_bytes().to_owned())),
+    );
+
+    let pipeline = Pipeline {
+        root: serde_json::from_str(
+            r#"[
+                { "module": "reverse_string", "command": "reverse_resource", "parameters": ["${resource}"] }
+            ]"#,
+        )
+        .unwrap(),
+    };
+
+    let msg = capnp_message!(string::Builder, builder => {
+        builder.set_string("unused");
+    });
+    let msg_vec = divvun_schema::util::message_to_vec(msg).unwrap();
+
+    let mut variables = BTreeMap::new();
+    variables.insert("resource".to_string(), "yucky_resource".to_string());
+
+    let runner = PipelineRunConfigurationBuilder::default()
+        .pipeline(pipeline)
+        .resources(resources)
+        .input(msg_vec)
+        .variables(variables)
+        .module_search_path(common::get_test_module_search_path())
+        .build()
+        .unwrap();
+    let mut output = async_std::task::block_on(runner.run()).unwrap();
+
+    let mut data = Vec::new();
+    output.output.read_to_end(&mut data).unwrap();
+    let message =
+        divvun_schema::util::read_message::<string::Owned>(data.as_ptr(), data.len()).unwrap();
+    assert_eq!(message.get().unwrap().get_string().unwrap(), "ykcuy");
+}
+
+#[test]
+fn pipeline_run_text_io() {
+    let _ = env_logger::builder().is_test(true).try_init();
+
+    let pipeline = Pipeline {
+        root: serde_json::from_str(r#"[{ "module": "reverse_string", "command": "reverse" }]"#)
+            .unwrap(),
+    };
+
+    let runner = PipelineRunConfigurationBuilder::default()
+        .pipeline(pipeline)
+        .resources(Arc::new(ResourceRegistry::new()))
+        .input(b"Hello world!".to_vec())
+        .input_format(DataFormat::Text)
+        .output_format(DataFormat::Text)
+        .module_search_path(common::get_test_module_search_path())
+        .build()
+        .unwrap();
+    let mut output = async_std::task::block_on(runner.run()).unwrap();
+
+    let mut text = String::new();
+    output.output.read_to_string(&mut text).unwrap();
+    assert_eq!(text, "!dlrow olleH");
+}
+
+#[test]
+fn pipeline_run_blocking() {
+    let pipeline = Pipeline {
+        root: serde_json::from_str(r#"[{ "module": "reverse_string", "command": "reverse" }]"#)
+            .unwrap(),
+    };
+
+    let runner = PipelineRunConfigurationBuilder::default()
+        .pipeline(pipeline)
+        .resources(Arc::new(ResourceRegistry::new()))
+        .input(b"Hello world!".to_vec())
+        .input_format(DataFormat::Text)
+        .output_format(DataFormat::Text)
+        .module_search_path(common::get_test_module_search_path())
+        .executor(Some(Arc::new(ThreadExecutor)))
+        .build()
+        .unwrap();
+    let mut output = runner.run_blocking().unwrap();
+
+    let mut text = String::new();
+    output.output.read_to_string(&mut text).unwrap();
+    assert_eq!(text, "!dlrow olleH");
+}
+
+#[derive(Default)]
+struct OutputRecorder {
+    outputs: Mutex<Vec<(String, String)>>,
+}
+
+impl PipelineObserver for OutputRecorder {
+    fn command_finished(
+        &self,
+        path: &NodePath,
+        command: &PipelineCommand,
+        _input: &PipelineType,
+        output: &PipelineType,
+        _duration: Duration,
+    ) {
+        let message =
+            divvun_schema::util::read_message::<string::Owned>(output[0].data, output[0].size)
+                .unwrap();
+        let text = message.get().unwrap().get_string().unwrap().to_string();
+        self.outputs
+            .lock()
+            .push((format!("{} {}", path, command.command), text));
+    }
+}
+
+#[test]
+fn pipeline_run_observed() {
+    let (registry, _allocator, _resources) = common::setup_test_registry(AllocationType::Memory);
+
+    let pipeline = Pipeline {
+        root: serde_json::from_str(
+            r#"[
+                { "module": "reverse_string", "command": "reverse" },
+                [
+                    { "module": "reverse_string", "command": "reverse" },
+                    { "module": "do_things_strings", "command": "stuff" }
+                ]
+            ]"#,
+        )
+        .unwrap(),
+    };
+
+    let msg = capnp_message!(string::Builder, builder => {
+        builder.set_string("Hello");
+    });
+    let msg_vec = divvun_schema::util::message_to_vec(msg).unwrap();
+
+    let recorder = Arc::new(OutputRecorder::default());
+    async_std::task::block_on(pipeline.run_observed(
+        Arc::new(registry),
+        Arc::new(vec![Arc::new(PipelineData {
+            data: msg_vec.as_ptr(),
+            size: msg_vec.len(),
+        })]),
+        recorder.clone(),
+    ))
+    .unwrap();
+
+    assert_eq!(
+        *recorder.outputs.lock(),
+        vec![
+            ("0 reverse".to_string(), "olleH".to_string()),
+            ("1.0 reverse".to_string(), "Hello".to_string()),
+            (
+                "1.1 stuff".to_string(),
+                "Here is a computation stuff!".to_string()
+            ),
+        ]
+    );
+}
+
+#[test]
+fn pipeline_run_with_executor() {
+    let (registry, _allocator, _resources) = common::setup_test_registry(AllocationType::Memory);
+
+    let pipeline = Pipeline {
+        root: serde_json::from_str(
+            r#"[
+                { "module": "reverse_string", "command": "reverse" },
+                [
+                    { "module": "reverse_string", "command": "reverse" },
+                    { "module": "reverse_string", "command": "reverse" }
+                ],
+                { "module": "concat_strings", "command": "concat" }
+            ]"#,
+        )
+        .unwrap(),
+    };
+
+    let msg = capnp_message!(string::Builder, builder => {
+        builder.set_string("Hello");
+    });
+    let msg_vec = divvun_schema::util::message_to_vec(msg).unwrap();
+
+    let calls = Arc::new(AtomicUsize::new(0));
+    let executor_calls = calls.clone();
+    let executor: Arc<dyn PipelineExecutor> = Arc::new(move |task: BlockingTask| {
+        executor_calls.fetch_add(1, Ordering::SeqCst);
+        ThreadExecutor.spawn_blocking(task);
+    });
+
+    let output = async_std::task::block_on(pipeline.run_with(
+        Arc::new(registry),
+        Arc::new(vec![Arc::new(PipelineData {
+            data: msg_vec.as_ptr(),
+            size: msg_vec.len(),
+        })]),
+        None,
+        Some(executor),
+    ))
+    .unwrap();
+
+    let message =
+        divvun_schema::util::read_message::<string::Owned>(output[0].data, output[0].size).unwrap();
+    assert_eq!(message.get().unwrap().get_string().unwrap(), "HelloHello");
+    assert_eq!(calls.load(Ordering::SeqCst), 4);
+}
+
+#[test]
+fn pipeline_run_dropped_with_executor() {
+    use std::future::Future;
+
+    let (registry, _allocator, _resources) = common::setup_test_registry(AllocationType::Memory);
+
+    let pipeline = Pipeline {
+        root: serde_json::from_str(r#"[{ "module": "reverse_string", "command": "reverse" }]"#)
+            .unwrap(),
+    };
+
+    let msg = capnp_message!(string::Builder, builder => {
+        builder.set_string("Hello");
+    });
+    let msg_vec = divvun_schema::util::message_to_vec(msg).unwrap();
+
+    // Starts module calls late, so the run is dropped while its call is in flight
+    let executor: Arc<dyn PipelineExecutor> = Arc::new(|task: BlockingTask| {
+        std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(100));
+            task();
+        });
+    });
+
+    let recorder = Arc::new(OutputRecorder::default());
+    let mut run = Box::pin(pipeline.run_with(
+        Arc::new(registry),
+        Arc::new(vec![Arc::new(PipelineData {
+            data: msg_vec.as_ptr(),
+            size: msg_vec.len(),
+        })]),
+        Some(recorder.clone()),
+        Some(executor),
+    ));
+    let waker = futures::task::noop_waker();
+    let mut context = std::task::Context::from_waker(&waker);
+    assert!(run.as_mut().poll(&mut context).is_pending());
+
+    // Dropping the run waits for the call, which still reports its output
+    drop(run);
+    assert_eq!(
+        *recorder.outputs.lock(),
+        vec![("0 reverse".to_string(), "olleH".to_string())]
+    );
+}
@@ -0,0 +1,79 @@
+use std::{env, fs, path::PathBuf, sync::Arc};
+
+use divvun_pipeline::{
+    module::{resolve_search_paths, ModuleAllocator, ModuleRegistry, PipelineConfig},
+    resources::ResourceRegistry,
+};
+
+#[test]
+fn search_path_order() {
+    let td = tempfile::tempdir().unwrap();
+    let user_config = td.path().join("user.toml");
+    fs::write(
+        &user_config,
+        r#"module_paths = ["/opt/modules", "relative"]"#,
+    )
+    .unwrap();
+    let system_config = td.path().join("system.toml");
+    fs::write(
+        &system_config,
+        r#"module_paths = ["/usr/lib/modules", "/env/b"]"#,
+    )
+    .unwrap();
+
+    let env_paths = env::join_paths(&["/env/a", "/env/b"]).unwrap();
+    let paths = resolve_search_paths(
+        &[PathBuf::from("explicit"), PathBuf::from("/env/a")],
+        Some(&env_paths),
+        &[user_config, td.path().join("missing.toml"), system_config],
+    )
+    .unwrap();
+
+    assert_eq!(
+        paths,
+        vec![
+            PathBuf::from("explicit"),
+            PathBuf::from("/env/a"),
+            PathBuf::from("/env/b"),
+            PathBuf::from("/opt/modules"),
+            td.path().join("relative"),
+            PathBuf::from("/usr/lib/modules"),
+        ]
+    );
+}
+
+#[test]
+fn search_path_default() {
+    let paths = resolve_search_paths(&[], None, &[]).unwrap();
+    assert_eq!(paths, vec![PathBuf::from("modules")]);
+}
+
+#[test]
+fn search_path_invalid_config() {
+    let td = tempfile::tempdir().unwrap();
+    let config = td.path().join("config.toml");
+    fs::write(&config, "module_path = \"typo\"").unwrap();
+
+    assert!(PipelineConfig::load(&config).is_err());
+    assert!(resolve_search_paths(&[], None, &[config]).is_err());
+    assert!(PipelineConfig::load(&td.path().join("missing.toml"))
+        .unwrap()
+        .is_none());
+}
+
+#[test]
+fn registry_search_path_order() {
+    let mut registry = ModuleRegistry::new(
+        Arc::new(ModuleAllocator::new_default()),
+        Arc::new(ResourceRegistry::new()),
+    )
+    .unwrap();
+    for path in &["b", "a", "b", "c"] {
+        registry.add_search_path(&PathBuf::from(path));
+    }
+
+    assert_eq!(
+        registry.search_paths(),
+        &[PathBuf::from("b"), PathBuf::from("a"), PathBuf::from("c")]
+    );
+}
@@ -0,0 +1,155 @@
+use std::{
+    io::{Read, Write},
+    net::{SocketAddr, TcpStream},
+    thread,
+    time::Duration,
+};
+
+use divvun_pipeline::serve::Server;
+use serde_json::json;
+
+mod common;
+
+fn start_server(
+    max_concurrent: usize,
+) -> (
+    SocketAddr,
+    divvun_pipeline::serve::ShutdownHandle,
+    thread::JoinHandle<()>,
+    tempfile::TempDir,
+) {
+    let td = tempfile::tempdir().unwrap();
+    let server = Server::bind(
+        "127.0.0.1:0",
+        common::reverse_packages(td.path()),
+        max_concurrent,
+    )
+    .unwrap();
+    let address = server.local_addr().unwrap();
+    let handle = server.shutdown_handle();
+    let thread = thread::spawn(move || server.run().unwrap());
+    (address, handle, thread, td)
+}
+
+fn request(address: SocketAddr, request: &str) -> (u16, String) {
+    let mut stream = TcpStream::connect(address).unwrap();
+    stream.write_all(request.as_bytes()).unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+
+    let status = response[9..12].parse().unwrap();
+    let body = match response.find("\r\n\r\n") {
+        Some(index) => response[index + 4..].to_string(),
+        None => String::new(),
+    };
+    (status, body)
+}
+
+fn post(address: SocketAddr, path: &str, content_type: &str, body: &str) -> (u16, String) {
+    request(
+        address,
+        &format!(
+            "POST {} HTTP/1.1\r\nHost: localhost\r\nContent-Type: {}\r\nContent-Length: {}\r\n\r\n{}",
+            path,
+            content_type,
+            body.len(),
+            body
+        ),
+    )
+}
+
+#[test]
+fn serve_pipelines() {
+    let (address, handle, thread, _td) = start_server(4);
+
+    let (status, body) = request(address, "GET /health HTTP/1.1\r\n\r\n");
+    assert_eq!(status, 200);
+    let health: serde_json::Value = serde_json::from_str(&body).unwrap();
+    assert_eq!(health["status"], "ok");
+    assert_eq!(
+        health["pipelines"],
+        json!([
+            "reverse",
+            "reverse/broken",
+            "reverse/default",
+            "reverse/twice"
+        ])
+    );
+
+    assert_eq!(
+        post(address, "/pipelines/reverse", "text/plain", "Hello"),
+        (200, "olleH".to_string())
+    );
+    assert_eq!(
+        post(address, "/pipelines/reverse/twice", "text/plain", "Hello"),
+        (200, "Hello".to_string())
+    );
+    assert_eq!(
+        post(
+            address,
+            "/pipelines/reverse",
+            "application/json",
+            r#"{"string":"Hello"}"#
+        ),
+        (200, r#"{"string":"olleH"}"#.to_string())
+    );
+
+    assert_eq!(
+        post(address, "/pipelines/missing", "text/plain", "Hello").0,
+        404
+    );
+    assert_eq!(
+        post(address, "/pipelines/reverse/missing", "text/plain", "Hello").0,
+        404
+    );
+    assert_eq!(
+        request(address, "GET /pipelines/reverse HTTP/1.1\r\n\r\n").0,
+        405
+    );
+    assert_eq!(
+        post(address, "/pipelines/reverse", "image/png", "Hello").0,
+        400
+    );
+
+    handle.shutdown();
+    thread.join().unwrap();
+}
+
+#[test]
+fn serve_concurrency_limit() {
+    let (address, handle, thread, _td) = start_server(1);
+
+    // Occupies the only slot until it sends its request
+    let mut pending = TcpStream::connect(address).unwrap();
+    thread::sleep(Duration::from_millis(200));
+
+    assert_eq!(request(address, "GET /health HTTP/1.1\r\n\r\n").0, 503);
+
+    // Shutting down waits for the pending request to be answered
+    handle.shutdown();
+    pending.write_all(b"GET /health HTTP/1.1\r\n\r\n").unwrap();
+    let mut response = String::new();
+    pending.read_to_string(&mut response).unwrap();
+    assert!(response.starts_with("HTTP/1.1 200"));
+
+    thread.join().unwrap();
+}
+
+#[test]
+fn serve_module_failure() {
+    let (address, handle, thread, _td) = start_server(1);
+
+    // A failed run frees its slot, so the second one isn't rejected
+    for _ in 0..2 {
+        let (status, body) = post(address, "/pipelines/reverse/broken", "text/plain", "Hello");
+        assert_eq!(status, 500);
+        assert!(body.contains("missing_module"));
+    }
+    assert_eq!(
+        post(address, "/pipelines/reverse", "text/plain", "Hello"),
+        (200, "olleH".to_string())
+    );
+
+    handle.shutdown();
+    thread.join().unwrap();
+}
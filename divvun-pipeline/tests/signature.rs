@@ -0,0 +1,271 @@
+use std::fs;
+
+use divvun_pipeline::{
+    file::{load_pipeline_file, load_pipeline_file_with_keys, FileLoadError},
+    manifest::{Manifest, MANIFEST_FILE_NAME},
+    module::resolve_trusted_keys,
+    pack::{inspect_pipeline_file, sign_pipeline_file, PackError, PipelinePacker},
+    resources::ResourceMetadata,
+    signature::{public_key_hex, sign_manifest, SignatureError, TrustedKeys, SIGNATURE_FILE_NAME},
+};
+use ed25519_dalek::{Keypair, PublicKey, SecretKey};
+use zip::CompressionMethod;
+
+mod common;
+
+const PIPELINE_JSON: &str = r#"[
+    { "module": "reverse_string", "command": "reverse_resource", "parameters": ["yummy_resource"] }
+]"#;
+
+fn keypair(seed: u8) -> Keypair {
+    let secret = SecretKey::from_bytes(&[seed; 32]).unwrap();
+    let public = PublicKey::from(&secret);
+    Keypair { secret, public }
+}
+
+fn trusted(keypair: &Keypair) -> TrustedKeys {
+    let mut keys = TrustedKeys::new();
+    keys.add_key(&public_key_hex(keypair)).unwrap();
+    keys
+}
+
+fn manifest() -> Manifest {
+    Manifest {
+        pipeline: Some(serde_json::from_str(PIPELINE_JSON).unwrap()),
+        ..Default::default()
+    }
+}
+
+fn write_signed(dir: &std::path::Path, keypair: Keypair) -> std::path::PathBuf {
+    fs::write(dir.join("yummy"), b"yummy").unwrap();
+
+    let mut packer = PipelinePacker::new(manifest());
+    packer
+        .add_resource("yummy_resource", &dir.join("yummy"))
+        .unwrap();
+    packer.sign_with(keypair);
+
+    let path = dir.join("signed.zpipe");
+    packer.write(&path).unwrap();
+    path
+}
+
+#[test]
+fn signed_pack_verified() {
+    let td = tempfile::tempdir().unwrap();
+    let path = write_signed(td.path(), keypair(1));
+
+    let file = load_pipeline_file_with_keys(&path, &trusted(&keypair(1))).unwrap();
+    assert!(file.resources.get("yummy_resource").is_ok());
+
+    // Without trusted keys any valid signature is accepted
+    assert!(load_pipeline_file(&path).is_ok());
+}
+
+#[test]
+fn signed_by_untrusted_key() {
+    let td = tempfile::tempdir().unwrap();
+    let path = write_signed(td.path(), keypair(1));
+
+    match load_pipeline_file_with_keys(&path, &trusted(&keypair(2))) {
+        Err(FileLoadError::Signature(SignatureError::UntrustedKey(_))) => {}
+        Err(e) => panic!("unexpected error: {}", e),
+        Ok(_) => panic!("untrusted signature was accepted"),
+    }
+}
+
+#[test]
+fn trusted_keys_from_config() {
+    let td = tempfile::tempdir().unwrap();
+    let path = write_signed(td.path(), keypair(1));
+    fs::write(td.path().join("keys.txt"), public_key_hex(&keypair(2))).unwrap();
+    let config = td.path().join("config.toml");
+    fs::write(
+        &config,
+        "trusted_keys = [\"keys.txt\"]\nrequire_signature = true\n",
+    )
+    .unwrap();
+
+    let keys = resolve_trusted_keys(&[], &[config.clone()]).unwrap();
+    match load_pipeline_file_with_keys(&path, &keys) {
+        Err(FileLoadError::Signature(SignatureError::UntrustedKey(_))) => {}
+        Err(e) => panic!("unexpected error: {}", e),
+        Ok(_) => panic!("untrusted signature was accepted"),
+    }
+
+    fs::write(td.path().join("more.txt"), public_key_hex(&keypair(1))).unwrap();
+    let keys = resolve_trusted_keys(&[td.path().join("more.txt")], &[config.clone()]).unwrap();
+    assert!(load_pipeline_file_with_keys(&path, &keys).is_ok());
+
+    assert!(resolve_trusted_keys(&[td.path().join("missing.txt")], &[config]).is_err());
+}
+
+#[test]
+fn unsigned_rejected_when_required() {
+    let td = tempfile::tempdir().unwrap();
+    let manifest = serde_json::to_vec(&manifest()).unwrap();
+    let path = common::write_zpipe(
+        td.path(),
+        &[(MANIFEST_FILE_NAME, &manifest)],
+        CompressionMethod::Stored,
+    );
+
+    assert!(load_pipeline_file_with_keys(&path, &trusted(&keypair(1))).is_ok());
+    match load_pipeline_file_with_keys(&path, &trusted(&keypair(1)).require_signature(true)) {
+        Err(FileLoadError::Signature(SignatureError::MissingSignature)) => {}
+        Err(e) => panic!("unexpected error: {}", e),
+        Ok(_) => panic!("unsigned pipeline file was accepted"),
+    }
+}
+
+#[test]
+fn tampered_manifest_rejected() {
+    let td = tempfile::tempdir().unwrap();
+    let manifest = serde_json::to_vec(&manifest()).unwrap();
+    let signature = sign_manifest(&manifest, &keypair(1));
+    let signature = serde_json::to_vec(&signature).unwrap();
+
+    let mut tampered = manifest.clone();
+    tampered.extend_from_slice(b" ");
+    let path = common::write_zpipe(
+        td.path(),
+        &[
+            (MANIFEST_FILE_NAME, &tampered),
+            (SIGNATURE_FILE_NAME, &signature),
+        ],
+        CompressionMethod::Stored,
+    );
+
+    match load_pipeline_file(&path) {
+        Err(FileLoadError::Signature(SignatureError::VerificationFailed { .. })) => {}
+        Err(e) => panic!("unexpected error: {}", e),
+        Ok(_) => panic!("tampered manifest was accepted"),
+    }
+}
+
+#[test]
+fn signed_archive_with_unlisted_entry() {
+    let td = tempfile::tempdir().unwrap();
+    let manifest = serde_json::to_vec(&manifest()).unwrap();
+    let signature = serde_json::to_vec(&sign_manifest(&manifest, &keypair(1))).unwrap();
+    let path = common::write_zpipe(
+        td.path(),
+        &[
+            (MANIFEST_FILE_NAME, &manifest),
+            (SIGNATURE_FILE_NAME, &signature),
+            ("injected", b"injected"),
+        ],
+        CompressionMethod::Stored,
+    );
+
+    match load_pipeline_file(&path) {
+        Err(FileLoadError::UnsignedEntry(name)) => assert_eq!(name, "injected"),
+        Err(e) => panic!("unexpected error: {}", e),
+        Ok(_) => panic!("unlisted entry was accepted"),
+    }
+}
+
+#[test]
+fn tampered_resource_rejected() {
+    let td = tempfile::tempdir().unwrap();
+    let manifest = serde_json::to_vec(&Manifest {
+        resources: vec![ResourceMetadata::new("yummy_resource", b"yummy")],
+        ..manifest()
+    })
+    .unwrap();
+    let signature = serde_json::to_vec(&sign_manifest(&manifest, &keypair(1))).unwrap();
+    let path = common::write_zpipe(
+        td.path(),
+        &[
+            (MANIFEST_FILE_NAME, &manifest),
+            (SIGNATURE_FILE_NAME, &signature),
+            ("yummy_resource", b"yucky"),
+        ],
+        CompressionMethod::Stored,
+    );
+
+    let file = load_pipeline_file_with_keys(&path, &trusted(&keypair(1))).unwrap();
+    assert!(file.resources.get("yummy_resource").is_err());
+}
+
+#[test]
+fn tampered_pipeline_rejected() {
+    let manifest = serde_json::to_vec(&Manifest {
+        resources: vec![
+            ResourceMetadata::new("pipeline.json", PIPELINE_JSON.as_bytes()),
+            ResourceMetadata::new("yummy_resource", b"yummy"),
+        ],
+        ..Default::default()
+    })
+    .unwrap();
+    let signature = serde_json::to_vec(&sign_manifest(&manifest, &keypair(1))).unwrap();
+    let load = |pipeline: &[u8]| {
+        let td = tempfile::tempdir().unwrap();
+        let path = common::write_zpipe(
+            td.path(),
+            &[
+                (MANIFEST_FILE_NAME, &manifest),
+                (SIGNATURE_FILE_NAME, &signature),
+                ("pipeline.json", pipeline),
+                ("yummy_resource", b"yummy"),
+            ],
+            CompressionMethod::Stored,
+        );
+        load_pipeline_file_with_keys(&path, &trusted(&keypair(1)))
+    };
+
+    let file = load(PIPELINE_JSON.as_bytes()).unwrap();
+    assert_eq!(file.pipeline(None).unwrap().commands().len(), 1);
+
+    let tampered = br#"[{ "module": "evil", "command": "run", "parameters": null }]"#;
+    match load(tampered) {
+        Err(FileLoadError::CorruptResource { name, .. }) => assert_eq!(name, "pipeline.json"),
+        Err(e) => panic!("unexpected error: {}", e),
+        Ok(_) => panic!("tampered pipeline accepted"),
+    }
+}
+
+#[test]
+fn sign_existing_file() {
+    let td = tempfile::tempdir().unwrap();
+    fs::write(td.path().join("yummy"), b"yummy").unwrap();
+    let mut packer = PipelinePacker::new(manifest());
+    packer
+        .add_resource("yummy_resource", &td.path().join("yummy"))
+        .unwrap();
+    let path = td.path().join("test.zpipe");
+    packer.write(&path).unwrap();
+
+    let signature = sign_pipeline_file(&path, &keypair(3)).unwrap();
+    assert_eq!(signature.public_key, public_key_hex(&keypair(3)));
+
+    let info = inspect_pipeline_file(&path).unwrap();
+    let resource = info
+        .entries
+        .iter()
+        .find(|entry| entry.name == "yummy_resource")
+        .unwrap();
+    assert!(resource.is_aligned());
+
+    // Signing again replaces the signature
+    sign_pipeline_file(&path, &keypair(4)).unwrap();
+    assert!(load_pipeline_file_with_keys(&path, &trusted(&keypair(4))).is_ok());
+    assert!(load_pipeline_file_with_keys(&path, &trusted(&keypair(3))).is_err());
+}
+
+#[test]
+fn sign_requires_listed_entries() {
+    let td = tempfile::tempdir().unwrap();
+    let manifest = serde_json::to_vec(&manifest()).unwrap();
+    let path = common::write_zpipe(
+        td.path(),
+        &[(MANIFEST_FILE_NAME, &manifest), ("unlisted", b"unlisted")],
+        CompressionMethod::Stored,
+    );
+
+    match sign_pipeline_file(&path, &keypair(1)) {
+        Err(PackError::UnlistedEntry(name)) => assert_eq!(name, "unlisted"),
+        Err(e) => panic!("unexpected error: {}", e),
+        Ok(_) => panic!("unlisted entry was signed"),
+    }
+}
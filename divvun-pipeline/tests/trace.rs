@@ -0,0 +1,89 @@
+use std::{fs, sync::Arc};
+
+use divvun_pipeline::{
+    convert::DataFormat,
+    pipeline::Pipeline,
+    resources::ResourceRegistry,
+    run::PipelineRunConfigurationBuilder,
+    trace::{TraceFormat, TraceRecord},
+};
+use serde_json::json;
+
+mod common;
+
+fn run_traced(trace: &std::path::Path, format: TraceFormat) {
+    let _ = env_logger::builder().is_test(true).try_init();
+
+    let pipeline = Pipeline {
+        root: serde_json::from_str(
+            r#"[
+                { "module": "reverse_string", "command": "reverse" },
+                { "module": "reverse_string", "command": "reverse" }
+            ]"#,
+        )
+        .unwrap(),
+    };
+
+    let runner = PipelineRunConfigurationBuilder::default()
+        .pipeline(pipeline)
+        .resources(Arc::new(ResourceRegistry::new()))
+        .input(b"Hello".to_vec())
+        .input_format(DataFormat::Text)
+        .output_format(DataFormat::Text)
+        .module_search_path(common::get_test_module_search_path())
+        .trace(Some(trace.to_path_buf()))
+        .trace_format(format)
+        .build()
+        .unwrap();
+    async_std::task::block_on(runner.run()).unwrap();
+}
+
+#[test]
+fn trace_json_lines() {
+    let td = tempfile::tempdir().unwrap();
+    let path = td.path().join("trace.jsonl");
+    run_traced(&path, TraceFormat::JsonLines);
+
+    let records = fs::read_to_string(&path)
+        .unwrap()
+        .lines()
+        .map(|line| serde_json::from_str::<TraceRecord>(line).unwrap())
+        .collect::<Vec<_>>();
+
+    assert_eq!(records.len(), 2);
+    assert_eq!(records[0].index, 0);
+    assert_eq!(records[0].path, "0");
+    assert_eq!(records[0].command, "reverse");
+    assert_eq!(records[0].output, Some(json!({ "string": "olleH" })));
+    assert_eq!(records[1].path, "1");
+    assert_eq!(records[1].output, Some(json!({ "string": "Hello" })));
+    assert_eq!(records[1].input_sizes, vec![records[0].output_size]);
+    assert!(records[1].allocator_total_bytes >= records[0].allocator_total_bytes);
+}
+
+#[test]
+fn trace_directory() {
+    let td = tempfile::tempdir().unwrap();
+    let dir = td.path().join("trace");
+    run_traced(&dir, TraceFormat::Directory);
+
+    let record: TraceRecord =
+        serde_json::from_slice(&fs::read(dir.join("0001-1.json")).unwrap()).unwrap();
+    assert_eq!(record.output, Some(json!({ "string": "Hello" })));
+
+    let output = fs::read(dir.join("0001-1.bin")).unwrap();
+    assert_eq!(output.len(), record.output_size);
+}
+
+#[test]
+fn trace_format_names() {
+    assert_eq!(
+        "jsonl".parse::<TraceFormat>().unwrap(),
+        TraceFormat::JsonLines
+    );
+    assert_eq!(
+        "dir".parse::<TraceFormat>().unwrap(),
+        TraceFormat::Directory
+    );
+    assert!("xml".parse::<TraceFormat>().is_err());
+}
@@ -1,13 +1,74 @@
 use std::{
     borrow::Cow,
+    error::Error,
     ffi::{CStr, CString},
+    fmt,
     os::raw::{c_char, c_void},
 };
 
+/// Identifies a single claim of a resource. Every successful load hands out a new id which
+/// has to be released exactly once.
+pub type ResourceHandleId = u64;
+
 pub type AllocFn = extern "C" fn(*mut c_void, usize) -> *mut u8;
-pub type LoadResourceFn =
-    extern "C" fn(*mut c_void, *const c_char, *mut *const u8, *mut usize) -> bool;
-pub type ReleaseResourceFn = extern "C" fn(*mut c_void, *const c_char) -> bool;
+/// Loads the named resource. On `LoadResourceStatus::Ok` the handle id is set and the output
+/// points to the resource data, otherwise the output points to a UTF-8 error message allocated
+/// with the module allocator.
+pub type LoadResourceFn = extern "C" fn(
+    *mut c_void,
+    *const c_char,
+    *mut ResourceHandleId,
+    *mut *const u8,
+    *mut usize,
+) -> LoadResourceStatus;
+/// Releases the claim identified by the handle id, returns false for unknown ids.
+pub type ReleaseResourceFn = extern "C" fn(*mut c_void, ResourceHandleId) -> bool;
+/// Frees an object previously attached to a resource.
+pub type ObjectDestructorFn = extern "C" fn(*mut c_void);
+/// Returns the object attached to the claimed resource under the given key, or null.
+pub type GetResourceObjectFn =
+    extern "C" fn(*mut c_void, ResourceHandleId, *const c_char) -> *mut c_void;
+/// Attaches a module-owned object to the claimed resource under the given key. The object is
+/// freed with the destructor when the resource is unloaded. If another object was attached
+/// under the same key in the meantime, the passed in object is destroyed right away and the
+/// existing one is returned instead. Returns null if the handle id is unknown.
+pub type AttachResourceObjectFn = extern "C" fn(
+    *mut c_void,
+    ResourceHandleId,
+    *const c_char,
+    *mut c_void,
+    ObjectDestructorFn,
+) -> *mut c_void;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[repr(C)]
+pub enum LoadResourceStatus {
+    Ok = 0,
+    NotFound = 1,
+    LoadFailed = 2,
+}
+
+#[derive(Debug)]
+pub enum ResourceError {
+    NotInitialized,
+    NotFound(String),
+    LoadFailed { name: String, message: String },
+}
+
+impl fmt::Display for ResourceError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ResourceError::NotInitialized => write!(f, "pipeline interface not initialized"),
+            ResourceError::NotFound(ref name) => write!(f, "resource {} not found", name),
+            ResourceError::LoadFailed {
+                ref name,
+                ref message,
+            } => write!(f, "load of resource {} failed: {}", name, message),
+        }
+    }
+}
+
+impl Error for ResourceError {}
 
 #[derive(Debug)]
 #[repr(C)]
@@ -16,6 +77,8 @@ pub struct ModuleInterface {
     pub alloc_fn: AllocFn,
     pub load_resource_fn: LoadResourceFn,
     pub release_resource_fn: ReleaseResourceFn,
+    pub get_resource_object_fn: GetResourceObjectFn,
+    pub attach_resource_object_fn: AttachResourceObjectFn,
 }
 
 unsafe impl Send for ModuleInterface {}
@@ -75,30 +138,91 @@ impl ModuleInterface {
         Some(result)
     }
 
-    pub fn load_resource(&self, name: &str) -> Option<PipelineResource> {
-        let cstr = CString::new(name).unwrap();
+    pub fn load_resource(&self, name: &str) -> Result<PipelineResource, ResourceError> {
+        let cstr = CString::new(name).map_err(|_| ResourceError::NotFound(name.into()))?;
+        let mut handle: ResourceHandleId = 0;
         let mut data: *const u8 = std::ptr::null_mut();
         let mut data_size: usize = 0;
-        let result = (self.load_resource_fn)(self.data, cstr.as_ptr(), &mut data, &mut data_size);
-        if !result {
-            return None;
+        let status = (self.load_resource_fn)(
+            self.data,
+            cstr.as_ptr(),
+            &mut handle,
+            &mut data,
+            &mut data_size,
+        );
+        if status != LoadResourceStatus::Ok {
+            let message = if data.is_null() {
+                String::new()
+            } else {
+                String::from_utf8_lossy(unsafe { std::slice::from_raw_parts(data, data_size) })
+                    .to_string()
+            };
+
+            return Err(match status {
+                LoadResourceStatus::NotFound => ResourceError::NotFound(name.into()),
+                _ => ResourceError::LoadFailed {
+                    name: name.into(),
+                    message,
+                },
+            });
         }
 
-        Some(PipelineResource {
+        Ok(PipelineResource {
+            interface: self,
+            handle,
             name: name.into(),
             data,
             data_size,
         })
     }
 
-    pub fn release_resource(&self, name: &str) -> bool {
-        let cstr = CString::new(name).unwrap();
-        return (self.release_resource_fn)(self.data, cstr.as_ptr());
+    pub fn release_resource(&self, handle: ResourceHandleId) -> bool {
+        (self.release_resource_fn)(self.data, handle)
+    }
+
+    pub fn resource_object(&self, handle: ResourceHandleId, key: &str) -> Option<*mut c_void> {
+        let cstr = CString::new(key).ok()?;
+        let object = (self.get_resource_object_fn)(self.data, handle, cstr.as_ptr());
+        if object.is_null() {
+            return None;
+        }
+        Some(object)
+    }
+
+    pub fn attach_resource_object(
+        &self,
+        handle: ResourceHandleId,
+        key: &str,
+        object: *mut c_void,
+        destructor: ObjectDestructorFn,
+    ) -> Option<*mut c_void> {
+        let cstr = match CString::new(key) {
+            Ok(cstr) => cstr,
+            Err(_) => {
+                destructor(object);
+                return None;
+            }
+        };
+        let object =
+            (self.attach_resource_object_fn)(self.data, handle, cstr.as_ptr(), object, destructor);
+        if object.is_null() {
+            return None;
+        }
+        Some(object)
     }
 }
 
+extern "C" fn drop_boxed<T>(object: *mut c_void) {
+    unsafe {
+        drop(Box::from_raw(object as *mut T));
+    }
+}
+
+/// A claimed resource, released through the interface that loaded it when dropped.
 #[derive(Debug)]
 pub struct PipelineResource {
+    interface: *const ModuleInterface,
+    handle: ResourceHandleId,
     name: String,
     data: *const u8,
     data_size: usize,
@@ -109,6 +233,10 @@ impl PipelineResource {
         &self.name
     }
 
+    pub fn handle(&self) -> ResourceHandleId {
+        self.handle
+    }
+
     pub fn as_ptr(&self) -> *const u8 {
         self.data
     }
@@ -120,11 +248,35 @@ impl PipelineResource {
     pub fn as_slice(&self) -> &[u8] {
         unsafe { std::slice::from_raw_parts(self.data, self.data_size) }
     }
+
+    /// Returns the object cached on this resource under `key`, creating it from the resource
+    /// data on first use. The object lives as long as the resource stays loaded, so parsing
+    /// only happens again after the resource was unloaded. Keys are shared between modules and
+    /// should be prefixed with the module name. Returns None if `create` fails.
+    pub fn cached_object<T, F>(&self, key: &str, create: F) -> Option<&T>
+    where
+        T: Send + Sync + 'static,
+        F: FnOnce(&[u8]) -> Option<T>,
+    {
+        let interface = unsafe { &*self.interface };
+
+        let object = match interface.resource_object(self.handle, key) {
+            Some(object) => object,
+            None => {
+                let object = Box::into_raw(Box::new(create(self.as_slice())?)) as *mut c_void;
+                interface.attach_resource_object(self.handle, key, object, drop_boxed::<T>)?
+            }
+        };
+
+        Some(unsafe { &*(object as *const T) })
+    }
 }
 
 impl Drop for PipelineResource {
     fn drop(&mut self) {
-        release_resource(&self.name);
+        unsafe {
+            (*self.interface).release_resource(self.handle);
+        }
     }
 }
 
@@ -143,14 +295,20 @@ pub fn initialize(interface: *const ModuleInterface) -> bool {
     true
 }
 
-pub fn load_resource(name: &str) -> Option<PipelineResource> {
-    unsafe { PIPELINE_INTERFACE.and_then(|interface| (*interface).load_resource(name)) }
+pub fn load_resource(name: &str) -> Result<PipelineResource, ResourceError> {
+    unsafe {
+        PIPELINE_INTERFACE
+            .ok_or(ResourceError::NotInitialized)
+            .and_then(|interface| (*interface).load_resource(name))
+    }
 }
 
-pub fn release_resource(name: &str) -> bool {
+/// Releases a resource claim early. Dropping the `PipelineResource` does the same, so this is
+/// only needed when the handle id was kept around instead of the resource.
+pub fn release_resource(handle: ResourceHandleId) -> bool {
     unsafe {
         PIPELINE_INTERFACE
-            .map(|interface| (*interface).release_resource(name))
+            .map(|interface| (*interface).release_resource(handle))
             .unwrap_or(false)
     }
 }
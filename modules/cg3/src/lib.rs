@@ -12,12 +12,15 @@ use std::{
     io::Cursor,
     os::raw::c_char,
     str,
+    sync::Mutex,
 };
 
 extern "C" {
+    fn cg3_grammar_create(grammar_data: *const u8, grammar_size: usize) -> *mut c_void;
+    fn cg3_grammar_destroy(grammar: *mut c_void);
+
     fn cg3_run(
-        grammar_data: *const u8,
-        grammar_size: usize,
+        grammar: *mut c_void,
         input_data: *const u8,
         input_size: usize,
         output_size: *mut usize,
@@ -27,6 +30,29 @@ extern "C" {
     fn cg3_copy_output(stream: *const c_void, output: *mut u8, size: usize);
 }
 
+/// A grammar and applicator loaded by cg3, cached on the grammar resource so it is only parsed
+/// once while the resource stays loaded. cg3 applicators aren't safe to share between threads.
+struct Grammar(Mutex<*mut c_void>);
+
+unsafe impl Send for Grammar {}
+unsafe impl Sync for Grammar {}
+
+impl Grammar {
+    fn new(data: &[u8]) -> Option<Grammar> {
+        let grammar = unsafe { cg3_grammar_create(data.as_ptr(), data.len()) };
+        if grammar.is_null() {
+            return None;
+        }
+        Some(Grammar(Mutex::new(grammar)))
+    }
+}
+
+impl Drop for Grammar {
+    fn drop(&mut self) {
+        unsafe { cg3_grammar_destroy(*self.0.get_mut().unwrap()) };
+    }
+}
+
 #[no_mangle]
 pub extern "C" fn pipeline_init(interface: *const ModuleInterface) -> bool {
     interface::initialize(interface)
@@ -62,21 +88,52 @@ pub extern "C" fn pipeline_run(p: *const ModuleRunParameters) -> bool {
                 let input_data = message.get().unwrap().get_string().unwrap();
 
                 let grammar_resource = p.get_parameter(0);
-                let grammar = interface::load_resource(&*grammar_resource)
-                    .expect("pmatch resource doesn't exist");
+                let grammar = match interface::load_resource(&*grammar_resource) {
+                    Ok(grammar) => grammar,
+                    Err(e) => {
+                        util::output_message(
+                            p.output,
+                            p.output_size,
+                            divvun_schema::capnp_error!(
+                                divvun_schema::error_capnp::pipeline_error::ErrorKind::InvalidParameters,
+                                &e.to_string()
+                            ),
+                        )
+                        .unwrap();
+                        return false;
+                    }
+                };
+
+                let grammar = match grammar.cached_object("cg3_grammar", Grammar::new) {
+                    Some(grammar) => grammar,
+                    None => {
+                        util::output_message(
+                            p.output,
+                            p.output_size,
+                            divvun_schema::capnp_error!(
+                                divvun_schema::error_capnp::pipeline_error::ErrorKind::InvalidParameters,
+                                &format!("failed to load grammar {}", grammar_resource)
+                            ),
+                        )
+                        .unwrap();
+                        return false;
+                    }
+                };
 
                 let mut output_size: usize = 0;
                 // hfst_run runs the hfst tokenizer and writes into a std::stringstream
                 // If we want to avoid the copying here, we have to make a custom STL allocator in C++
                 // to use our allocator, and then get a pointer to that buffer back instead :)
-                let stream = unsafe {
-                    cg3_run(
-                        grammar.as_ptr(),
-                        grammar.size(),
-                        input_data.as_ptr(),
-                        input_data.len(),
-                        &mut output_size,
-                    )
+                let stream = {
+                    let grammar = grammar.0.lock().unwrap();
+                    unsafe {
+                        cg3_run(
+                            *grammar,
+                            input_data.as_ptr(),
+                            input_data.len(),
+                            &mut output_size,
+                        )
+                    }
                 };
 
                 // When we have a interface deallocate function we can use our allocation system to
@@ -154,13 +211,13 @@ mod tests {
     #[test]
     fn test() {
         let grammar = std::fs::read("../../se_zcheck/grc-disambiguator.bin").unwrap();
+        let grammar = Grammar::new(&grammar).unwrap();
         let input_data = b"\"<Hello>\"\n\t\"heallat\" Ex/V Ex/IV Der/PassS V IV Ind Prs ConNeg <W:0.0>\n\t\"heallat\" Ex/V Ex/IV Der/PassS V IV Ind Prs Sg3 <W:0.0>\n\t\"heallat\" V IV Imprt ConNegII <W:0.0>\n: \n\"<world>\"\n\t\"world\" ?\n: \n\"<what>\"\n\t\"what\" ?\n: \n\"<is>\"\n\t\"is\" ?\n: \n\"<going>\"\n\t\"going\" ?\n: \n\"<on>\"\n\t\"on\" Adv <W:0.0>\n\"<,>\"\n\t\",\" CLB <W:0.0>\n: \n\"<please>\"\n\t\"please\" ?\n: \n\"<correct>\"\n\t\"correct\" ?\n: \n\"<me>\"\n\t\"me\" ?\n";
         println!("cg3 input: {}", String::from_utf8_lossy(input_data));
         let mut output_size: usize = 0;
         let stream = unsafe {
             cg3_run(
-                grammar.as_ptr(),
-                grammar.len(),
+                *grammar.0.lock().unwrap(),
                 input_data.as_ptr(),
                 input_data.len(),
                 &mut output_size,
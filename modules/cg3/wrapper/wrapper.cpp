@@ -36,11 +36,17 @@ cg3_applicator_free(applicator)
 cg3_cleanup()
 */
 
-extern "C" std::stringstream *
-cg3_run(const uint8_t *grammar_data, size_t grammar_size, const uint8_t *input_data, size_t input_size,
-        size_t *output_size)
+struct cg3_compiled_grammar
+{
+    cg3_grammar *grammar;
+    cg3_applicator *applicator;
+};
+
+extern "C" cg3_compiled_grammar *cg3_grammar_create(const uint8_t *grammar_data, size_t grammar_size)
 {
-    if (!cg3_init(stdin, stdout, stderr))
+    // cg3_init only has to happen once per process
+    static bool initialized = cg3_init(stdin, stdout, stderr);
+    if (!initialized)
         return nullptr;
 
     auto grammar = cg3_grammar_load_buffer((const char *)grammar_data, grammar_size);
@@ -51,15 +57,29 @@ cg3_run(const uint8_t *grammar_data, size_t grammar_size, const uint8_t *input_d
     auto applicator = cg3_applicator_create(grammar);
 
     if (!applicator)
+    {
+        cg3_grammar_free(grammar);
         return nullptr;
+    }
 
+    return new cg3_compiled_grammar{grammar, applicator};
+}
+
+extern "C" void cg3_grammar_destroy(cg3_compiled_grammar *compiled)
+{
+    cg3_applicator_free(compiled->applicator);
+    cg3_grammar_free(compiled->grammar);
+    delete compiled;
+}
+
+extern "C" std::stringstream *
+cg3_run(cg3_compiled_grammar *compiled, const uint8_t *input_data, size_t input_size, size_t *output_size)
+{
     memstream input_stream(input_data, input_size);
 
     auto output = new std::stringstream(std::ios::in | std::ios::out | std::ios::binary);
 
-    cg3_run_grammar_on_text(applicator, &input_stream, output);
-    cg3_applicator_free(applicator);
-    cg3_grammar_free(grammar);
+    cg3_run_grammar_on_text(compiled->applicator, &input_stream, output);
 
     output->seekg(0, output->end);
     *output_size = output->tellg();
@@ -8,15 +8,17 @@ use divvun_schema::{
 };
 use lazy_static::lazy_static;
 use log::info;
-use std::{ffi::CStr, io::Cursor, os::raw::c_char, str};
+use std::{ffi::CStr, io::Cursor, os::raw::c_char, str, sync::Mutex};
 mod bindings;
 use std::ffi::c_void;
 
 extern "C" {
+    fn hfst_container_create(pmatch_data: *const u8, pmatch_size: usize) -> *mut c_void;
+    fn hfst_container_destroy(container: *mut c_void);
+
     fn hfst_run(
         settings: *const bindings::hfst_ol_tokenize_TokenizeSettings,
-        pmatch_data: *const u8,
-        pmatch_size: usize,
+        container: *mut c_void,
         input_data: *const u8,
         input_size: usize,
         output_size: *mut usize,
@@ -26,6 +28,29 @@ extern "C" {
     fn hfst_copy_output(stream: *const c_void, output: *mut u8, size: usize);
 }
 
+/// A parsed pmatch transducer, cached on the pmatch resource so it is only parsed once while the
+/// resource stays loaded. The container keeps state while tokenizing, so runs are serialized.
+struct PmatchContainer(Mutex<*mut c_void>);
+
+unsafe impl Send for PmatchContainer {}
+unsafe impl Sync for PmatchContainer {}
+
+impl PmatchContainer {
+    fn new(data: &[u8]) -> Option<PmatchContainer> {
+        let container = unsafe { hfst_container_create(data.as_ptr(), data.len()) };
+        if container.is_null() {
+            return None;
+        }
+        Some(PmatchContainer(Mutex::new(container)))
+    }
+}
+
+impl Drop for PmatchContainer {
+    fn drop(&mut self) {
+        unsafe { hfst_container_destroy(*self.0.get_mut().unwrap()) };
+    }
+}
+
 #[no_mangle]
 pub extern "C" fn pipeline_init(interface: *const PipelineInterface) -> bool {
     interface::initialize(interface)
@@ -76,22 +101,53 @@ pub extern "C" fn pipeline_run(p: *const ModuleRunParameters) -> bool {
                 };
 
                 let pmatch_resource = p.get_parameter(0);
-                let pmatch = interface::load_resource(&*pmatch_resource)
-                    .expect("pmatch resource doesn't exist");
+                let pmatch = match interface::load_resource(&*pmatch_resource) {
+                    Ok(pmatch) => pmatch,
+                    Err(e) => {
+                        util::output_message(
+                            p.output,
+                            p.output_size,
+                            divvun_schema::capnp_error!(
+                                divvun_schema::error_capnp::pipeline_error::ErrorKind::InvalidParameters,
+                                &e.to_string()
+                            ),
+                        )
+                        .unwrap();
+                        return false;
+                    }
+                };
+
+                let container = match pmatch.cached_object("hfst_pmatch", PmatchContainer::new) {
+                    Some(container) => container,
+                    None => {
+                        util::output_message(
+                            p.output,
+                            p.output_size,
+                            divvun_schema::capnp_error!(
+                                divvun_schema::error_capnp::pipeline_error::ErrorKind::InvalidParameters,
+                                &format!("failed to load pmatch file {}", pmatch_resource)
+                            ),
+                        )
+                        .unwrap();
+                        return false;
+                    }
+                };
 
                 let mut output_size: usize = 0;
                 // hfst_run runs the hfst tokenizer and writes into a std::stringstream
                 // If we want to avoid the copying here, we have to make a custom STL allocator in C++
                 // to use our allocator, and then get a pointer to that buffer back instead :)
-                let stream = unsafe {
-                    hfst_run(
-                        &settings,
-                        pmatch.as_ptr(),
-                        pmatch.size(),
-                        input_data.as_ptr(),
-                        input_data.len(),
-                        &mut output_size,
-                    )
+                let stream = {
+                    let container = container.0.lock().unwrap();
+                    unsafe {
+                        hfst_run(
+                            &settings,
+                            *container,
+                            input_data.as_ptr(),
+                            input_data.len(),
+                            &mut output_size,
+                        )
+                    }
                 };
 
                 // When we have a interface deallocate function we can use our allocation system to
@@ -184,14 +240,14 @@ mod tests {
         };
 
         let pmatch = std::fs::read("../../se_zcheck/tokeniser-gramcheck-gt-desc.pmhfst").unwrap();
+        let container = PmatchContainer::new(&pmatch).unwrap();
         let input_data = b"Hello world please correc this or something";
         println!("hfst input: {}", String::from_utf8_lossy(input_data));
         let mut output_size: usize = 0;
         let stream = unsafe {
             hfst_run(
                 &settings,
-                pmatch.as_ptr(),
-                pmatch.len(),
+                *container.0.lock().unwrap(),
                 input_data.as_ptr(),
                 input_data.len(),
                 &mut output_size,
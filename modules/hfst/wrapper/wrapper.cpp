@@ -25,15 +25,23 @@ private:
     membuf _buffer;
 };
 
-extern "C" std::stringstream *hfst_run(hfst_ol_tokenize::TokenizeSettings *settings, const uint8_t *pmatch_data, size_t pmatch_size, const uint8_t *input_data, size_t input_size, size_t *output_size)
+extern "C" hfst_ol::PmatchContainer *hfst_container_create(const uint8_t *pmatch_data, size_t pmatch_size)
 {
     memstream pmatch_stream(pmatch_data, pmatch_size);
-    hfst_ol::PmatchContainer container(pmatch_stream);
+    return new hfst_ol::PmatchContainer(pmatch_stream);
+}
 
+extern "C" void hfst_container_destroy(hfst_ol::PmatchContainer *container)
+{
+    delete container;
+}
+
+extern "C" std::stringstream *hfst_run(hfst_ol_tokenize::TokenizeSettings *settings, hfst_ol::PmatchContainer *container, const uint8_t *input_data, size_t input_size, size_t *output_size)
+{
     memstream input_stream(input_data, input_size);
 
     auto output = new std::stringstream(std::ios::in | std::ios::out | std::ios::binary);
-    hfst_ol_tokenize::process_input(container, input_stream, *output, *settings);
+    hfst_ol_tokenize::process_input(*container, input_stream, *output, *settings);
     output->seekg(0, output->end);
     *output_size = output->tellg();
 
@@ -86,7 +86,21 @@ pub extern "C" fn pipeline_run(p: *const ModuleRunParameters) -> bool {
 
             let resource_name = unsafe { CStr::from_ptr(parameters[0]).to_string_lossy() };
             println!("loading resource {}", resource_name);
-            let res = interface::load_resource(&*resource_name).expect("resource");
+            let res = match interface::load_resource(&*resource_name) {
+                Ok(res) => res,
+                Err(e) => {
+                    util::output_message(
+                        p.output,
+                        p.output_size,
+                        divvun_schema::capnp_error!(
+                            divvun_schema::error_capnp::pipeline_error::ErrorKind::InvalidParameters,
+                            &e.to_string()
+                        ),
+                    )
+                    .unwrap();
+                    return false;
+                }
+            };
             println!("res {:?}", res);
 
             let string = String::from_utf8_lossy(res.as_slice());
@@ -3,3 +3,7 @@ cp target/debug/libreverse_string.so modules/reverse_string.so
 cp target/debug/libdo_things_strings.so modules/do_things_strings.so
 cp target/debug/libconcat_strings.so modules/concat_strings.so
 RUST_LOG=divvun_pipeline=info cargo test -- --test-threads 1 --nocapture
+cbindgen --quiet --config divvun-pipeline/cbindgen.toml --output target/divvun_pipeline.h divvun-pipeline
+diff -u divvun-pipeline/include/divvun_pipeline.h target/divvun_pipeline.h || { echo "divvun_pipeline.h is out of date, regenerate it with cbindgen"; exit 1; }
+cc divvun-pipeline/tests/c/reverse.c -I divvun-pipeline/include -L target/debug -l divvun_pipeline -o target/debug/c-reverse
+LD_LIBRARY_PATH=target/debug target/debug/c-reverse divvun-pipeline/tests/c/reverse modules
@@ -6,3 +6,7 @@ cp target/debug/libconcat_strings.dylib target/modules/concat_strings.dylib
 cp target/debug/libdivvun_pipeline_hfst.dylib target/modules/hfst.dylib
 cp target/debug/libdivvun_pipeline_cg3.dylib target/modules/cg3.dylib
 RUST_LOG=divvun_pipeline=info cargo test -- --test-threads 1 --nocapture
+cbindgen --quiet --config divvun-pipeline/cbindgen.toml --output target/divvun_pipeline.h divvun-pipeline
+diff -u divvun-pipeline/include/divvun_pipeline.h target/divvun_pipeline.h || { echo "divvun_pipeline.h is out of date, regenerate it with cbindgen"; exit 1; }
+cc divvun-pipeline/tests/c/reverse.c -I divvun-pipeline/include -L target/debug -l divvun_pipeline -o target/debug/c-reverse
+DYLD_LIBRARY_PATH=target/debug target/debug/c-reverse divvun-pipeline/tests/c/reverse target/modules